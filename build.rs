@@ -7,12 +7,43 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also bakes the node's settings into `config.rs` in the output directory,
+//! from `PIBOW_*` environment variables or the gitignored `pibow.toml`.
 
 use std::env;
-use std::fs::File;
+use std::fs::{ self, File };
 use std::io::Write;
+use std::net::Ipv4Addr;
 use std::path::PathBuf;
 
+use base64::{ engine::general_purpose::STANDARD, Engine };
+
+// Every setting, as named in `pibow.toml`. The environment variable is `PIBOW_` + the name in caps.
+// Settings without a default must be given, the build fails otherwise.
+const SETTINGS: &[(&str, Option<&str>)] = &[
+    ("wifi_network", None),
+    ("wifi_password", None),
+    ("key", None),
+    ("multicast_ip", Some("224.0.0.127")),
+    ("multicast_port", Some("4265")),
+    ("node_port", Some("5325")),
+    ("server_port", Some("7325")),
+    ("fault_tolerance", Some("5")),
+    ("relay_active_high", Some("false")),
+    ("encrypt_session", Some("false")),
+    ("force_off_hold_ms", Some("8000")),
+    ("power_press_ms", Some("500")),
+    ("reset_press_ms", Some("500")),
+    ("settle_ms", Some("250")),
+    ("verify_timeout_ms", Some("30000")),
+    ("retry_press", Some("false")),
+    ("channels", Some("main:14:15:16")),
+    ("debounce_ms", Some("50")),
+    ("steady_ms", Some("2000")),
+    ("blink_window_ms", Some("5000")),
+];
+
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
@@ -33,4 +64,274 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+
+    match node_config() {
+        Ok(config) => fs::write(out.join("config.rs"), config).unwrap(),
+        Err(problems) => {
+            for problem in problems {
+                println!("cargo::error={problem}");
+            }
+            println!(
+                "cargo::error=Settings come from pibow.toml (see pibow.example.toml) or PIBOW_* variables"
+            );
+        }
+    }
+}
+
+// The generated consts, or everything that's wrong with the settings at once.
+fn node_config() -> Result<String, Vec<String>> {
+    println!("cargo:rerun-if-changed=pibow.toml");
+    let file = match fs::read_to_string("pibow.toml") {
+        Ok(text) => {
+            text.parse::<toml::Table>().map_err(|error| vec![format!("pibow.toml is broken: {error}")])?
+        }
+        Err(_) => toml::Table::new(),
+    };
+
+    let mut problems = Vec::new();
+    let mut setting = |name: &str| -> String {
+        let variable = format!("PIBOW_{}", name.to_uppercase());
+        println!("cargo:rerun-if-env-changed={variable}");
+
+        let default = SETTINGS.iter()
+            .find(|(known, _)| *known == name)
+            .and_then(|(_, default)| *default);
+        let value = env::var(&variable).ok().or_else(|| {
+            file.get(name).map(|value| match value {
+                toml::Value::String(text) => text.clone(),
+                other => other.to_string(),
+            })
+        });
+        match value.or(default.map(String::from)) {
+            Some(value) => value,
+            None => {
+                problems.push(format!("The {name} setting is missing"));
+                String::new()
+            }
+        }
+    };
+
+    let wifi_network = setting("wifi_network");
+    let wifi_password = setting("wifi_password");
+    let key = setting("key");
+    let multicast_ip = setting("multicast_ip");
+    let multicast_port = setting("multicast_port");
+    let node_port = setting("node_port");
+    let server_port = setting("server_port");
+    let fault_tolerance = setting("fault_tolerance");
+    let relay_active_high = setting("relay_active_high");
+    let encrypt_session = setting("encrypt_session");
+    let force_off_hold_ms = setting("force_off_hold_ms");
+    let power_press_ms = setting("power_press_ms");
+    let reset_press_ms = setting("reset_press_ms");
+    let settle_ms = setting("settle_ms");
+    let verify_timeout_ms = setting("verify_timeout_ms");
+    let retry_press = setting("retry_press");
+    let channels = setting("channels");
+    let debounce_ms = setting("debounce_ms");
+    let steady_ms = setting("steady_ms");
+    let blink_window_ms = setting("blink_window_ms");
+
+    for name in file.keys() {
+        if !SETTINGS.iter().any(|(known, _)| known == name) {
+            problems.push(format!("pibow.toml has an unknown setting: {name}"));
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    // Same limits as the flash record.
+    if wifi_network.len() > 32 {
+        problems.push("The wifi_network setting is longer than 32 bytes".to_string());
+    }
+    if wifi_password.len() > 64 {
+        problems.push("The wifi_password setting is longer than 64 bytes".to_string());
+    }
+    let key: Vec<u8> = match STANDARD.decode(key.trim()) {
+        Ok(key) if key.len() == 32 => key,
+        _ => {
+            problems.push(
+                "The key setting must be 32 bytes of base64, like `openssl rand -base64 32`".to_string()
+            );
+            Vec::new()
+        }
+    };
+    let multicast_ip = match multicast_ip.parse::<Ipv4Addr>() {
+        Ok(address) if address.is_multicast() => address.to_bits(),
+        _ => {
+            problems.push(format!("The multicast_ip setting isn't multicast: {multicast_ip}"));
+            0
+        }
+    };
+    let mut number = |name: &str, value: &str| -> u64 {
+        value.trim().parse().unwrap_or_else(|_| {
+            problems.push(format!("The {name} setting isn't a number: {value}"));
+            0
+        })
+    };
+    let multicast_port = number("multicast_port", &multicast_port);
+    let node_port = number("node_port", &node_port);
+    let server_port = number("server_port", &server_port);
+    let fault_tolerance = number("fault_tolerance", &fault_tolerance);
+    let force_off_hold_ms = number("force_off_hold_ms", &force_off_hold_ms);
+    let power_press_ms = number("power_press_ms", &power_press_ms);
+    let reset_press_ms = number("reset_press_ms", &reset_press_ms);
+    let settle_ms = number("settle_ms", &settle_ms);
+    let verify_timeout_ms = number("verify_timeout_ms", &verify_timeout_ms);
+    let debounce_ms = number("debounce_ms", &debounce_ms);
+    let steady_ms = number("steady_ms", &steady_ms);
+    let blink_window_ms = number("blink_window_ms", &blink_window_ms);
+    let ports = [
+        ("multicast_port", multicast_port),
+        ("node_port", node_port),
+        ("server_port", server_port),
+    ];
+    for (name, port) in ports {
+        if port == 0 || port > u16::MAX as u64 {
+            problems.push(format!("The {name} setting isn't a port: {port}"));
+        }
+    }
+    // The flash record keeps it in 16 bits.
+    if fault_tolerance > u16::MAX as u64 {
+        problems.push(format!("The fault_tolerance setting is too big: {fault_tolerance}"));
+    }
+    // Same bounds as pibow_power's, the node clamps to them anyway.
+    let timings = [
+        ("force_off_hold_ms", force_off_hold_ms, 1_000..=30_000),
+        ("power_press_ms", power_press_ms, 50..=3_000),
+        ("reset_press_ms", reset_press_ms, 50..=3_000),
+        ("settle_ms", settle_ms, 0..=10_000),
+        ("verify_timeout_ms", verify_timeout_ms, 1_000..=120_000),
+        ("debounce_ms", debounce_ms, 0..=1_000),
+        ("steady_ms", steady_ms, 100..=60_000),
+        ("blink_window_ms", blink_window_ms, 500..=60_000),
+    ];
+    for (name, value, bounds) in timings {
+        if !bounds.contains(&value) {
+            problems.push(
+                format!(
+                    "The {name} setting must be between {} and {}: {value}",
+                    bounds.start(),
+                    bounds.end()
+                )
+            );
+        }
+    }
+    let mut flag = |name: &str, value: &str| -> bool {
+        match value.trim() {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            other => {
+                problems.push(format!("The {name} setting isn't true or false: {other}"));
+                false
+            }
+        }
+    };
+    let relay_active_high = flag("relay_active_high", &relay_active_high);
+    let encrypt_session = flag("encrypt_session", &encrypt_session);
+    let retry_press = flag("retry_press", &retry_press);
+    let channels = parse_channels(&channels, relay_active_high).unwrap_or_else(|problem| {
+        problems.push(problem);
+        Vec::new()
+    });
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    Ok(
+        format!(
+            "// Generated by build.rs, from pibow.toml and the PIBOW_* environment variables.\n\
+             pub const WIFI_NETWORK: &str = {wifi_network:?};\n\
+             pub const WIFI_PASSWORD: &str = {wifi_password:?};\n\
+             pub const SECRET_HASH_KEY: &[u8; 32] = &{key:?};\n\
+             pub const MULTICAST_IP: u32 = {multicast_ip};\n\
+             pub const MULTICAST_PORT: u16 = {multicast_port};\n\
+             pub const NODE_PORT: u16 = {node_port};\n\
+             pub const SERVER_PORT: u16 = {server_port};\n\
+             pub const FAULT_TOLERANCE: usize = {fault_tolerance};\n\
+             // Whether the relay module switches on a high level.\n\
+             pub const RELAY_ACTIVE_HIGH: bool = {relay_active_high};\n\
+             // Whether to ask the server for an encrypted session.\n\
+             pub const ENCRYPT_SESSION: bool = {encrypt_session};\n\
+             // How long a force off holds the power switch at most.\n\
+             pub const FORCE_OFF_HOLD_MS: u32 = {force_off_hold_ms};\n\
+             // How the relays press, and how long the machine gets to follow.\n\
+             pub const POWER_PRESS_MS: u32 = {power_press_ms};\n\
+             pub const RESET_PRESS_MS: u32 = {reset_press_ms};\n\
+             pub const SETTLE_MS: u32 = {settle_ms};\n\
+             pub const VERIFY_TIMEOUT_MS: u32 = {verify_timeout_ms};\n\
+             // Whether to press the power switch again when the machine didn't follow.\n\
+             pub const RETRY_PRESS: bool = {retry_press};\n\
+             // The machines wired to the node: name, power, reset and state pins, polarity, then\n\
+             // the sleep pin and whether it reads high while suspended.\n\
+             pub const CHANNELS: &[(&str, u8, u8, u8, bool, Option<u8>, bool)] = &{channels:?};\n\
+             // How the state pins get filtered into ON, OFF and asleep.\n\
+             pub const DEBOUNCE_MS: u32 = {debounce_ms};\n\
+             pub const STEADY_MS: u32 = {steady_ms};\n\
+             pub const BLINK_WINDOW_MS: u32 = {blink_window_ms};\n"
+        )
+    )
+}
+
+// How a channel goes into the generated consts, see CHANNELS.
+type Channel = (String, u8, u8, u8, bool, Option<u8>, bool);
+
+// `<name>:<power pin>:<reset pin>:<state pin>[:high|low[:<sleep pin>[:high|low]]]`, comma separated.
+// Same rules as the flash record's channel table, 4 channels at most, on pins of their own that
+// aren't the Wifi chip's. The sleep pin reads low while suspended unless it says high, like SLP_S3#.
+fn parse_channels(value: &str, active_high: bool) -> Result<Vec<Channel>, String> {
+    let problem = |detail: &str| format!("The channels setting {detail}: {value}");
+    let polarity = |word: &str| match word {
+        "high" => Ok(true),
+        "low" => Ok(false),
+        _ => Err(problem("goes like main:14:15:16,second:17:18:19:high:20:low")),
+    };
+    let mut channels = Vec::new();
+    let mut taken = Vec::new();
+    for channel in value.split(',') {
+        let fields: Vec<&str> = channel.trim().split(':').collect();
+        let (name, mut pins, rest) = match fields.as_slice() {
+            [name, power, reset, state, rest @ ..] if rest.len() <= 3 => {
+                (*name, vec![*power, *reset, *state], rest)
+            }
+            _ => {
+                return Err(problem("goes like main:14:15:16,second:17:18:19:high:20:low"));
+            }
+        };
+        let (relays, sleep_active_high) = match rest {
+            [] => (active_high, false),
+            [relays] => (polarity(*relays)?, false),
+            [relays, sleep] => {
+                pins.push(*sleep);
+                (polarity(*relays)?, false)
+            }
+            [relays, sleep, asleep] => {
+                pins.push(*sleep);
+                (polarity(*relays)?, polarity(*asleep)?)
+            }
+            _ => unreachable!(),
+        };
+        if name.len() > 16 {
+            return Err(problem("has a name longer than 16 bytes"));
+        }
+        let mut numbers = Vec::new();
+        for pin in pins {
+            match pin.parse() {
+                Ok(pin @ (0..=22 | 26..=28)) if !taken.contains(&pin) => {
+                    numbers.push(pin);
+                    taken.push(pin);
+                }
+                _ => {
+                    return Err(problem("needs GPIO 0 to 22 or 26 to 28, none of them shared"));
+                }
+            }
+        }
+        let (power, reset, state, sleep) = (numbers[0], numbers[1], numbers[2], numbers.get(3).copied());
+        channels.push((name.to_string(), power, reset, state, relays, sleep, sleep_active_high));
+    }
+    if channels.len() > 4 {
+        return Err(problem("has more than 4 channels"));
+    }
+    Ok(channels)
 }
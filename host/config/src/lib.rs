@@ -0,0 +1,523 @@
+//! The node's configuration record, as stored in its reserved flash sector.
+//!
+//! Layout, all little endian:
+//! `["PBCF", <version>, <sequence>, <payload length u16>, <payload>, <crc32 of everything before>]`.
+//!
+//! The payload only ever grows at the end. Records written by older firmware are shorter, the
+//! fields they don't have keep the defaults handed to [`NodeConfig::decode`].
+//!
+//! The sequence tells the newer of two copies apart, so a record can be written next to the
+//! current one and only take over once it's whole. Older firmware kept a single copy at 0.
+
+#![no_std]
+
+use heapless::{ String, Vec };
+
+pub const MAGIC: &[u8; 4] = b"PBCF";
+pub const FORMAT_VERSION: u8 = 1;
+
+/// Enough room for any record, a whole flash sector is reserved anyway.
+pub const MAX_RECORD_LENGTH: usize = 512;
+
+const HEADER_LENGTH: usize = 8;
+const CRC_LENGTH: usize = 4;
+
+pub const SSID_CAPACITY: usize = 32;
+pub const PASSWORD_CAPACITY: usize = 64;
+pub const CHANNEL_NAME_CAPACITY: usize = 16;
+
+/// How many machines one node can drive.
+pub const MAX_CHANNELS: usize = 4;
+// A channel without a sleep pin, in the record.
+const NO_PIN: u8 = 0xff;
+
+/// A state pin read through the ADC, the machine is ON from `on_mv` up and OFF from `off_mv`
+/// down, for LED headers that sit at odd voltages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnalogSense {
+    pub on_mv: u16,
+    pub off_mv: u16,
+}
+
+/// A current-sense shunt in the machine's supply, by the ADC pin across it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shunt {
+    pub pin: u8,
+    pub milliohms: u16,
+}
+
+/// Magic packets that power a machine ON, by the MAC address they're for, along with the SecureOn
+/// password they have to carry when there's one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakeOnLan {
+    pub mac_address: [u8; 6],
+    pub password: Option<[u8; 6]>,
+}
+
+/// One machine wired to the node, by the GPIO numbers of its switches and its state pin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Channel {
+    pub name: String<CHANNEL_NAME_CAPACITY>,
+    pub power_pin: u8,
+    pub reset_pin: u8,
+    pub state_pin: u8,
+    /// Whether this machine's relays switch on a high level.
+    pub active_high: bool,
+    /// A second state pin that says when the machine is suspended, like the board's SLP_S3#.
+    pub sleep_pin: Option<u8>,
+    /// Whether the sleep pin reads high while suspended, SLP_S3# goes low.
+    pub sleep_active_high: bool,
+    /// Read the state pin through the ADC instead, it has to be one of the ADC pins then.
+    pub analog: Option<AnalogSense>,
+    /// Only read along with an analog state pin, the current goes out with its voltage.
+    pub shunt: Option<Shunt>,
+    pub wake_on_lan: Option<WakeOnLan>,
+}
+
+impl Channel {
+    pub fn pins(&self) -> [u8; 3] {
+        [self.power_pin, self.reset_pin, self.state_pin]
+    }
+
+    // Every pin it takes, the optional ones included.
+    fn all_pins(&self) -> impl Iterator<Item = u8> {
+        let shunt_pin = self.shunt.map(|shunt| shunt.pin);
+        self.pins().into_iter().chain(self.sleep_pin).chain(shunt_pin)
+    }
+
+    // Whatever gets read through the ADC is on an ADC pin, a shunt only goes with analog sensing.
+    fn adc_fits(&self) -> bool {
+        match (self.analog, self.shunt) {
+            (None, None) => true,
+            (None, Some(_)) => false,
+            (Some(_), shunt) => {
+                adc_pin(self.state_pin) && shunt.is_none_or(|shunt| adc_pin(shunt.pin))
+            }
+        }
+    }
+}
+
+/// Whether a channel can use that GPIO, the rest are wired to the Wifi chip on a Pico W.
+pub fn usable_pin(pin: u8) -> bool {
+    matches!(pin, 0..=22 | 26..=28)
+}
+
+/// Whether the ADC can read that GPIO, the RP2040 has it on 26 to 29 and 29 goes to the Wifi chip.
+pub fn adc_pin(pin: u8) -> bool {
+    matches!(pin, 26..=28)
+}
+
+/// Whether every channel is on usable pins, none of them shared.
+pub fn channels_fit(channels: &[Channel]) -> bool {
+    if !channels.iter().all(Channel::adc_fits) {
+        return false;
+    }
+    let mut taken = 0_u32;
+    for pin in channels.iter().flat_map(Channel::all_pins) {
+        if !usable_pin(pin) || taken & (1 << pin) != 0 {
+            return false;
+        }
+        taken |= 1 << pin;
+    }
+    true
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeConfig {
+    pub wifi_network: String<SSID_CAPACITY>,
+    pub wifi_password: String<PASSWORD_CAPACITY>,
+    pub secret_key: [u8; 32],
+    pub multicast_ip: u32,
+    pub multicast_port: u16,
+    pub node_port: u16,
+    pub server_port: u16,
+    pub fault_tolerance: u16,
+    /// Whether the relay module switches on a high level. The first channel's polarity in
+    /// records from before the channel table.
+    pub relay_active_high: bool,
+    /// Whether to ask the server for an encrypted session, older servers only do plain ones.
+    pub encrypt_session: bool,
+    /// The key before the last rotation, still good for `previous_key_grace_secs`.
+    pub previous_key: [u8; 32],
+    /// What's left of the grace window, the node writes it back every once in a while and takes
+    /// some off on every boot. 0 once the previous key is done with.
+    pub previous_key_grace_secs: u32,
+    /// How long a force off holds the power switch at most, it lets go as soon as the machine is
+    /// off.
+    pub force_off_hold_ms: u32,
+    /// How long a power and a reset press go on.
+    pub power_press_ms: u32,
+    pub reset_press_ms: u32,
+    /// How long to wait after letting go of a switch, before doing anything else.
+    pub settle_ms: u32,
+    /// How long the machine gets to follow a press.
+    pub verify_timeout_ms: u32,
+    /// Whether to press the power switch once more when the machine didn't follow.
+    pub retry_press: bool,
+    /// The machines this node drives, the first one is channel 0.
+    pub channels: Vec<Channel, MAX_CHANNELS>,
+    /// How long a state pin's level has to hold before it counts at all.
+    pub debounce_ms: u32,
+    /// How long it has to hold before the machine is ON or OFF.
+    pub steady_ms: u32,
+    /// The machine is asleep when its LED blinks twice within this long.
+    pub blink_window_ms: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// Erased flash, nothing was ever saved.
+    Empty,
+    BadMagic,
+    UnsupportedVersion(u8),
+    BadLength,
+    BadChecksum,
+    /// A string field isn't valid UTF-8 or doesn't fit.
+    BadString,
+    BufferTooSmall,
+}
+
+impl NodeConfig {
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, ConfigError> {
+        self.encode_with_sequence(0, buffer)
+    }
+
+    pub fn encode_with_sequence(&self, sequence: u8, buffer: &mut [u8]) -> Result<usize, ConfigError> {
+        let mut writer = Writer { buffer, offset: HEADER_LENGTH };
+        writer.string(&self.wifi_network, SSID_CAPACITY)?;
+        writer.string(&self.wifi_password, PASSWORD_CAPACITY)?;
+        writer.bytes(&self.secret_key)?;
+        writer.bytes(&self.multicast_ip.to_le_bytes())?;
+        writer.bytes(&self.multicast_port.to_le_bytes())?;
+        writer.bytes(&self.node_port.to_le_bytes())?;
+        writer.bytes(&self.server_port.to_le_bytes())?;
+        writer.bytes(&self.fault_tolerance.to_le_bytes())?;
+        writer.bytes(&[self.relay_active_high as u8])?;
+        writer.bytes(&[self.encrypt_session as u8])?;
+        writer.bytes(&self.previous_key)?;
+        writer.bytes(&self.previous_key_grace_secs.to_le_bytes())?;
+        writer.bytes(&self.force_off_hold_ms.to_le_bytes())?;
+        writer.bytes(&self.power_press_ms.to_le_bytes())?;
+        writer.bytes(&self.reset_press_ms.to_le_bytes())?;
+        writer.bytes(&self.settle_ms.to_le_bytes())?;
+        writer.bytes(&self.verify_timeout_ms.to_le_bytes())?;
+        writer.bytes(&[self.retry_press as u8])?;
+        // The whole table, unused channels zeroed, so the record doesn't move with the count.
+        writer.bytes(&[self.channels.len() as u8])?;
+        for index in 0..MAX_CHANNELS {
+            match self.channels.get(index) {
+                Some(channel) => {
+                    writer.string(&channel.name, CHANNEL_NAME_CAPACITY)?;
+                    writer.bytes(&channel.pins())?;
+                    writer.bytes(&[channel.active_high as u8])?;
+                }
+                None => {
+                    writer.string("", CHANNEL_NAME_CAPACITY)?;
+                    writer.bytes(&[0; 4])?;
+                }
+            }
+        }
+        writer.bytes(&self.debounce_ms.to_le_bytes())?;
+        writer.bytes(&self.steady_ms.to_le_bytes())?;
+        writer.bytes(&self.blink_window_ms.to_le_bytes())?;
+        // The sleep pins came after the table, so they go in a table of their own.
+        for index in 0..MAX_CHANNELS {
+            match self.channels.get(index) {
+                Some(channel) => {
+                    let sleep_pin = channel.sleep_pin.unwrap_or(NO_PIN);
+                    writer.bytes(&[sleep_pin, channel.sleep_active_high as u8])?;
+                }
+                None => writer.bytes(&[NO_PIN, 0])?,
+            }
+        }
+        // Same for analog sensing, no thresholds and NO_PIN when a channel doesn't have it.
+        for index in 0..MAX_CHANNELS {
+            let channel = self.channels.get(index);
+            let analog = channel.and_then(|channel| channel.analog);
+            let shunt = channel.and_then(|channel| channel.shunt);
+            writer.bytes(&[analog.is_some() as u8])?;
+            let AnalogSense { on_mv, off_mv } = analog.unwrap_or(AnalogSense { on_mv: 0, off_mv: 0 });
+            writer.bytes(&on_mv.to_le_bytes())?;
+            writer.bytes(&off_mv.to_le_bytes())?;
+            let Shunt { pin, milliohms } = shunt.unwrap_or(Shunt { pin: NO_PIN, milliohms: 0 });
+            writer.bytes(&[pin])?;
+            writer.bytes(&milliohms.to_le_bytes())?;
+        }
+        // And Wake-on-LAN, whether a channel has it, its MAC address, whether it has a password
+        // and the password.
+        for index in 0..MAX_CHANNELS {
+            let wake_on_lan = self.channels.get(index).and_then(|channel| channel.wake_on_lan);
+            let mac_address = wake_on_lan.map(|wake| wake.mac_address);
+            let password = wake_on_lan.and_then(|wake| wake.password);
+            writer.bytes(&[mac_address.is_some() as u8])?;
+            writer.bytes(&mac_address.unwrap_or_default())?;
+            writer.bytes(&[password.is_some() as u8])?;
+            writer.bytes(&password.unwrap_or_default())?;
+        }
+
+        let end = writer.offset;
+        let payload_length = (end - HEADER_LENGTH) as u16;
+        let buffer = writer.buffer;
+        if buffer.len() < end + CRC_LENGTH {
+            return Err(ConfigError::BufferTooSmall);
+        }
+        buffer[..4].copy_from_slice(MAGIC);
+        buffer[4] = FORMAT_VERSION;
+        buffer[5] = sequence;
+        buffer[6..8].copy_from_slice(&payload_length.to_le_bytes());
+        let crc = crc32(&buffer[..end]);
+        buffer[end..end + CRC_LENGTH].copy_from_slice(&crc.to_le_bytes());
+
+        Ok(end + CRC_LENGTH)
+    }
+
+    /// Read a record back, anything missing from an older, shorter record comes from `defaults`.
+    pub fn decode(record: &[u8], defaults: &NodeConfig) -> Result<NodeConfig, ConfigError> {
+        if record.len() < HEADER_LENGTH {
+            return Err(ConfigError::BadLength);
+        }
+        if record[..HEADER_LENGTH].iter().all(|byte| *byte == 0xff) {
+            return Err(ConfigError::Empty);
+        }
+        if &record[..4] != MAGIC {
+            return Err(ConfigError::BadMagic);
+        }
+        if record[4] != FORMAT_VERSION {
+            return Err(ConfigError::UnsupportedVersion(record[4]));
+        }
+
+        let end = HEADER_LENGTH + u16::from_le_bytes([record[6], record[7]]) as usize;
+        if record.len() < end + CRC_LENGTH {
+            return Err(ConfigError::BadLength);
+        }
+        let mut crc = [0_u8; CRC_LENGTH];
+        crc.copy_from_slice(&record[end..end + CRC_LENGTH]);
+        if crc32(&record[..end]) != u32::from_le_bytes(crc) {
+            return Err(ConfigError::BadChecksum);
+        }
+
+        let mut reader = Reader { payload: &record[HEADER_LENGTH..end] };
+        let mut config = defaults.clone();
+        if let Some(wifi_network) = reader.string()? {
+            config.wifi_network = wifi_network;
+        }
+        if let Some(wifi_password) = reader.string()? {
+            config.wifi_password = wifi_password;
+        }
+        if let Some(secret_key) = reader.array() {
+            config.secret_key = secret_key;
+        }
+        if let Some(multicast_ip) = reader.array() {
+            config.multicast_ip = u32::from_le_bytes(multicast_ip);
+        }
+        if let Some(multicast_port) = reader.array() {
+            config.multicast_port = u16::from_le_bytes(multicast_port);
+        }
+        if let Some(node_port) = reader.array() {
+            config.node_port = u16::from_le_bytes(node_port);
+        }
+        if let Some(server_port) = reader.array() {
+            config.server_port = u16::from_le_bytes(server_port);
+        }
+        if let Some(fault_tolerance) = reader.array() {
+            config.fault_tolerance = u16::from_le_bytes(fault_tolerance);
+        }
+        if let Some([relay_active_high]) = reader.array() {
+            config.relay_active_high = relay_active_high != 0;
+        }
+        if let Some([encrypt_session]) = reader.array() {
+            config.encrypt_session = encrypt_session != 0;
+        }
+        if let Some(previous_key) = reader.array() {
+            config.previous_key = previous_key;
+        }
+        if let Some(previous_key_grace_secs) = reader.array() {
+            config.previous_key_grace_secs = u32::from_le_bytes(previous_key_grace_secs);
+        }
+        if let Some(force_off_hold_ms) = reader.array() {
+            config.force_off_hold_ms = u32::from_le_bytes(force_off_hold_ms);
+        }
+        if let Some(power_press_ms) = reader.array() {
+            config.power_press_ms = u32::from_le_bytes(power_press_ms);
+        }
+        if let Some(reset_press_ms) = reader.array() {
+            config.reset_press_ms = u32::from_le_bytes(reset_press_ms);
+        }
+        if let Some(settle_ms) = reader.array() {
+            config.settle_ms = u32::from_le_bytes(settle_ms);
+        }
+        if let Some(verify_timeout_ms) = reader.array() {
+            config.verify_timeout_ms = u32::from_le_bytes(verify_timeout_ms);
+        }
+        if let Some([retry_press]) = reader.array() {
+            config.retry_press = retry_press != 0;
+        }
+        match reader.channels()? {
+            Some(channels) => config.channels = channels,
+            // Only the one machine back then, wired the way the relay polarity says.
+            None => {
+                if let Some(first) = config.channels.first_mut() {
+                    first.active_high = config.relay_active_high;
+                }
+            }
+        }
+        if let Some(debounce_ms) = reader.array() {
+            config.debounce_ms = u32::from_le_bytes(debounce_ms);
+        }
+        if let Some(steady_ms) = reader.array() {
+            config.steady_ms = u32::from_le_bytes(steady_ms);
+        }
+        if let Some(blink_window_ms) = reader.array() {
+            config.blink_window_ms = u32::from_le_bytes(blink_window_ms);
+        }
+        if let Some(sleep_pins) = reader.array::<{ 2 * MAX_CHANNELS }>() {
+            for (channel, sleep) in config.channels.iter_mut().zip(sleep_pins.chunks_exact(2)) {
+                channel.sleep_pin = Some(sleep[0]).filter(|pin| *pin != NO_PIN);
+                channel.sleep_active_high = sleep[1] != 0;
+            }
+        }
+        if let Some(analog) = reader.array::<{ 8 * MAX_CHANNELS }>() {
+            for (channel, analog) in config.channels.iter_mut().zip(analog.chunks_exact(8)) {
+                let on_mv = u16::from_le_bytes([analog[1], analog[2]]);
+                let off_mv = u16::from_le_bytes([analog[3], analog[4]]);
+                channel.analog = (analog[0] != 0).then_some(AnalogSense { on_mv, off_mv });
+                let milliohms = u16::from_le_bytes([analog[6], analog[7]]);
+                let shunt = Shunt { pin: analog[5], milliohms };
+                channel.shunt = Some(shunt).filter(|shunt| shunt.pin != NO_PIN);
+            }
+        }
+        if let Some(wake) = reader.array::<{ 14 * MAX_CHANNELS }>() {
+            for (channel, wake) in config.channels.iter_mut().zip(wake.chunks_exact(14)) {
+                let (mac_address, password) = wake.split_at(7);
+                let mut wake_on_lan = WakeOnLan { mac_address: [0; 6], password: None };
+                wake_on_lan.mac_address.copy_from_slice(&mac_address[1..]);
+                if password[0] != 0 {
+                    let mut given = [0_u8; 6];
+                    given.copy_from_slice(&password[1..]);
+                    wake_on_lan.password = Some(given);
+                }
+                channel.wake_on_lan = (mac_address[0] != 0).then_some(wake_on_lan);
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// The sequence of a record that decoded fine.
+pub fn record_sequence(record: &[u8]) -> u8 {
+    record.get(5).copied().unwrap_or(0)
+}
+
+/// Whether `sequence` was written after `than`, the sequence wraps around.
+pub fn is_newer(sequence: u8, than: u8) -> bool {
+    (sequence.wrapping_sub(than) as i8) > 0
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    offset: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), ConfigError> {
+        let end = self.offset + bytes.len();
+        if self.buffer.len() < end {
+            return Err(ConfigError::BufferTooSmall);
+        }
+        self.buffer[self.offset..end].copy_from_slice(bytes);
+        self.offset = end;
+        Ok(())
+    }
+
+    // Strings take their whole capacity, prefixed by their actual length.
+    fn string(&mut self, string: &str, capacity: usize) -> Result<(), ConfigError> {
+        self.bytes(&[string.len() as u8])?;
+        self.bytes(string.as_bytes())?;
+        for _ in string.len()..capacity {
+            self.bytes(&[0])?;
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    payload: &'a [u8],
+}
+
+impl Reader<'_> {
+    // None once the payload ran out, the record is from before this field existed.
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        if self.payload.len() < N {
+            self.payload = &[];
+            return None;
+        }
+        let mut array = [0_u8; N];
+        array.copy_from_slice(&self.payload[..N]);
+        self.payload = &self.payload[N..];
+        Some(array)
+    }
+
+    fn channels(&mut self) -> Result<Option<Vec<Channel, MAX_CHANNELS>>, ConfigError> {
+        let Some([count]) = self.array::<1>() else {
+            return Ok(None);
+        };
+        // The first machine always stays, a table without it can only be a broken record.
+        if count == 0 || count as usize > MAX_CHANNELS {
+            return Err(ConfigError::BadLength);
+        }
+
+        let mut channels = Vec::new();
+        for index in 0..MAX_CHANNELS {
+            let name = self.string()?;
+            let pins = self.array::<4>();
+            let (Some(name), Some([power_pin, reset_pin, state_pin, active_high])) = (name, pins) else {
+                return Ok(None);
+            };
+            if index < count as usize {
+                let active_high = active_high != 0;
+                // Fits, the count was checked.
+                let _ = channels.push(Channel {
+                    name,
+                    power_pin,
+                    reset_pin,
+                    state_pin,
+                    active_high,
+                    sleep_pin: None,
+                    sleep_active_high: false,
+                    analog: None,
+                    shunt: None,
+                    wake_on_lan: None,
+                });
+            }
+        }
+        Ok(Some(channels))
+    }
+
+    fn string<const N: usize>(&mut self) -> Result<Option<String<N>>, ConfigError> {
+        let Some([length]) = self.array::<1>() else {
+            return Ok(None);
+        };
+        let Some(field) = self.array::<N>() else {
+            return Ok(None);
+        };
+
+        let text = field
+            .get(..length as usize)
+            .and_then(|bytes| core::str::from_utf8(bytes).ok())
+            .ok_or(ConfigError::BadString)?;
+        String::try_from(text).map(Some).map_err(|_| ConfigError::BadString)
+    }
+}
+
+/// CRC-32 (IEEE), bit by bit, it only runs at boot and on saves.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,222 @@
+use heapless::{ String, Vec };
+use pibow_config::{
+    channels_fit,
+    crc32,
+    is_newer,
+    record_sequence,
+    AnalogSense,
+    Channel,
+    ConfigError,
+    NodeConfig,
+    Shunt,
+    WakeOnLan,
+    MAX_RECORD_LENGTH,
+};
+
+
+// The build's defaults on the "ssid" network, wired to one machine.
+fn example() -> NodeConfig {
+    NodeConfig {
+        wifi_network: String::try_from("ssid").unwrap(),
+        wifi_password: String::try_from("password").unwrap(),
+        secret_key: [0_u8; 32],
+        multicast_ip: 3758096511,
+        multicast_port: 4265,
+        node_port: 5325,
+        server_port: 7325,
+        fault_tolerance: 5,
+        relay_active_high: false,
+        encrypt_session: false,
+        previous_key: [0_u8; 32],
+        previous_key_grace_secs: 0,
+        force_off_hold_ms: 8000,
+        power_press_ms: 500,
+        reset_press_ms: 500,
+        settle_ms: 250,
+        verify_timeout_ms: 30000,
+        retry_press: false,
+        channels: Vec::from_slice(&[example_channel()]).unwrap(),
+        debounce_ms: 50,
+        steady_ms: 2000,
+        blink_window_ms: 5000,
+    }
+}
+
+// A machine on GPIO 14 to 16, with nothing but its power, reset and state pins.
+fn example_channel() -> Channel {
+    Channel {
+        name: String::try_from("main").unwrap(),
+        power_pin: 14,
+        reset_pin: 15,
+        state_pin: 16,
+        active_high: false,
+        sleep_pin: None,
+        sleep_active_high: false,
+        analog: None,
+        shunt: None,
+        wake_on_lan: None,
+    }
+}
+
+#[test]
+fn record_round_trips() {
+    let mut config = example();
+    config.wifi_network = "Rack 4".try_into().unwrap();
+    config.secret_key = [7_u8; 32];
+    config.relay_active_high = true;
+    config.encrypt_session = true;
+    config.previous_key = [8_u8; 32];
+    config.previous_key_grace_secs = 3600;
+    config.force_off_hold_ms = 10_000;
+    config.power_press_ms = 300;
+    config.settle_ms = 0;
+    config.steady_ms = 1500;
+
+    let mut record = [0xff_u8; MAX_RECORD_LENGTH];
+    let length = config.encode(&mut record).unwrap();
+    assert_eq!(&record[..5], b"PBCF\x01");
+    assert_eq!(NodeConfig::decode(&record[..length], &example()), Ok(config));
+}
+
+#[test]
+fn erased_and_damaged_records_are_rejected() {
+    let defaults = example();
+    let mut record = [0xff_u8; MAX_RECORD_LENGTH];
+    assert_eq!(NodeConfig::decode(&record, &defaults), Err(ConfigError::Empty));
+
+    let length = defaults.encode(&mut record).unwrap();
+    record[20] ^= 1;
+    assert_eq!(NodeConfig::decode(&record[..length], &defaults), Err(ConfigError::BadChecksum));
+
+    record[4] = 9;
+    assert_eq!(NodeConfig::decode(&record, &defaults), Err(ConfigError::UnsupportedVersion(9)));
+}
+
+#[test]
+fn shorter_records_keep_the_defaults() {
+    let mut config = example();
+    config.server_port = 9000;
+    config.fault_tolerance = 1;
+    let mut record = [0_u8; MAX_RECORD_LENGTH];
+    config.encode(&mut record).unwrap();
+
+    // Cut the record right after the server port, like firmware that didn't know the rest yet.
+    let payload_length = 1 + 32 + 1 + 64 + 32 + 4 + 2 + 2 + 2;
+    record[6..8].copy_from_slice(&(payload_length as u16).to_le_bytes());
+    let end = 8 + payload_length;
+    let crc = crc32(&record[..end]);
+    record[end..end + 4].copy_from_slice(&crc.to_le_bytes());
+
+    let decoded = NodeConfig::decode(&record, &example()).unwrap();
+    assert_eq!(decoded.server_port, 9000);
+    assert_eq!(decoded.fault_tolerance, 5);
+}
+
+#[test]
+fn sequence_tells_the_newer_copy() {
+    let mut record = [0xff_u8; MAX_RECORD_LENGTH];
+    let length = example().encode_with_sequence(7, &mut record).unwrap();
+    assert_eq!(record_sequence(&record), 7);
+    assert_eq!(NodeConfig::decode(&record[..length], &example()), Ok(example()));
+
+    assert!(is_newer(8, 7));
+    assert!(!is_newer(7, 7));
+    assert!(!is_newer(6, 7));
+    // Right past the wrap.
+    assert!(is_newer(1, 255));
+}
+
+#[test]
+fn channel_table_round_trips_and_older_records_keep_one_machine() {
+    let mut config = example();
+    let second = Channel { name: "rack b".try_into().unwrap(), power_pin: 2, ..example_channel() };
+    config.channels.push(second).unwrap();
+    let mut record = [0_u8; MAX_RECORD_LENGTH];
+    let length = config.encode(&mut record).unwrap();
+    assert_eq!(NodeConfig::decode(&record[..length], &example()), Ok(config.clone()));
+
+    // Cut right before the table, the first channel takes the old relay polarity.
+    config.relay_active_high = true;
+    config.encode(&mut record).unwrap();
+    let table_onwards = 1 + 4 * (1 + 16 + 4) + 3 * 4 + 4 * 2 + 4 * 8 + 4 * 14;
+    let payload_length = u16::from_le_bytes([record[6], record[7]]) as usize - table_onwards;
+    record[6..8].copy_from_slice(&(payload_length as u16).to_le_bytes());
+    let end = 8 + payload_length;
+    let crc = crc32(&record[..end]);
+    record[end..end + 4].copy_from_slice(&crc.to_le_bytes());
+    let decoded = NodeConfig::decode(&record, &example()).unwrap();
+    assert_eq!(decoded.channels.len(), 1);
+    assert!(decoded.channels[0].active_high);
+}
+
+#[test]
+fn a_channel_table_without_machines_is_rejected() {
+    let config = NodeConfig { channels: Default::default(), ..example() };
+    let mut record = [0_u8; MAX_RECORD_LENGTH];
+    let length = config.encode(&mut record).unwrap();
+    let decoded = NodeConfig::decode(&record[..length], &example());
+    assert_eq!(decoded, Err(ConfigError::BadLength));
+}
+
+#[test]
+fn sleep_pins_round_trip_and_take_a_pin_of_their_own() {
+    let mut config = example();
+    config.channels[0].sleep_pin = Some(17);
+    let mut record = [0_u8; MAX_RECORD_LENGTH];
+    let length = config.encode(&mut record).unwrap();
+    assert_eq!(NodeConfig::decode(&record[..length], &example()), Ok(config.clone()));
+    assert!(channels_fit(&config.channels));
+
+    config.channels[0].sleep_pin = Some(15);
+    assert!(!channels_fit(&config.channels));
+}
+
+#[test]
+fn analog_sensing_round_trips_and_stays_on_adc_pins() {
+    let mut config = example();
+    config.channels[0].state_pin = 26;
+    config.channels[0].analog = Some(AnalogSense { on_mv: 1_500, off_mv: 800 });
+    config.channels[0].shunt = Some(Shunt { pin: 27, milliohms: 20 });
+    let mut record = [0_u8; MAX_RECORD_LENGTH];
+    let length = config.encode(&mut record).unwrap();
+    assert_eq!(NodeConfig::decode(&record[..length], &example()), Ok(config.clone()));
+    assert!(channels_fit(&config.channels));
+
+    config.channels[0].shunt = Some(Shunt { pin: 26, milliohms: 20 });
+    assert!(!channels_fit(&config.channels));
+    config.channels[0].shunt = Some(Shunt { pin: 28, milliohms: 20 });
+    config.channels[0].analog = None;
+    assert!(!channels_fit(&config.channels));
+    config.channels[0].analog = Some(AnalogSense { on_mv: 1_500, off_mv: 800 });
+    config.channels[0].state_pin = 16;
+    assert!(!channels_fit(&config.channels));
+}
+
+#[test]
+fn wake_on_lan_round_trips_with_or_without_a_password() {
+    let mut config = example();
+    let second = Channel { power_pin: 2, reset_pin: 3, state_pin: 4, ..example_channel() };
+    config.channels.push(second).unwrap();
+    config.channels[0].wake_on_lan = Some(WakeOnLan { mac_address: [2, 0, 0, 0, 0, 9], password: None });
+    config.channels[1].wake_on_lan = Some(WakeOnLan {
+        mac_address: [2, 0, 0, 0, 0, 10],
+        password: Some([1, 2, 3, 4, 5, 6]),
+    });
+    let mut record = [0_u8; MAX_RECORD_LENGTH];
+    let length = config.encode(&mut record).unwrap();
+    assert_eq!(NodeConfig::decode(&record[..length], &example()), Ok(config));
+}
+
+#[test]
+fn channels_need_pins_of_their_own() {
+    let mut config = example();
+    assert!(channels_fit(&config.channels));
+
+    let second = Channel { power_pin: 2, reset_pin: 3, state_pin: 16, ..example_channel() };
+    config.channels.push(second).unwrap();
+    assert!(!channels_fit(&config.channels));
+    config.channels[1].state_pin = 24;
+    assert!(!channels_fit(&config.channels));
+    config.channels[1].state_pin = 4;
+    assert!(channels_fit(&config.channels));
+}
@@ -0,0 +1,192 @@
+//! Turns a weak, biased noise source into random bytes worth trusting.
+//!
+//! Raw samples go through a [`HealthTest`] and into a [`Pool`], which conditions them through
+//! blake3 into a seed. The seed keys a [`Csprng`], reseeded with fresh samples before anything
+//! important gets drawn from it. Nothing in here knows where the samples come from, the firmware
+//! brings the ring oscillator and whatever else it can time.
+
+#![no_std]
+
+// blake3 wants a hardcoded, globally unique context string for every derived key.
+const SEED_CONTEXT: &str = "pibow 2025 entropy pool seed v1";
+const RESEED_CONTEXT: &str = "pibow 2025 entropy reseed v1";
+
+/// Identical samples in a row that mean the source is stuck.
+///
+/// SP 800-90B's repetition count test, at one bit of min-entropy per sample and a false alarm
+/// rate of 2^-20: `1 + 20 / 1`.
+pub const REPETITION_CUTOFF: u16 = 21;
+/// The adaptive proportion test looks at this many samples at a time.
+pub const PROPORTION_WINDOW: u16 = 512;
+/// How many samples of a window may match its first one, at the same entropy and alarm rate.
+pub const PROPORTION_CUTOFF: u16 = 410;
+
+/// Watches raw samples for a source that got stuck or badly skewed. Once it failed, it stays
+/// failed until [`HealthTest::reset`].
+#[derive(Debug, Clone)]
+pub struct HealthTest {
+    last: Option<u8>,
+    repeats: u16,
+    window_first: u8,
+    window_seen: u16,
+    window_matches: u16,
+    failed: bool,
+}
+
+impl HealthTest {
+    pub const fn new() -> Self {
+        HealthTest {
+            last: None,
+            repeats: 0,
+            window_first: 0,
+            window_seen: 0,
+            window_matches: 0,
+            failed: false,
+        }
+    }
+
+    /// Check one more sample, returns false once the source looks unhealthy.
+    pub fn feed(&mut self, sample: u8) -> bool {
+        // Repetition count: the same value over and over.
+        if self.last == Some(sample) {
+            self.repeats += 1;
+        } else {
+            self.last = Some(sample);
+            self.repeats = 1;
+        }
+        if self.repeats >= REPETITION_CUTOFF {
+            self.failed = true;
+        }
+
+        // Adaptive proportion: one value way too often, even when it's not in a row.
+        if self.window_seen == 0 {
+            self.window_first = sample;
+            self.window_matches = 0;
+        }
+        if sample == self.window_first {
+            self.window_matches += 1;
+        }
+        self.window_seen += 1;
+        if self.window_matches >= PROPORTION_CUTOFF {
+            self.failed = true;
+        }
+        if self.window_seen == PROPORTION_WINDOW {
+            self.window_seen = 0;
+        }
+
+        !self.failed
+    }
+
+    pub fn failed(&self) -> bool {
+        self.failed
+    }
+
+    pub fn reset(&mut self) {
+        *self = HealthTest::new();
+    }
+}
+
+impl Default for HealthTest {
+    fn default() -> Self {
+        HealthTest::new()
+    }
+}
+
+/// The source failed its health test, whatever it gave can't be trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unhealthy;
+
+/// Gathers raw samples and anything else unpredictable, and conditions them into a seed.
+#[derive(Debug, Clone)]
+pub struct Pool {
+    hasher: blake3::Hasher,
+    health: HealthTest,
+    samples: usize,
+}
+
+impl Pool {
+    pub fn new() -> Self {
+        Pool {
+            hasher: blake3::Hasher::new_derive_key(SEED_CONTEXT),
+            health: HealthTest::new(),
+            samples: 0,
+        }
+    }
+
+    /// A raw sample from the noise source, health tested.
+    pub fn add_sample(&mut self, sample: u8) {
+        self.health.feed(sample);
+        self.hasher.update(&[sample]);
+        self.samples += 1;
+    }
+
+    /// Anything else that's hard to guess, like timings. It can only help, so it's not tested.
+    pub fn stir(&mut self, bytes: &[u8]) {
+        self.hasher.update(&(bytes.len() as u32).to_le_bytes());
+        self.hasher.update(bytes);
+    }
+
+    /// Start the health test over, keeping everything mixed in so far. For another go once the
+    /// source failed, a source that recovered passes on fresh samples.
+    pub fn retest(&mut self) {
+        self.health.reset();
+    }
+
+    /// Raw samples so far.
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// The conditioned seed, refused when the raw samples failed their health test.
+    pub fn seed(&self) -> Result<[u8; 32], Unhealthy> {
+        if self.health.failed() {
+            return Err(Unhealthy);
+        }
+        Ok(self.seed_unchecked())
+    }
+
+    /// The seed no matter what the health test says, for what can live with a bad source.
+    pub fn seed_unchecked(&self) -> [u8; 32] {
+        *self.hasher.finalize().as_bytes()
+    }
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Pool::new()
+    }
+}
+
+/// blake3 in keyed XOF mode, ratcheting its key forward on every draw, so what it gave out
+/// before can't be worked out from its state after.
+#[derive(Clone)]
+pub struct Csprng {
+    key: [u8; 32],
+}
+
+impl Csprng {
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Csprng { key: seed }
+    }
+
+    /// Mix a fresh seed in, on top of everything before it.
+    pub fn reseed(&mut self, seed: &[u8; 32]) {
+        self.key = *blake3::Hasher::new_derive_key(RESEED_CONTEXT)
+            .update(&self.key)
+            .update(seed)
+            .finalize()
+            .as_bytes();
+    }
+
+    pub fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let mut output = blake3::Hasher::new_keyed(&self.key).finalize_xof();
+        output.fill(&mut self.key);
+        output.fill(dest);
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0_u8; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+}
@@ -0,0 +1,98 @@
+use pibow_entropy::{
+    Csprng,
+    HealthTest,
+    Pool,
+    Unhealthy,
+    PROPORTION_WINDOW,
+    REPETITION_CUTOFF,
+};
+
+// Cheap and varied enough to pass the health tests, not random at all.
+fn varied(count: usize) -> impl Iterator<Item = u8> {
+    (0..count).map(|index| (index as u32).wrapping_mul(2_654_435_761).to_be_bytes()[0])
+}
+
+#[test]
+fn a_stuck_source_fails_the_repetition_test() {
+    let mut health = HealthTest::new();
+    for _ in 1..REPETITION_CUTOFF {
+        assert!(health.feed(7));
+    }
+    assert!(!health.feed(7));
+    // It stays failed even once the source looks fine again.
+    assert!(!health.feed(8));
+
+    health.reset();
+    assert!(health.feed(7));
+}
+
+#[test]
+fn a_skewed_source_fails_the_proportion_test() {
+    let mut health = HealthTest::new();
+    // Never the same twice in a row, still mostly zeros.
+    for index in 0..PROPORTION_WINDOW {
+        health.feed(if index % 10 == 9 { 1 } else { 0 });
+    }
+    assert!(health.failed());
+
+    let mut health = HealthTest::new();
+    assert!(varied(4096).all(|sample| health.feed(sample)));
+}
+
+#[test]
+fn the_pool_refuses_a_stuck_source() {
+    let mut pool = Pool::new();
+    for sample in varied(256) {
+        pool.add_sample(sample);
+    }
+    let seed = pool.seed().unwrap();
+    assert_eq!(pool.samples(), 256);
+
+    // Anything stirred in changes the seed.
+    pool.stir(&[1, 2, 3]);
+    assert_ne!(pool.seed().unwrap(), seed);
+
+    for _ in 0..REPETITION_CUTOFF {
+        pool.add_sample(0);
+    }
+    assert_eq!(pool.seed(), Err(Unhealthy));
+}
+
+#[test]
+fn a_retested_pool_keeps_what_was_stirred_in() {
+    let mut pool = Pool::new();
+    pool.stir(&[1, 2, 3]);
+    for _ in 0..REPETITION_CUTOFF {
+        pool.add_sample(0);
+    }
+    assert_eq!(pool.seed(), Err(Unhealthy));
+
+    pool.retest();
+    let mut fresh = Pool::new();
+    for sample in varied(256) {
+        pool.add_sample(sample);
+        fresh.add_sample(sample);
+    }
+    assert_ne!(pool.seed().unwrap(), fresh.seed().unwrap());
+}
+
+#[test]
+fn the_generator_never_repeats_itself() {
+    let mut rng = Csprng::from_seed([1_u8; 32]);
+    let mut first = [0_u8; 64];
+    let mut second = [0_u8; 64];
+    rng.fill_bytes(&mut first);
+    rng.fill_bytes(&mut second);
+    assert_ne!(first, second);
+
+    // Same seed, same stream, until one gets reseeded.
+    let mut same = Csprng::from_seed([1_u8; 32]);
+    let mut again = [0_u8; 64];
+    same.fill_bytes(&mut again);
+    assert_eq!(again, first);
+
+    same.reseed(&[2_u8; 32]);
+    same.fill_bytes(&mut again);
+    assert_ne!(again, second);
+    assert_ne!(rng.next_u64(), same.next_u64());
+}
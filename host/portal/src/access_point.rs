@@ -0,0 +1,28 @@
+use core::fmt::Write;
+
+use heapless::String;
+
+// blake3 wants a hardcoded, globally unique context string for every derived key.
+const PASSWORD_CONTEXT: &str = "pibow 2025 setup access point password v1";
+
+/// WPA2 wants 8 to 63 characters, 16 hex digits carry 64 bits.
+pub const PASSWORD_LENGTH: usize = 16;
+
+/// The setup access point's name, `pibow-` and the end of the MAC address.
+pub fn access_point_name(mac_address: &[u8; 6]) -> String<16> {
+    let mut name = String::new();
+    let _ = write!(name, "pibow-{:02x}{:02x}{:02x}", mac_address[3], mac_address[4], mac_address[5]);
+    name
+}
+
+/// The setup access point's password, derived from the node's key. Every node has its own, and only
+/// whoever holds the key (the node's serial shell, or `setup-password` on the server that issued
+/// it) can tell it.
+pub fn access_point_password(key: &[u8; 32]) -> String<PASSWORD_LENGTH> {
+    let derived = blake3::Hasher::new_derive_key(PASSWORD_CONTEXT).update(key).finalize();
+    let mut password = String::new();
+    for byte in &derived.as_bytes()[..PASSWORD_LENGTH / 2] {
+        let _ = write!(password, "{byte:02x}");
+    }
+    password
+}
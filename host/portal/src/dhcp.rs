@@ -0,0 +1,106 @@
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+// Fixed part of a BOOTP message, then the magic cookie in front of the options.
+const FIXED_LENGTH: usize = 236;
+const COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_START: usize = FIXED_LENGTH + COOKIE.len();
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+
+const LEASE_SECS: u32 = 3600;
+
+/// Enough for any reply this writes.
+pub const MAX_REPLY_LENGTH: usize = OPTIONS_START + 48;
+
+/// Answer a DISCOVER with an OFFER and a REQUEST with an ACK.
+///
+/// There's no lease table, a client always gets the same address, picked from its hardware
+/// address. The portal only ever sees a couple of clients at once, collisions don't matter.
+/// Returns the length written into `reply`, to be broadcast to the client port.
+pub fn reply(request: &[u8], server: [u8; 4], reply: &mut [u8]) -> Option<usize> {
+    if request.len() < OPTIONS_START || request[0] != 1 {
+        return None;
+    }
+    if request[OPTIONS_START - 4..OPTIONS_START] != COOKIE {
+        return None;
+    }
+    let reply_type = match message_type(&request[OPTIONS_START..])? {
+        DISCOVER => OFFER,
+        REQUEST => ACK,
+        _ => {
+            return None;
+        }
+    };
+
+    let hardware_address = &request[28..34];
+    // Anyone in range picks the address, it has to wrap rather than overflow.
+    let hash = hardware_address
+        .iter()
+        .fold(0_u32, |sum, byte| sum.wrapping_mul(31).wrapping_add(u32::from(*byte)));
+    let host = 2 + (hash % 250) as u8;
+    let client = [server[0], server[1], server[2], host];
+
+    let reply = reply.get_mut(..MAX_REPLY_LENGTH)?;
+    reply.fill(0);
+    // Boot reply, with the client's hardware type, address length, transaction and flags.
+    reply[0] = 2;
+    reply[1..3].copy_from_slice(&request[1..3]);
+    reply[4..8].copy_from_slice(&request[4..8]);
+    reply[10..12].copy_from_slice(&request[10..12]);
+    reply[16..20].copy_from_slice(&client);
+    reply[20..24].copy_from_slice(&server);
+    reply[28..44].copy_from_slice(&request[28..44]);
+    reply[OPTIONS_START - 4..OPTIONS_START].copy_from_slice(&COOKIE);
+
+    let mut options = Options { buffer: &mut reply[OPTIONS_START..], offset: 0 };
+    options.push(53, &[reply_type]);
+    options.push(54, &server);
+    options.push(51, &LEASE_SECS.to_be_bytes());
+    options.push(1, &[255, 255, 255, 0]);
+    options.push(3, &server);
+    options.push(6, &server);
+    let end = OPTIONS_START + options.offset;
+    reply[end] = 255;
+
+    Some(end + 1)
+}
+
+fn message_type(mut options: &[u8]) -> Option<u8> {
+    loop {
+        match *options.first()? {
+            // Padding.
+            0 => {
+                options = &options[1..];
+            }
+            255 => {
+                return None;
+            }
+            code => {
+                let length = *options.get(1)? as usize;
+                let value = options.get(2..2 + length)?;
+                if code == 53 && length == 1 {
+                    return Some(value[0]);
+                }
+                options = &options[2 + length..];
+            }
+        }
+    }
+}
+
+struct Options<'a> {
+    buffer: &'a mut [u8],
+    offset: usize,
+}
+
+impl Options<'_> {
+    fn push(&mut self, code: u8, value: &[u8]) {
+        self.buffer[self.offset] = code;
+        self.buffer[self.offset + 1] = value.len() as u8;
+        self.buffer[self.offset + 2..self.offset + 2 + value.len()].copy_from_slice(value);
+        self.offset += 2 + value.len();
+    }
+}
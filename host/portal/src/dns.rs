@@ -0,0 +1,56 @@
+pub const DNS_PORT: u16 = 53;
+
+const HEADER_LENGTH: usize = 12;
+// A pointer back at the question's name, then type A, class IN, TTL 60, 4 bytes of address.
+const ANSWER: [u8; 12] = [0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4];
+
+/// Answer any A query with `address`, everything else gets an empty answer.
+/// Returns the length written into `response`, None for anything that isn't a plain query.
+pub fn answer(query: &[u8], address: [u8; 4], response: &mut [u8]) -> Option<usize> {
+    if query.len() < HEADER_LENGTH {
+        return None;
+    }
+    // Responses and anything but a standard query.
+    if query[2] & 0xf8 != 0 {
+        return None;
+    }
+    // Exactly one question, that's what every resolver sends.
+    if query[4..6] != [0, 1] {
+        return None;
+    }
+
+    // Walk the name labels to find where the question ends.
+    let mut offset = HEADER_LENGTH;
+    loop {
+        let length = *query.get(offset)? as usize;
+        offset += 1;
+        if length == 0 {
+            break;
+        }
+        if length & 0xc0 != 0 {
+            return None;
+        }
+        offset += length;
+    }
+    let question_end = offset + 4;
+    let question = query.get(HEADER_LENGTH..question_end)?;
+    let is_a = question[question.len() - 4..question.len() - 2] == [0, 1];
+
+    let length = question_end + if is_a { ANSWER.len() + 4 } else { 0 };
+    let response = response.get_mut(..length)?;
+
+    response[..2].copy_from_slice(&query[..2]);
+    // Response, authoritative, keep the recursion desired bit.
+    response[2] = 0x84 | (query[2] & 0x01);
+    response[3] = 0;
+    response[4..6].copy_from_slice(&[0, 1]);
+    response[6..8].copy_from_slice(&[0, is_a as u8]);
+    response[8..12].fill(0);
+    response[HEADER_LENGTH..question_end].copy_from_slice(question);
+    if is_a {
+        response[question_end..question_end + ANSWER.len()].copy_from_slice(&ANSWER);
+        response[question_end + ANSWER.len()..].copy_from_slice(&address);
+    }
+
+    Some(length)
+}
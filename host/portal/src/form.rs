@@ -0,0 +1,205 @@
+use core::{ fmt::{ self, Write }, net::Ipv4Addr };
+
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use heapless::String;
+use pibow_config::{ NodeConfig, PASSWORD_CAPACITY, SSID_CAPACITY };
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormError {
+    /// Broken percent encoding, or not UTF-8 once decoded.
+    BadEncoding,
+    /// The form left the Wifi network empty, there's nothing to join then.
+    MissingNetwork,
+    /// This field doesn't fit in the config.
+    TooLong(&'static str),
+    BadKey,
+    BadNumber(&'static str),
+    /// The key or the server fields changed, without the key the node has now.
+    WrongCurrentKey,
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::BadEncoding => formatter.write_str("The form didn't come through right"),
+            FormError::MissingNetwork => formatter.write_str("The Wifi network is required"),
+            FormError::TooLong(field) => write!(formatter, "The {field} is too long"),
+            FormError::BadKey => formatter.write_str("The secret key must be 32 bytes of base64"),
+            FormError::BadNumber(field) => write!(formatter, "The {field} isn't valid"),
+            FormError::WrongCurrentKey => {
+                formatter.write_str("Changing the key or the server takes the current key")
+            }
+        }
+    }
+}
+
+/// Apply an `application/x-www-form-urlencoded` body on top of `config`.
+/// Empty fields keep what's there, so the form only needs what changes. The key and the server
+/// fields only change along with the current key, anyone in radio range might be filling it in.
+pub fn apply_form(body: &[u8], config: &mut NodeConfig) -> Result<(), FormError> {
+    // Nothing gets applied before the whole form checks out.
+    let mut updated = config.clone();
+    let mut current_key = None;
+    let mut protected = false;
+
+    for pair in body.split(|byte| *byte == b'&') {
+        let mut parts = pair.splitn(2, |byte| *byte == b'=');
+        let name = parts.next().unwrap_or_default();
+        let value: String<128> = decode(parts.next().unwrap_or_default())?;
+        if value.is_empty() {
+            continue;
+        }
+
+        match name {
+            b"ssid" => {
+                updated.wifi_network = String::try_from(value.as_str()).map_err(|_| {
+                    FormError::TooLong("Wifi network")
+                })?;
+            }
+            b"password" => {
+                updated.wifi_password = String::try_from(value.as_str()).map_err(|_| {
+                    FormError::TooLong("Wifi password")
+                })?;
+            }
+            b"current_key" => {
+                current_key = Some(decode_key(&value).ok_or(FormError::WrongCurrentKey)?);
+            }
+            b"key" => {
+                updated.secret_key = decode_key(&value).ok_or(FormError::BadKey)?;
+                protected = true;
+            }
+            b"multicast_ip" => {
+                protected = true;
+                match value.trim().parse::<Ipv4Addr>() {
+                    Ok(address) if address.is_multicast() => {
+                        updated.multicast_ip = address.to_bits();
+                    }
+                    _ => {
+                        return Err(FormError::BadNumber("multicast group"));
+                    }
+                }
+            }
+            b"multicast_port" => {
+                protected = true;
+                updated.multicast_port = port(&value, "multicast port")?;
+            }
+            b"server_port" => {
+                protected = true;
+                updated.server_port = port(&value, "server port")?;
+            }
+            b"node_port" => {
+                protected = true;
+                updated.node_port = port(&value, "node port")?;
+            }
+            // Unknown fields are fine, a newer page might send more.
+            _ => {}
+        }
+    }
+
+    if updated.wifi_network.is_empty() {
+        return Err(FormError::MissingNetwork);
+    }
+    // A node without a key yet has nothing to protect.
+    let keyed = config.secret_key != [0_u8; 32];
+    if protected && keyed && !current_key.is_some_and(|key| same_key(&key, &config.secret_key)) {
+        return Err(FormError::WrongCurrentKey);
+    }
+
+    *config = updated;
+    Ok(())
+}
+
+fn decode_key(value: &str) -> Option<[u8; 32]> {
+    let mut key = [0_u8; 48];
+    match STANDARD.decode_slice(value.trim(), &mut key) {
+        Ok(32) => key[..32].try_into().ok(),
+        _ => None,
+    }
+}
+
+// In constant time, it's a guess at the node's key.
+fn same_key(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+fn port(value: &str, field: &'static str) -> Result<u16, FormError> {
+    match value.trim().parse() {
+        Ok(0) | Err(_) => Err(FormError::BadNumber(field)),
+        Ok(port) => Ok(port),
+    }
+}
+
+fn decode<const N: usize>(encoded: &[u8]) -> Result<String<N>, FormError> {
+    let mut bytes: heapless::Vec<u8, N> = heapless::Vec::new();
+    let mut index = 0;
+    while index < encoded.len() {
+        let byte = match encoded[index] {
+            b'+' => b' ',
+            b'%' => {
+                let hex = encoded.get(index + 1..index + 3).ok_or(FormError::BadEncoding)?;
+                let hex = core::str::from_utf8(hex).map_err(|_| FormError::BadEncoding)?;
+                index += 2;
+                u8::from_str_radix(hex, 16).map_err(|_| FormError::BadEncoding)?
+            }
+            byte => byte,
+        };
+        bytes.push(byte).map_err(|_| FormError::BadEncoding)?;
+        index += 1;
+    }
+    String::from_utf8(bytes).map_err(|_| FormError::BadEncoding)
+}
+
+/// The whole page, the form prefilled with anything that isn't a secret.
+pub fn render_page(config: &NodeConfig, notice: Option<&str>, out: &mut impl Write) -> fmt::Result {
+    out.write_str(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width\"><title>Pibow setup</title></head>\
+         <body><h1>Pibow setup</h1>"
+    )?;
+    if let Some(notice) = notice {
+        out.write_str("<p><b>")?;
+        escape(notice, out)?;
+        out.write_str("</b></p>")?;
+    }
+
+    out.write_str("<form method=\"post\" action=\"/save\">")?;
+    write!(out, "<p>Wifi network<br><input name=\"ssid\" maxlength=\"{SSID_CAPACITY}\" value=\"")?;
+    escape(&config.wifi_network, out)?;
+    write!(
+        out,
+        "\"></p><p>Wifi password<br>\
+         <input name=\"password\" type=\"password\" maxlength=\"{PASSWORD_CAPACITY}\"></p>"
+    )?;
+    out.write_str(
+        "<p>Secret key (base64, empty keeps the current one)<br><input name=\"key\" size=\"44\"></p>"
+    )?;
+    out.write_str(
+        "<p>Current secret key (to change the key or the server)<br>\
+         <input name=\"current_key\" type=\"password\" size=\"44\"></p>"
+    )?;
+    write!(
+        out,
+        "<p>Multicast group<br><input name=\"multicast_ip\" placeholder=\"{}\"></p>\
+         <p>Multicast port<br><input name=\"multicast_port\" placeholder=\"{}\"></p>\
+         <p>Server port<br><input name=\"server_port\" placeholder=\"{}\"></p>\
+         <p>Node port<br><input name=\"node_port\" placeholder=\"{}\"></p>",
+        Ipv4Addr::from_bits(config.multicast_ip),
+        config.multicast_port,
+        config.server_port,
+        config.node_port
+    )?;
+    out.write_str("<p><button>Save and reboot</button></p></form></body></html>")
+}
+
+fn escape(text: &str, out: &mut impl Write) -> fmt::Result {
+    for character in text.chars() {
+        match character {
+            '<' => out.write_str("&lt;")?,
+            '>' => out.write_str("&gt;")?,
+            '&' => out.write_str("&amp;")?,
+            '"' => out.write_str("&quot;")?,
+            character => out.write_char(character)?,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestHead<'a> {
+    pub method: Method,
+    pub path: &'a str,
+    pub content_length: usize,
+    /// Where the body starts in the buffer handed to [`parse_head`].
+    pub head_length: usize,
+}
+
+/// Parse the request line and headers, None until the whole head is in `buffer`
+/// (or when it's not HTTP at all, the caller gives up once its buffer is full).
+pub fn parse_head(buffer: &[u8]) -> Option<RequestHead<'_>> {
+    let head_end = buffer.windows(4).position(|window| window == b"\r\n\r\n")?;
+    let head = core::str::from_utf8(&buffer[..head_end]).ok()?;
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next()?.split(' ');
+    let method = match request_line.next()? {
+        "GET" => Method::Get,
+        "POST" => Method::Post,
+        _ => Method::Other,
+    };
+    let path = request_line.next()?;
+
+    let mut content_length = 0;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().ok()?;
+        }
+    }
+
+    Some(RequestHead { method, path, content_length, head_length: head_end + 4 })
+}
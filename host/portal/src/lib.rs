@@ -0,0 +1,29 @@
+//! The provisioning portal a node serves from its own access point.
+//!
+//! Only the parts that don't touch the network live here: a minimal DHCP server and DNS
+//! responder (every name points at the node, so phones pop the portal up by themselves),
+//! the HTTP request head, the form page and applying a submitted form to the config, and the
+//! access point's name and password.
+
+#![no_std]
+
+mod access_point;
+mod dhcp;
+mod dns;
+mod form;
+mod http;
+
+pub use access_point::{ access_point_name, access_point_password, PASSWORD_LENGTH };
+pub use dhcp::{
+    reply as dhcp_reply,
+    DHCP_CLIENT_PORT,
+    DHCP_SERVER_PORT,
+    MAX_REPLY_LENGTH as MAX_DHCP_REPLY_LENGTH,
+};
+pub use dns::{ answer as dns_answer, DNS_PORT };
+pub use form::{ apply_form, render_page, FormError };
+pub use http::{ parse_head, Method, RequestHead };
+
+/// The node's address on its own network, clients get the rest of the /24.
+pub const PORTAL_ADDRESS: [u8; 4] = [192, 168, 4, 1];
+pub const HTTP_PORT: u16 = 80;
@@ -0,0 +1,165 @@
+use heapless::{ String, Vec };
+use pibow_config::NodeConfig;
+use pibow_portal::{
+    access_point_name,
+    access_point_password,
+    apply_form,
+    dhcp_reply,
+    dns_answer,
+    parse_head,
+    FormError,
+    Method,
+    MAX_DHCP_REPLY_LENGTH,
+    PASSWORD_LENGTH,
+    PORTAL_ADDRESS,
+};
+
+// A node that was never set up, nothing to join yet. The form never touches the channels.
+fn unconfigured() -> NodeConfig {
+    NodeConfig {
+        wifi_network: String::new(),
+        wifi_password: String::new(),
+        secret_key: [0_u8; 32],
+        multicast_ip: 3758096511,
+        multicast_port: 4265,
+        node_port: 5325,
+        server_port: 7325,
+        fault_tolerance: 5,
+        relay_active_high: false,
+        encrypt_session: false,
+        previous_key: [0_u8; 32],
+        previous_key_grace_secs: 0,
+        force_off_hold_ms: 8000,
+        power_press_ms: 500,
+        reset_press_ms: 500,
+        settle_ms: 250,
+        verify_timeout_ms: 30000,
+        retry_press: false,
+        channels: Vec::new(),
+        debounce_ms: 50,
+        steady_ms: 2000,
+        blink_window_ms: 5000,
+    }
+}
+
+#[test]
+fn form_applies_only_filled_fields() {
+    let mut config = unconfigured();
+    let body = b"ssid=Rack+4%21&password=p%40ss&key=BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc%3D\
+                 &multicast_ip=&multicast_port=&server_port=8000&node_port=";
+    apply_form(body, &mut config).unwrap();
+
+    assert_eq!(config.wifi_network, "Rack 4!");
+    assert_eq!(config.wifi_password, "p@ss");
+    assert_eq!(config.secret_key, [7_u8; 32]);
+    assert_eq!(config.server_port, 8000);
+    assert_eq!(config.multicast_port, 4265);
+}
+
+#[test]
+fn bad_form_leaves_the_config_alone() {
+    let mut config = unconfigured();
+    assert_eq!(apply_form(b"password=secret", &mut config), Err(FormError::MissingNetwork));
+    assert_eq!(apply_form(b"ssid=lab&key=AAAA", &mut config), Err(FormError::BadKey));
+    assert_eq!(
+        apply_form(b"ssid=lab&multicast_ip=10.0.0.1", &mut config),
+        Err(FormError::BadNumber("multicast group"))
+    );
+    assert_eq!(config, unconfigured());
+}
+
+#[test]
+fn key_and_server_changes_take_the_current_key() {
+    let mut config = unconfigured();
+    config.secret_key = [7_u8; 32];
+    let current = "current_key=BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwc%3D";
+    let wrong = "current_key=CAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg%3D";
+
+    // The Wifi alone is fine, it still takes the key to talk to the node.
+    apply_form(b"ssid=lab", &mut config).unwrap();
+    assert_eq!(apply_form(b"ssid=lab&server_port=8000", &mut config), Err(FormError::WrongCurrentKey));
+    let body = format!("ssid=lab&server_port=8000&{wrong}");
+    assert_eq!(apply_form(body.as_bytes(), &mut config), Err(FormError::WrongCurrentKey));
+    assert_eq!(config.server_port, 7325);
+
+    let key = "key=AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE%3D";
+    let body = format!("ssid=lab&server_port=8000&{key}&{current}");
+    apply_form(body.as_bytes(), &mut config).unwrap();
+    assert_eq!(config.server_port, 8000);
+    assert_eq!(config.secret_key, [1_u8; 32]);
+}
+
+#[test]
+fn every_node_has_its_own_access_point_password() {
+    let password = access_point_password(&[7_u8; 32]);
+    assert_eq!(password.len(), PASSWORD_LENGTH);
+    assert!(password.chars().all(|character| character.is_ascii_hexdigit()));
+    assert_eq!(password, access_point_password(&[7_u8; 32]));
+    assert_ne!(password, access_point_password(&[8_u8; 32]));
+    assert_eq!(access_point_name(&[2, 0, 0, 0xab, 0xcd, 0xef]), "pibow-abcdef");
+}
+
+#[test]
+fn head_needs_the_blank_line() {
+    let request = b"POST /save HTTP/1.1\r\nHost: 192.168.4.1\r\nContent-Length: 12\r\n\r\nssid=lab";
+    assert_eq!(parse_head(&request[..40]), None);
+
+    let head = parse_head(request).unwrap();
+    assert_eq!(head.method, Method::Post);
+    assert_eq!(head.path, "/save");
+    assert_eq!(head.content_length, 12);
+    assert_eq!(&request[head.head_length..], b"ssid=lab");
+}
+
+#[test]
+fn dns_points_everything_at_the_portal() {
+    // Transaction 0x1234, recursion desired, one question: A example.com.
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+
+    let mut response = [0_u8; 512];
+    let length = dns_answer(&query, PORTAL_ADDRESS, &mut response).unwrap();
+    assert_eq!(&response[..2], &[0x12, 0x34]);
+    assert_eq!(response[2] & 0x80, 0x80);
+    assert_eq!(&response[6..8], &[0, 1]);
+    assert_eq!(&response[length - 4..length], &PORTAL_ADDRESS);
+}
+
+#[test]
+fn dhcp_offers_then_acks_the_same_address() {
+    let mut request = vec![0_u8; 240];
+    request[0] = 1;
+    request[1] = 1;
+    request[2] = 6;
+    request[4..8].copy_from_slice(&[1, 2, 3, 4]);
+    request[28..34].copy_from_slice(&[2, 0, 0, 0, 0, 9]);
+    request[236..240].copy_from_slice(&[99, 130, 83, 99]);
+    let discover = [request.as_slice(), &[53, 1, 1, 255]].concat();
+    let ask = [request.as_slice(), &[53, 1, 3, 255]].concat();
+
+    let mut offer = [0_u8; MAX_DHCP_REPLY_LENGTH];
+    let offer_length = dhcp_reply(&discover, PORTAL_ADDRESS, &mut offer).unwrap();
+    assert_eq!(&offer[240..243], &[53, 1, 2]);
+    assert_eq!(&offer[4..8], &[1, 2, 3, 4]);
+    assert_eq!(offer[offer_length - 1], 255);
+
+    let mut ack = [0_u8; MAX_DHCP_REPLY_LENGTH];
+    dhcp_reply(&ask, PORTAL_ADDRESS, &mut ack).unwrap();
+    assert_eq!(&ack[240..243], &[53, 1, 5]);
+    assert_eq!(&ack[16..20], &offer[16..20]);
+    assert_eq!(&ack[16..19], &PORTAL_ADDRESS[..3]);
+}
+
+#[test]
+fn any_hardware_address_gets_an_address() {
+    let mut request = vec![0_u8; 240];
+    request[0] = 1;
+    // Takes the hash right up to the top before its last byte.
+    request[28..34].copy_from_slice(&[150, 0, 19, 29, 16, 255]);
+    request[236..240].copy_from_slice(&[99, 130, 83, 99]);
+    let discover = [request.as_slice(), &[53, 1, 1, 255]].concat();
+
+    let mut offer = [0_u8; MAX_DHCP_REPLY_LENGTH];
+    dhcp_reply(&discover, PORTAL_ADDRESS, &mut offer).unwrap();
+    assert!((2..252).contains(&offer[19]));
+}
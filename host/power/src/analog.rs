@@ -0,0 +1,84 @@
+//! Sensing a machine through the RP2040's ADC instead of a digital pin. Some boards put their
+//! power LED header at a voltage that sits right between a GPIO's low and high, and a
+//! current-sense shunt tells a machine that draws power from one that just has its LED lit.
+
+use pibow_protocol::Reading;
+
+/// What the ADC reads at its reference voltage, it has 12 bits.
+pub const ADC_FULL_SCALE: u16 = 4_095;
+/// The Pico's ADC reference, its 3.3 V rail.
+pub const ADC_REFERENCE_MV: u16 = 3_300;
+
+/// A raw ADC sample in millivolts.
+pub fn millivolts(raw: u16) -> u16 {
+    let raw = u32::from(raw.min(ADC_FULL_SCALE));
+    (raw * u32::from(ADC_REFERENCE_MV) / u32::from(ADC_FULL_SCALE)) as u16
+}
+
+/// The current through a shunt of `milliohms` with `millivolts` across it, u16::MAX at most.
+/// None for a shunt of no resistance, there's no telling.
+pub fn shunt_milliamps(millivolts: u16, milliohms: u16) -> Option<u16> {
+    if milliohms == 0 {
+        return None;
+    }
+    let milliamps = u32::from(millivolts) * 1_000 / u32::from(milliohms);
+    Some(milliamps.min(u32::from(u16::MAX)) as u16)
+}
+
+/// Where a voltage turns into a level, the gap between both keeps a level that hovers around
+/// one threshold from flipping back and forth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Thresholds {
+    /// At or above this, the level is high.
+    pub on_mv: u16,
+    /// At or below this, the level is low. Anything in between keeps the level it had.
+    pub off_mv: u16,
+}
+
+impl Thresholds {
+    /// Whether the thresholds make sense, OFF can't be above ON.
+    pub fn valid(&self) -> bool {
+        self.off_mv <= self.on_mv && self.on_mv <= ADC_REFERENCE_MV
+    }
+}
+
+/// Turns the voltages of one ADC pin into levels a [`crate::sense::Sensor`] takes, the way a
+/// Schmitt trigger would.
+#[derive(Debug, Clone)]
+pub struct Comparator {
+    thresholds: Thresholds,
+    level: Option<bool>,
+    millivolts: Option<u16>,
+}
+
+impl Comparator {
+    pub fn new(thresholds: Thresholds) -> Self {
+        Comparator { thresholds, level: None, millivolts: None }
+    }
+
+    pub fn thresholds(&self) -> Thresholds {
+        self.thresholds
+    }
+
+    /// The voltage read last, None before the first sample.
+    pub fn millivolts(&self) -> Option<u16> {
+        self.millivolts
+    }
+
+    /// Take a voltage, returns the level it makes. None while it has only ever been between the
+    /// thresholds, there's no level to keep yet.
+    pub fn sample(&mut self, millivolts: u16) -> Option<bool> {
+        self.millivolts = Some(millivolts);
+        if millivolts >= self.thresholds.on_mv {
+            self.level = Some(true);
+        } else if millivolts <= self.thresholds.off_mv {
+            self.level = Some(false);
+        }
+        self.level
+    }
+
+    /// What to tell the server, along with the current through the shunt when there's one.
+    pub fn reading(&self, milliamps: Option<u16>) -> Option<Reading> {
+        self.millivolts.map(|millivolts| Reading { millivolts, milliamps })
+    }
+}
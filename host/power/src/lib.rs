@@ -0,0 +1,285 @@
+//! What a node can do to the machine it's wired to, and the rules for when it does it.
+//!
+//! The firmware drives real relays through GPIO, anything else (tests, the simulator) brings its
+//! own [`PowerInterface`].
+
+#![no_std]
+#![allow(async_fn_in_trait)]
+
+pub mod analog;
+pub mod mock;
+pub mod sense;
+
+use pibow_protocol::{ Action, CommandResult, MachineState, Reading, TimingOverrides };
+
+/// Bounds on a power or reset press. A power press stays well short of the 4 seconds that cut
+/// the power on most boards.
+pub const PRESS_MIN_MS: u32 = 50;
+pub const PRESS_MAX_MS: u32 = 3_000;
+/// Bounds on how long a force off holds the power switch, whatever the config says. Boards cut
+/// the power after holding it for 4 seconds, some take longer.
+pub const FORCE_OFF_MIN_MS: u32 = 1_000;
+pub const FORCE_OFF_MAX_MS: u32 = 30_000;
+pub const SETTLE_MAX_MS: u32 = 10_000;
+pub const VERIFY_MIN_MS: u32 = 1_000;
+pub const VERIFY_MAX_MS: u32 = 120_000;
+
+/// The power switch, the reset switch and the machine's state pin.
+pub trait PowerInterface {
+    /// Press the power switch for `press_ms`.
+    async fn press_power(&mut self, press_ms: u32);
+
+    /// Press the reset switch for `press_ms`.
+    async fn press_reset(&mut self, press_ms: u32);
+
+    /// Hold the power switch until the machine is off, for `hold_ms` at most. Returns whether
+    /// it went off.
+    async fn hold_power(&mut self, hold_ms: u32) -> bool;
+
+    /// Whether the machine is currently ON.
+    fn is_on(&mut self) -> bool;
+
+    /// What the machine is doing, for an interface that can tell more than ON or OFF, see
+    /// [`sense::Sensor`].
+    fn state(&mut self) -> MachineState {
+        MachineState::from(self.is_on())
+    }
+
+    /// What the interface measures on the machine, None unless it senses it through an ADC,
+    /// see [`analog`].
+    fn reading(&mut self) -> Option<Reading> {
+        None
+    }
+
+    /// Whether the switches are tied up, by a press going on somewhere else or a machine that
+    /// wouldn't take one right now.
+    fn busy(&mut self) -> bool;
+
+    /// Resolve once the machine's state changes.
+    async fn wait_for_change(&mut self);
+
+    /// Wait for the state pin to read `powered`, for `timeout_ms` at most. Returns whether it
+    /// did.
+    async fn wait_for_state(&mut self, powered: bool, timeout_ms: u32) -> bool;
+
+    /// Wait for the machine to be in `state`, for `timeout_ms` at most. Returns whether it got
+    /// there. Only an interface that can tell a sleeping machine apart needs more than this.
+    async fn wait_for_machine_state(&mut self, state: MachineState, timeout_ms: u32) -> bool {
+        match state.powered() {
+            Some(powered) => self.wait_for_state(powered, timeout_ms).await && self.state() == state,
+            None => false,
+        }
+    }
+
+    /// Let go of every switch, in case a press got cut short.
+    fn release(&mut self);
+
+    /// Wait `ms` without touching anything.
+    async fn delay(&mut self, ms: u32);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The switch got pressed, and for a power ON or OFF the machine followed.
+    Pressed,
+    /// Nothing got pressed, the machine already is in the requested state, or it isn't asleep
+    /// for a wake.
+    AlreadyInState {
+        powered: bool,
+    },
+    /// The switch got pressed, but the machine didn't end up in the requested state.
+    Failed,
+    /// Nothing got pressed, the switches are tied up, see [`PowerInterface::busy`].
+    Busy,
+    /// Nothing got pressed for a power OFF, the machine sleeps and the press would wake it.
+    Asleep,
+    /// Not an action this node knows.
+    Unknown,
+}
+
+impl From<Outcome> for CommandResult {
+    fn from(outcome: Outcome) -> Self {
+        match outcome {
+            Outcome::Pressed => CommandResult::Executed,
+            Outcome::AlreadyInState { .. } => CommandResult::AlreadyInState,
+            Outcome::Failed => CommandResult::Failed,
+            Outcome::Busy => CommandResult::Busy,
+            Outcome::Asleep => CommandResult::Asleep,
+            Outcome::Unknown => CommandResult::UnknownAction,
+        }
+    }
+}
+
+/// A timing override out of the node's bounds, nothing gets pressed for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBounds;
+
+/// How the node presses its switches, in milliseconds. [`execute`] keeps every one of them within
+/// its bounds, whatever the config says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    /// Within [`PRESS_MIN_MS`] and [`PRESS_MAX_MS`], as is the reset press.
+    pub power_press_ms: u32,
+    pub reset_press_ms: u32,
+    /// How long [`Action::ForceOff`] holds the power switch at most, kept within
+    /// [`FORCE_OFF_MIN_MS`] and [`FORCE_OFF_MAX_MS`].
+    pub force_off_hold_ms: u32,
+    /// How long to wait after letting go of a switch, [`SETTLE_MAX_MS`] at most.
+    pub settle_ms: u32,
+    /// How long the machine gets to follow a press, within [`VERIFY_MIN_MS`] and
+    /// [`VERIFY_MAX_MS`].
+    pub verify_timeout_ms: u32,
+    /// Whether to press the power switch once more when the machine didn't follow the first
+    /// press in time.
+    pub retry_press: bool,
+}
+
+impl Default for Timings {
+    fn default() -> Self {
+        Timings {
+            power_press_ms: 500,
+            reset_press_ms: 500,
+            force_off_hold_ms: 8_000,
+            settle_ms: 250,
+            verify_timeout_ms: 30_000,
+            retry_press: false,
+        }
+    }
+}
+
+impl Timings {
+    /// Every timing pulled back within its bounds.
+    pub fn bounded(&self) -> Timings {
+        Timings {
+            power_press_ms: self.power_press_ms.clamp(PRESS_MIN_MS, PRESS_MAX_MS),
+            reset_press_ms: self.reset_press_ms.clamp(PRESS_MIN_MS, PRESS_MAX_MS),
+            force_off_hold_ms: self.force_off_hold_ms.clamp(FORCE_OFF_MIN_MS, FORCE_OFF_MAX_MS),
+            settle_ms: self.settle_ms.min(SETTLE_MAX_MS),
+            verify_timeout_ms: self.verify_timeout_ms.clamp(VERIFY_MIN_MS, VERIFY_MAX_MS),
+            retry_press: self.retry_press,
+        }
+    }
+
+    /// These timings with the server's overrides for one `action`. Unlike the config, overrides
+    /// out of bounds aren't pulled back, the whole command gets turned down.
+    pub fn with_overrides(
+        &self,
+        action: Action,
+        overrides: &TimingOverrides
+    ) -> Result<Timings, OutOfBounds> {
+        let mut timings = *self;
+        let press = overrides.press_ms;
+        match action {
+            Action::PowerOn | Action::PowerOff | Action::Wake => {
+                timings.power_press_ms = pick(self.power_press_ms, press, PRESS_MIN_MS, PRESS_MAX_MS)?;
+            }
+            Action::Reset => {
+                timings.reset_press_ms = pick(self.reset_press_ms, press, PRESS_MIN_MS, PRESS_MAX_MS)?;
+            }
+            Action::ForceOff => {
+                timings.force_off_hold_ms = pick(
+                    self.force_off_hold_ms,
+                    press,
+                    FORCE_OFF_MIN_MS,
+                    FORCE_OFF_MAX_MS
+                )?;
+            }
+            Action::Unknown(_) => {}
+        }
+        timings.settle_ms = pick(self.settle_ms, overrides.settle_ms, 0, SETTLE_MAX_MS)?;
+        timings.verify_timeout_ms = pick(
+            self.verify_timeout_ms,
+            overrides.verify_ms,
+            VERIFY_MIN_MS,
+            VERIFY_MAX_MS
+        )?;
+        Ok(timings)
+    }
+}
+
+// An override, when there is one, 0 keeps the node's own.
+fn pick(own: u32, value: u32, min: u32, max: u32) -> Result<u32, OutOfBounds> {
+    match value {
+        0 => Ok(own),
+        value if (min..=max).contains(&value) => Ok(value),
+        _ => Err(OutOfBounds),
+    }
+}
+
+/// Carry out a server's action, without pressing anything that would do the opposite.
+pub async fn execute(action: Action, power: &mut impl PowerInterface, timings: &Timings) -> Outcome {
+    if !matches!(action, Action::Unknown(_)) && power.busy() {
+        return Outcome::Busy;
+    }
+    let timings = timings.bounded();
+    let outcome = match action {
+        Action::PowerOn | Action::PowerOff => {
+            // A sleeping machine wakes up on a press instead, it takes a wake or a force off.
+            if action == Action::PowerOff && power.state() == MachineState::Sleep {
+                return Outcome::Asleep;
+            }
+            let powered = power.is_on();
+            // No don't press it when it's already in the state the server wants.
+            if powered == (action == Action::PowerOn) {
+                return Outcome::AlreadyInState { powered };
+            }
+            if press_and_verify(power, !powered, &timings).await {
+                Outcome::Pressed
+            } else {
+                Outcome::Failed
+            }
+        }
+        // The state pin doesn't change on a reset, nothing to verify it by.
+        Action::Reset => {
+            power.press_reset(timings.reset_press_ms).await;
+            Outcome::Pressed
+        }
+        // Only a sleeping machine, a press would turn any other one ON or OFF.
+        Action::Wake => {
+            if power.state() != MachineState::Sleep {
+                return Outcome::AlreadyInState { powered: power.is_on() };
+            }
+            power.press_power(timings.power_press_ms).await;
+            if power.wait_for_machine_state(MachineState::On, timings.verify_timeout_ms).await {
+                Outcome::Pressed
+            } else {
+                Outcome::Failed
+            }
+        }
+        Action::ForceOff => {
+            if !power.is_on() {
+                return Outcome::AlreadyInState { powered: false };
+            }
+            if power.hold_power(timings.force_off_hold_ms).await {
+                Outcome::Pressed
+            } else {
+                Outcome::Failed
+            }
+        }
+        Action::Unknown(_) => Outcome::Unknown,
+    };
+
+    if matches!(outcome, Outcome::Pressed | Outcome::Failed) {
+        power.delay(timings.settle_ms).await;
+    }
+    outcome
+}
+
+// Press the power switch until the machine ends up `powered`, returns whether it did.
+async fn press_and_verify(power: &mut impl PowerInterface, powered: bool, timings: &Timings) -> bool {
+    power.press_power(timings.power_press_ms).await;
+    if power.wait_for_state(powered, timings.verify_timeout_ms).await {
+        return true;
+    }
+    if !timings.retry_press {
+        return false;
+    }
+
+    // It might just have made it while settling, pressing again would send it right back.
+    power.delay(timings.settle_ms).await;
+    if power.is_on() == powered {
+        return true;
+    }
+    power.press_power(timings.power_press_ms).await;
+    power.wait_for_state(powered, timings.verify_timeout_ms).await
+}
@@ -0,0 +1,88 @@
+use pibow_protocol::MachineState;
+
+use crate::PowerInterface;
+
+/// A machine that only exists in memory, for testing the rules off-target.
+#[derive(Debug, Clone, Default)]
+pub struct MockPower {
+    pub powered: bool,
+    pub power_presses: usize,
+    pub reset_presses: usize,
+    pub busy: bool,
+    /// How long the power switch has to be held before the machine cuts out.
+    pub force_off_after_ms: u32,
+    /// How long the last press or hold went on.
+    pub held_ms: u32,
+    /// Every delay so far, added up.
+    pub delayed_ms: u32,
+    /// How many more power presses the machine shrugs off, like one that fails to boot.
+    pub ignored_presses: usize,
+    /// Suspended, the next power press wakes it up.
+    pub asleep: bool,
+}
+
+impl MockPower {
+    pub fn new(powered: bool) -> Self {
+        MockPower { powered, force_off_after_ms: 4_000, ..Default::default() }
+    }
+}
+
+impl PowerInterface for MockPower {
+    /// Toggles the machine right away, there's no OS taking its time to shut down in here.
+    async fn press_power(&mut self, press_ms: u32) {
+        self.power_presses += 1;
+        self.held_ms = press_ms;
+        if self.ignored_presses > 0 {
+            self.ignored_presses -= 1;
+        } else if self.asleep {
+            self.asleep = false;
+        } else {
+            self.powered = !self.powered;
+        }
+    }
+
+    async fn press_reset(&mut self, press_ms: u32) {
+        self.reset_presses += 1;
+        self.held_ms = press_ms;
+    }
+
+    /// Holds for exactly as long as it takes, without any actual waiting.
+    async fn hold_power(&mut self, hold_ms: u32) -> bool {
+        self.power_presses += 1;
+        if self.powered && hold_ms >= self.force_off_after_ms {
+            self.powered = false;
+            self.held_ms = self.force_off_after_ms;
+        } else {
+            self.held_ms = hold_ms;
+        }
+        !self.powered
+    }
+
+    fn is_on(&mut self) -> bool {
+        self.powered
+    }
+
+    fn state(&mut self) -> MachineState {
+        if self.asleep { MachineState::Sleep } else { MachineState::from(self.powered) }
+    }
+
+    fn busy(&mut self) -> bool {
+        self.busy
+    }
+
+    /// Nothing changes the mock behind the caller's back, so this never resolves.
+    async fn wait_for_change(&mut self) {
+        core::future::pending::<()>().await
+    }
+
+    /// Presses land right away, so the machine is either there already or never gets there.
+    async fn wait_for_state(&mut self, powered: bool, _timeout_ms: u32) -> bool {
+        self.powered == powered
+    }
+
+    fn release(&mut self) {}
+
+    async fn delay(&mut self, ms: u32) {
+        self.delayed_ms += ms;
+    }
+}
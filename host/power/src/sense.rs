@@ -0,0 +1,190 @@
+//! Telling what a machine is doing from the raw levels of its state pin, and of its sleep pin
+//! when it has one. A power LED flickers, an ATX standby rail glitches and a suspended machine
+//! blinks its LED, none of which should reach the server as a string of ONs and OFFs.
+
+use pibow_protocol::MachineState;
+
+pub const DEBOUNCE_MAX_MS: u32 = 1_000;
+pub const STEADY_MIN_MS: u32 = 100;
+pub const STEADY_MAX_MS: u32 = 60_000;
+pub const BLINK_WINDOW_MIN_MS: u32 = 500;
+pub const BLINK_WINDOW_MAX_MS: u32 = 60_000;
+
+// Twice on and off, one blink could just as well be someone pulling a cable.
+const BLINK_EDGES: usize = 4;
+
+/// How the levels get filtered, in milliseconds. [`Sensor::new`] keeps every one of them within
+/// its bounds, whatever the config says.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SenseSettings {
+    /// How long a level has to hold before it counts at all, [`DEBOUNCE_MAX_MS`] at most.
+    /// Shorter glitches never happened, on the sleep pin as well.
+    pub debounce_ms: u32,
+    /// How long a level has to hold before the machine is ON or OFF, within [`STEADY_MIN_MS`]
+    /// and [`STEADY_MAX_MS`]. Until then the state stays what it was, so it takes more than a
+    /// blip to flip it. Longer than half a blink of the LED, or a sleeping machine reads as ON
+    /// and OFF in turns.
+    pub steady_ms: u32,
+    /// The machine is asleep when its LED went on and off twice within this long, between
+    /// [`BLINK_WINDOW_MIN_MS`] and [`BLINK_WINDOW_MAX_MS`].
+    pub blink_window_ms: u32,
+}
+
+impl Default for SenseSettings {
+    fn default() -> Self {
+        SenseSettings { debounce_ms: 50, steady_ms: 2_000, blink_window_ms: 5_000 }
+    }
+}
+
+impl SenseSettings {
+    /// Every setting pulled back within its bounds.
+    pub fn bounded(&self) -> SenseSettings {
+        SenseSettings {
+            debounce_ms: self.debounce_ms.min(DEBOUNCE_MAX_MS),
+            steady_ms: self.steady_ms.clamp(STEADY_MIN_MS, STEADY_MAX_MS),
+            blink_window_ms: self.blink_window_ms.clamp(BLINK_WINDOW_MIN_MS, BLINK_WINDOW_MAX_MS),
+        }
+    }
+
+    /// How long a level takes to become a state at most, once it stops changing.
+    pub fn decision_ms(&self) -> u32 {
+        self.debounce_ms + self.steady_ms
+    }
+}
+
+/// Filters one state pin. Feed it every level read with [`Sensor::sample`], it only ever answers
+/// with a clean [`MachineState`]. Times are milliseconds from any fixed point, like boot.
+#[derive(Debug, Clone)]
+pub struct Sensor {
+    settings: SenseSettings,
+    // The last level read, and since when it reads that.
+    raw: Option<(bool, u64)>,
+    // The last level that held past the debounce, and since when.
+    level: Option<(bool, u64)>,
+    // The same for the sleep pin, asserted or not.
+    sleep_raw: Option<(bool, u64)>,
+    asleep: bool,
+    // When the debounced level changed lately, oldest first.
+    edges: [u64; BLINK_EDGES],
+    edge_count: usize,
+    state: MachineState,
+}
+
+impl Sensor {
+    pub fn new(settings: SenseSettings) -> Self {
+        Sensor {
+            settings: settings.bounded(),
+            raw: None,
+            level: None,
+            sleep_raw: None,
+            asleep: false,
+            edges: [0; BLINK_EDGES],
+            edge_count: 0,
+            state: MachineState::Unknown,
+        }
+    }
+
+    pub fn settings(&self) -> SenseSettings {
+        self.settings
+    }
+
+    pub fn state(&self) -> MachineState {
+        self.state
+    }
+
+    /// Take the pin's `level` read at `now_ms`. Returns the new state when it changed.
+    pub fn sample(&mut self, level: bool, now_ms: u64) -> Option<MachineState> {
+        self.sample_with_sleep(level, false, now_ms)
+    }
+
+    /// Same as [`Sensor::sample`], along with whether the sleep pin is asserted. A machine with
+    /// a sleep pin is asleep for as long as it says so, whatever its LED does.
+    pub fn sample_with_sleep(
+        &mut self,
+        level: bool,
+        asleep: bool,
+        now_ms: u64
+    ) -> Option<MachineState> {
+        let sleep_since = match self.sleep_raw {
+            Some((raw, since)) if raw == asleep => since,
+            _ => now_ms,
+        };
+        self.sleep_raw = Some((asleep, sleep_since));
+        if now_ms.saturating_sub(sleep_since) >= u64::from(self.settings.debounce_ms) {
+            self.asleep = asleep;
+        }
+
+        let since = match self.raw {
+            Some((raw, since)) if raw == level => since,
+            _ => now_ms,
+        };
+        self.raw = Some((level, since));
+
+        let debounced = now_ms.saturating_sub(since) >= u64::from(self.settings.debounce_ms);
+        if debounced && self.level.map(|(level, _)| level) != Some(level) {
+            // Dated from when the pin got there, not from when the debounce let it through.
+            if self.level.is_some() {
+                self.edge(since);
+            }
+            self.level = Some((level, since));
+        }
+
+        let state = self.decide(now_ms);
+        if state == self.state {
+            return None;
+        }
+        self.state = state;
+        Some(state)
+    }
+
+    /// When the state might change without the pin doing anything else, sample again then.
+    /// None when only a new level can change it.
+    pub fn deadline(&self) -> Option<u64> {
+        if let Some((asleep, since)) = self.sleep_raw
+            && asleep != self.asleep
+        {
+            return Some(since + u64::from(self.settings.debounce_ms));
+        }
+        // Only the sleep pin lets go of it.
+        if self.asleep {
+            return None;
+        }
+        let (raw, raw_since) = self.raw?;
+        match self.level {
+            Some((level, since)) if level == raw => {
+                let steady = MachineState::from(level);
+                (self.state != steady).then_some(since + u64::from(self.settings.steady_ms))
+            }
+            _ => Some(raw_since + u64::from(self.settings.debounce_ms)),
+        }
+    }
+
+    fn edge(&mut self, at_ms: u64) {
+        if self.edge_count == BLINK_EDGES {
+            self.edges.rotate_left(1);
+            self.edges[BLINK_EDGES - 1] = at_ms;
+        } else {
+            self.edges[self.edge_count] = at_ms;
+            self.edge_count += 1;
+        }
+    }
+
+    fn decide(&self, now_ms: u64) -> MachineState {
+        if self.asleep {
+            return MachineState::Sleep;
+        }
+        let Some((level, since)) = self.level else {
+            return self.state;
+        };
+        if now_ms.saturating_sub(since) >= u64::from(self.settings.steady_ms) {
+            return MachineState::from(level);
+        }
+        let blinking = self.edge_count == BLINK_EDGES
+            && now_ms.saturating_sub(self.edges[0]) <= u64::from(self.settings.blink_window_ms);
+        if blinking {
+            return MachineState::Sleep;
+        }
+        // Neither steady nor blinking yet, it takes more than that to move the state.
+        self.state
+    }
+}
@@ -0,0 +1,176 @@
+use embassy_futures::block_on;
+use pibow_power::{ execute, mock::MockPower, OutOfBounds, Outcome, Timings };
+use pibow_protocol::{ Action, CommandResult, TimingOverrides };
+
+#[test]
+fn power_on_presses_only_when_off() {
+    let mut power = MockPower::new(false);
+    assert_eq!(block_on(execute(Action::PowerOn, &mut power, &Timings::default())), Outcome::Pressed);
+    assert!(power.powered);
+
+    assert_eq!(
+        block_on(execute(Action::PowerOn, &mut power, &Timings::default())),
+        Outcome::AlreadyInState { powered: true }
+    );
+    assert_eq!(power.power_presses, 1);
+}
+
+#[test]
+fn power_off_presses_only_when_on() {
+    let mut power = MockPower::new(false);
+    assert_eq!(
+        block_on(execute(Action::PowerOff, &mut power, &Timings::default())),
+        Outcome::AlreadyInState { powered: false }
+    );
+    assert_eq!(power.power_presses, 0);
+
+    power.powered = true;
+    assert_eq!(block_on(execute(Action::PowerOff, &mut power, &Timings::default())), Outcome::Pressed);
+    assert!(!power.powered);
+}
+
+#[test]
+fn reset_always_presses_and_unknown_never_does() {
+    let mut power = MockPower::new(false);
+    assert_eq!(block_on(execute(Action::Reset, &mut power, &Timings::default())), Outcome::Pressed);
+    assert_eq!(block_on(execute(Action::Unknown(9), &mut power, &Timings::default())), Outcome::Unknown);
+    assert_eq!((power.reset_presses, power.power_presses), (1, 0));
+}
+
+#[test]
+fn nothing_gets_pressed_while_busy() {
+    let mut power = MockPower::new(false);
+    power.busy = true;
+    assert_eq!(block_on(execute(Action::PowerOn, &mut power, &Timings::default())), Outcome::Busy);
+    assert_eq!(block_on(execute(Action::Reset, &mut power, &Timings::default())), Outcome::Busy);
+    assert_eq!(block_on(execute(Action::Unknown(9), &mut power, &Timings::default())), Outcome::Unknown);
+    assert_eq!((power.reset_presses, power.power_presses), (0, 0));
+
+    power.busy = false;
+    let outcome = block_on(execute(Action::PowerOn, &mut power, &Timings::default()));
+    assert_eq!(CommandResult::from(outcome), CommandResult::Executed);
+}
+
+#[test]
+fn force_off_holds_until_the_machine_cuts_out() {
+    let mut power = MockPower::new(true);
+    let outcome = block_on(execute(Action::ForceOff, &mut power, &Timings::default()));
+    assert_eq!(outcome, Outcome::Pressed);
+    assert!(!power.powered);
+    assert_eq!(power.held_ms, 4_000);
+
+    // Nothing to hold for once it's off.
+    let outcome = block_on(execute(Action::ForceOff, &mut power, &Timings::default()));
+    assert_eq!(outcome, Outcome::AlreadyInState { powered: false });
+    assert_eq!(power.power_presses, 1);
+}
+
+#[test]
+fn force_off_reports_a_machine_that_stays_on() {
+    let mut power = MockPower::new(true);
+    power.force_off_after_ms = 10_000;
+    let timings = Timings { force_off_hold_ms: 6_000, ..Timings::default() };
+    assert_eq!(block_on(execute(Action::ForceOff, &mut power, &timings)), Outcome::Failed);
+    assert!(power.powered);
+
+    // Held within bounds, however long the config asks for.
+    let timings = Timings { force_off_hold_ms: u32::MAX, ..Timings::default() };
+    block_on(execute(Action::ForceOff, &mut power, &timings));
+    assert_eq!(power.held_ms, 10_000);
+    power.powered = true;
+    power.force_off_after_ms = u32::MAX;
+    block_on(execute(Action::ForceOff, &mut power, &timings));
+    assert_eq!(power.held_ms, pibow_power::FORCE_OFF_MAX_MS);
+}
+
+#[test]
+fn presses_go_by_the_timings_within_bounds() {
+    let mut power = MockPower::new(false);
+    let timings = Timings { power_press_ms: 800, settle_ms: 100, ..Timings::default() };
+    block_on(execute(Action::PowerOn, &mut power, &timings));
+    assert_eq!((power.held_ms, power.delayed_ms), (800, 100));
+
+    // A config gone wrong can't hold a relay forever.
+    let timings = Timings { reset_press_ms: u32::MAX, settle_ms: u32::MAX, ..Timings::default() };
+    block_on(execute(Action::Reset, &mut power, &timings));
+    assert_eq!(power.held_ms, pibow_power::PRESS_MAX_MS);
+    assert_eq!(power.delayed_ms, 100 + pibow_power::SETTLE_MAX_MS);
+}
+
+#[test]
+fn overrides_replace_only_what_they_set_and_stay_within_bounds() {
+    let timings = Timings::default();
+    let overrides = TimingOverrides { press_ms: 1_200, settle_ms: 0, verify_ms: 5_000 };
+    let overridden = timings.with_overrides(Action::Reset, &overrides).unwrap();
+    assert_eq!(overridden.reset_press_ms, 1_200);
+    assert_eq!(overridden.power_press_ms, timings.power_press_ms);
+    assert_eq!(overridden.settle_ms, timings.settle_ms);
+    assert_eq!(overridden.verify_timeout_ms, 5_000);
+
+    // The press is the force off's hold, which goes way longer.
+    let overrides = TimingOverrides { press_ms: 10_000, ..TimingOverrides::NONE };
+    assert_eq!(timings.with_overrides(Action::PowerOn, &overrides), Err(OutOfBounds));
+    let overridden = timings.with_overrides(Action::ForceOff, &overrides).unwrap();
+    assert_eq!(overridden.force_off_hold_ms, 10_000);
+
+    let overrides = TimingOverrides { settle_ms: u32::MAX, ..TimingOverrides::NONE };
+    assert_eq!(timings.with_overrides(Action::PowerOn, &overrides), Err(OutOfBounds));
+    assert_eq!(timings.with_overrides(Action::PowerOn, &TimingOverrides::NONE), Ok(timings));
+}
+
+#[test]
+fn power_presses_get_verified_and_retried_when_asked() {
+    let mut power = MockPower::new(false);
+    power.ignored_presses = 1;
+    assert_eq!(block_on(execute(Action::PowerOn, &mut power, &Timings::default())), Outcome::Failed);
+    assert_eq!(CommandResult::from(Outcome::Failed), CommandResult::Failed);
+    assert_eq!(power.power_presses, 1);
+
+    power.ignored_presses = 1;
+    let timings = Timings { retry_press: true, ..Timings::default() };
+    assert_eq!(block_on(execute(Action::PowerOn, &mut power, &timings)), Outcome::Pressed);
+    assert!(power.powered);
+    assert_eq!(power.power_presses, 3);
+
+    // Only the one retry.
+    power.ignored_presses = 2;
+    assert_eq!(block_on(execute(Action::PowerOff, &mut power, &timings)), Outcome::Failed);
+    assert_eq!(power.power_presses, 5);
+}
+
+#[test]
+fn only_a_sleeping_machine_gets_woken() {
+    let mut power = MockPower::new(false);
+    assert_eq!(
+        block_on(execute(Action::Wake, &mut power, &Timings::default())),
+        Outcome::AlreadyInState { powered: false }
+    );
+    assert_eq!(power.power_presses, 0);
+
+    power.powered = true;
+    power.asleep = true;
+    // Asleep still has power, a power ON leaves it be.
+    assert_eq!(
+        block_on(execute(Action::PowerOn, &mut power, &Timings::default())),
+        Outcome::AlreadyInState { powered: true }
+    );
+    assert_eq!(block_on(execute(Action::Wake, &mut power, &Timings::default())), Outcome::Pressed);
+    assert_eq!((power.powered, power.asleep, power.power_presses), (true, false, 1));
+}
+
+#[test]
+fn a_sleeping_machine_isnt_pressed_for_a_power_off() {
+    let mut power = MockPower::new(true);
+    power.asleep = true;
+    // A press would only wake it, retry or not.
+    let timings = Timings { retry_press: true, ..Timings::default() };
+    let outcome = block_on(execute(Action::PowerOff, &mut power, &timings));
+    assert_eq!(outcome, Outcome::Asleep);
+    assert_eq!(CommandResult::from(outcome), CommandResult::Asleep);
+    assert_eq!((power.powered, power.asleep, power.power_presses), (true, true, 0));
+
+    // A force off still takes it down.
+    let outcome = block_on(execute(Action::ForceOff, &mut power, &timings));
+    assert_eq!(outcome, Outcome::Pressed);
+    assert!(!power.powered);
+}
@@ -0,0 +1,34 @@
+use pibow_power::analog::{ millivolts, shunt_milliamps, Comparator, Thresholds, ADC_FULL_SCALE };
+use pibow_protocol::Reading;
+
+#[test]
+fn levels_only_flip_past_the_thresholds() {
+    let mut comparator = Comparator::new(Thresholds { on_mv: 1_500, off_mv: 800 });
+    assert_eq!(comparator.reading(None), None);
+    // Nothing to keep yet.
+    assert_eq!(comparator.sample(1_000), None);
+
+    assert_eq!(comparator.sample(1_600), Some(true));
+    assert_eq!(comparator.sample(1_000), Some(true));
+    assert_eq!(comparator.sample(800), Some(false));
+    assert_eq!(comparator.sample(1_499), Some(false));
+    assert_eq!(
+        comparator.reading(Some(250)),
+        Some(Reading { millivolts: 1_499, milliamps: Some(250) })
+    );
+}
+
+#[test]
+fn raw_samples_and_shunts_scale_to_units() {
+    assert_eq!(millivolts(0), 0);
+    assert_eq!(millivolts(ADC_FULL_SCALE), 3_300);
+    assert_eq!(millivolts(u16::MAX), 3_300);
+    assert_eq!(millivolts(2_048), 1_650);
+
+    // 50 mV across 20 mΩ.
+    assert_eq!(shunt_milliamps(50, 20), Some(2_500));
+    assert_eq!(shunt_milliamps(3_300, 1), Some(u16::MAX));
+    assert_eq!(shunt_milliamps(50, 0), None);
+    assert!(!Thresholds { on_mv: 800, off_mv: 1_500 }.valid());
+    assert!(!Thresholds { on_mv: 4_000, off_mv: 1_500 }.valid());
+}
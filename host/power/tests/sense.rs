@@ -0,0 +1,76 @@
+use pibow_power::sense::{ SenseSettings, Sensor };
+use pibow_protocol::MachineState;
+
+// Reads `level` every 10 ms from `from_ms` until `to_ms`, returns every state change on the way.
+fn hold(sensor: &mut Sensor, level: bool, from_ms: u64, to_ms: u64) -> Vec<MachineState> {
+    (from_ms..to_ms)
+        .step_by(10)
+        .filter_map(|now_ms| sensor.sample(level, now_ms))
+        .collect()
+}
+
+#[test]
+fn a_level_needs_to_hold_before_it_becomes_a_state() {
+    let mut sensor = Sensor::new(SenseSettings::default());
+    assert_eq!(sensor.state(), MachineState::Unknown);
+
+    assert_eq!(hold(&mut sensor, true, 0, 2_000), []);
+    assert_eq!(sensor.deadline(), Some(2_000));
+    assert_eq!(sensor.sample(true, 2_000), Some(MachineState::On));
+    assert_eq!(sensor.deadline(), None);
+}
+
+#[test]
+fn glitches_and_blips_dont_move_the_state() {
+    let mut sensor = Sensor::new(SenseSettings::default());
+    hold(&mut sensor, false, 0, 2_010);
+    assert_eq!(sensor.state(), MachineState::Off);
+
+    // Shorter than the debounce, never happened.
+    assert_eq!(hold(&mut sensor, true, 3_000, 3_030), []);
+    assert_eq!(hold(&mut sensor, false, 3_030, 4_000), []);
+    // Past the debounce but not steady, the state stays.
+    assert_eq!(hold(&mut sensor, true, 4_000, 4_500), []);
+    assert_eq!(hold(&mut sensor, false, 4_500, 8_000), []);
+    assert_eq!(sensor.state(), MachineState::Off);
+
+    assert_eq!(hold(&mut sensor, true, 8_000, 10_010), [MachineState::On]);
+}
+
+#[test]
+fn a_blinking_led_is_a_sleeping_machine() {
+    let mut sensor = Sensor::new(SenseSettings::default());
+    hold(&mut sensor, true, 0, 2_010);
+
+    let mut changes = Vec::new();
+    for blink in 0..5 {
+        let start = 3_000 + blink * 1_000;
+        changes.extend(hold(&mut sensor, false, start, start + 500));
+        changes.extend(hold(&mut sensor, true, start + 500, start + 1_000));
+    }
+    assert_eq!(changes, [MachineState::Sleep]);
+
+    // Once it stops blinking, it takes a steady level to wake it or turn it off.
+    assert_eq!(hold(&mut sensor, false, 8_000, 9_500), []);
+    assert_eq!(hold(&mut sensor, false, 9_500, 10_500), [MachineState::Off]);
+}
+
+#[test]
+fn settings_stay_within_bounds() {
+    let sensor = Sensor::new(SenseSettings { debounce_ms: 5_000, steady_ms: 0, blink_window_ms: 0 });
+    assert_eq!(
+        sensor.settings(),
+        SenseSettings { debounce_ms: 1_000, steady_ms: 100, blink_window_ms: 500 }
+    );
+}
+
+#[test]
+fn the_sleep_pin_wins_over_the_led() {
+    let mut sensor = Sensor::new(SenseSettings::default());
+    let changes: Vec<_> = (0..3_000)
+        .step_by(10)
+        .filter_map(|now_ms| sensor.sample_with_sleep(true, (1_000..2_500).contains(&now_ms), now_ms))
+        .collect();
+    assert_eq!(changes, [MachineState::Sleep, MachineState::On]);
+    assert_eq!(sensor.deadline(), None);
+}
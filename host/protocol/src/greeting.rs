@@ -0,0 +1,32 @@
+use crate::{ write_frame, Answer, EncodeError, Hello, ANSWER_LENGTH, HELLO_LENGTH };
+
+pub const GREETING_LENGTH: usize = HELLO_LENGTH + ANSWER_LENGTH;
+
+/// The server's answer to the [`crate::Introduction`]: `[<hello>, <server proof>]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Greeting {
+    pub hello: Hello,
+    pub proof: Answer,
+}
+
+impl Greeting {
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, EncodeError> {
+        write_frame(buffer, &[&self.hello.to_bytes(), &self.proof])
+    }
+
+    pub fn to_bytes(&self) -> [u8; GREETING_LENGTH] {
+        let mut bytes = [0_u8; GREETING_LENGTH];
+        // Can't fail, the buffer is exactly one greeting.
+        let _ = self.encode(&mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; GREETING_LENGTH]) -> Self {
+        let mut hello = [0_u8; HELLO_LENGTH];
+        let mut proof = [0_u8; ANSWER_LENGTH];
+        let (hello_bytes, proof_bytes) = bytes.split_at(HELLO_LENGTH);
+        hello.copy_from_slice(hello_bytes);
+        proof.copy_from_slice(proof_bytes);
+        Greeting { hello: Hello::from_bytes(&hello), proof }
+    }
+}
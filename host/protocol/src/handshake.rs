@@ -0,0 +1,123 @@
+use crate::{
+    session::{ Cipher, Opener, Role, Sealer },
+    Hello,
+    Introduction,
+    MacAddress,
+    Nonce,
+    ANSWER_LENGTH,
+};
+
+pub const PROOF_LENGTH: usize = ANSWER_LENGTH;
+
+// blake3 wants a hardcoded, globally unique context string for every derived key.
+const SESSION_KEY_CONTEXT: &str = "pibow 2025 node session key v1";
+const AEAD_SESSION_KEY_CONTEXT: &str = "pibow 2025 node aead session key v1";
+const KEY_WRAP_CONTEXT: &str = "pibow 2025 node key wrap v1";
+
+/// Everything both ends know once the introduction went through.
+///
+/// Each side proves it has the key over both nonces, so a proof never answers anything but
+/// this very handshake, and the session key can't come out the same twice.
+///
+/// The cipher goes into both proofs, that's how the node asks for it without a byte more on the
+/// wire: a server that doesn't know a cipher only sees a bad proof. The hellos go in too, the
+/// node's into both and the server's into its own, so neither end can be talked down.
+#[derive(Clone)]
+pub struct Handshake {
+    pub key: [u8; 32],
+    pub mac_address: MacAddress,
+    pub server_nonce: Nonce,
+    pub node_nonce: Nonce,
+    pub cipher: Cipher,
+    pub node_hello: Hello,
+}
+
+impl Handshake {
+    /// The server side, find which of the `accepted` ciphers the node's proof was made for.
+    ///
+    /// None when it checks out for none of them: wrong key, or a cipher this server won't do.
+    pub fn negotiate(
+        key: &[u8; 32],
+        server_nonce: Nonce,
+        introduction: &Introduction,
+        accepted: &[Cipher]
+    ) -> Option<Handshake> {
+        accepted
+            .iter()
+            .map(|cipher| Handshake {
+                key: *key,
+                mac_address: introduction.mac_address,
+                server_nonce,
+                node_nonce: introduction.nonce,
+                cipher: *cipher,
+                node_hello: introduction.hello,
+            })
+            .find(|handshake| crate::proof_matches(&handshake.node_proof(), &introduction.proof))
+    }
+
+    /// Sent by the node in its [`crate::Introduction`].
+    pub fn node_proof(&self) -> blake3::Hash {
+        self.proof(b"node").finalize()
+    }
+
+    /// Sent by the server in its [`crate::Greeting`] once the node's proof checked out, the node
+    /// won't take anything before.
+    pub fn server_proof(&self, server_hello: &Hello) -> blake3::Hash {
+        self.proof(b"server").update(&server_hello.to_bytes()).finalize()
+    }
+
+    // Labelled, so one side's proof can't be reflected back as the other's.
+    fn proof(&self, label: &[u8]) -> blake3::Hasher {
+        let mut hasher = blake3::Hasher::new_keyed(&self.key);
+        hasher.update(label);
+        if self.cipher != Cipher::Plain {
+            hasher.update(self.cipher.name().as_bytes());
+        }
+        hasher
+            .update(&self.mac_address)
+            .update(&self.server_nonce)
+            .update(&self.node_nonce)
+            .update(&self.node_hello.to_bytes());
+        hasher
+    }
+
+    pub fn session_key(&self) -> [u8; 32] {
+        let context = match self.cipher {
+            Cipher::Plain => SESSION_KEY_CONTEXT,
+            Cipher::ChaCha20Poly1305 => AEAD_SESSION_KEY_CONTEXT,
+        };
+        *blake3::Hasher::new_derive_key(context)
+            .update(&self.key)
+            .update(&self.mac_address)
+            .update(&self.server_nonce)
+            .update(&self.node_nonce)
+            .finalize()
+            .as_bytes()
+    }
+
+    /// Wrap the node's next key under its current one, for [`crate::ServerMessage::RotateKey`].
+    ///
+    /// The pad comes from this session's key too, so it's never used twice, even when a rotation
+    /// gets sent again.
+    pub fn wrap_key(&self, new_key: &[u8; 32]) -> [u8; 32] {
+        let pad = blake3::Hasher::new_derive_key(KEY_WRAP_CONTEXT)
+            .update(&self.key)
+            .update(&self.session_key())
+            .finalize();
+        let mut wrapped = *new_key;
+        for (byte, pad) in wrapped.iter_mut().zip(pad.as_bytes()) {
+            *byte ^= pad;
+        }
+        wrapped
+    }
+
+    pub fn unwrap_key(&self, wrapped: &[u8; 32]) -> [u8; 32] {
+        self.wrap_key(wrapped)
+    }
+
+    /// The two halves of the session, for this end of it.
+    pub fn session(&self, role: Role) -> (Sealer, Opener) {
+        let key = self.session_key();
+        (Sealer::new(key, role, self.cipher), Opener::new(key, role, self.cipher))
+    }
+}
@@ -0,0 +1,56 @@
+use crate::{
+    write_frame,
+    Answer,
+    EncodeError,
+    Hello,
+    MacAddress,
+    Nonce,
+    ANSWER_LENGTH,
+    HELLO_LENGTH,
+    MAC_LENGTH,
+    NONCE_LENGTH,
+};
+
+pub const INTRODUCTION_LENGTH: usize = MAC_LENGTH + NONCE_LENGTH + HELLO_LENGTH + ANSWER_LENGTH;
+
+/// Sent by the node right after the server's nonce:
+/// `[<mac>, <node nonce>, <hello>, <node proof>]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Introduction {
+    pub mac_address: MacAddress,
+    pub nonce: Nonce,
+    pub hello: Hello,
+    pub proof: Answer,
+}
+
+impl Introduction {
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, EncodeError> {
+        write_frame(buffer, &[&self.mac_address, &self.nonce, &self.hello.to_bytes(), &self.proof])
+    }
+
+    pub fn to_bytes(&self) -> [u8; INTRODUCTION_LENGTH] {
+        let mut bytes = [0_u8; INTRODUCTION_LENGTH];
+        // Can't fail, the buffer is exactly one introduction.
+        let _ = self.encode(&mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; INTRODUCTION_LENGTH]) -> Self {
+        let mut hello = [0_u8; HELLO_LENGTH];
+        let mut introduction = Introduction {
+            mac_address: [0_u8; MAC_LENGTH],
+            nonce: [0_u8; NONCE_LENGTH],
+            hello: Hello::from_bytes(&hello),
+            proof: [0_u8; ANSWER_LENGTH],
+        };
+        let (mac_address, rest) = bytes.split_at(MAC_LENGTH);
+        let (nonce, rest) = rest.split_at(NONCE_LENGTH);
+        let (hello_bytes, proof) = rest.split_at(HELLO_LENGTH);
+        introduction.mac_address.copy_from_slice(mac_address);
+        introduction.nonce.copy_from_slice(nonce);
+        hello.copy_from_slice(hello_bytes);
+        introduction.hello = Hello::from_bytes(&hello);
+        introduction.proof.copy_from_slice(proof);
+        introduction
+    }
+}
@@ -0,0 +1,18 @@
+use crate::MacAddress;
+
+// blake3 wants a hardcoded, globally unique context string for every derived key.
+const NODE_KEY_CONTEXT: &str = "pibow 2025 node key v1";
+
+/// The key of a single node, derived from the fleet's master key and the node's MAC address.
+///
+/// Only the server ever holds the master key, a node only gets its own key, so taking one apart
+/// gives away nothing about the others. Revoking a key for good means issuing the next
+/// `generation` for that MAC.
+pub fn node_key(master_key: &[u8; 32], mac_address: &MacAddress, generation: u32) -> [u8; 32] {
+    *blake3::Hasher::new_derive_key(NODE_KEY_CONTEXT)
+        .update(master_key)
+        .update(mac_address)
+        .update(&generation.to_le_bytes())
+        .finalize()
+        .as_bytes()
+}
@@ -0,0 +1,129 @@
+//! The wire protocol spoken between a Pibow node and its server.
+//!
+//! Shared by the firmware and anything running on the host, so both ends agree on the exact bytes.
+//! Nothing in here touches the network, it only encodes and decodes frames.
+//!
+//! Discovery goes:
+//! - node, on the multicast group: [`Poke`], a challenge and its MAC address
+//! - server, over TCP to the node port: the [`answer`], under that node's key, see [`node_key`]
+//!
+//! A session goes:
+//! - server: `<server nonce>`
+//! - node: [`Introduction`], its MAC address, its own nonce, its [`Hello`] and its proof of the
+//!   key, which also asks for a [`Cipher`]
+//! - server: [`Greeting`], its own hello and proof, see [`Handshake`]
+//! - then sealed frames both ways, see [`Sealer`] and [`Opener`]. An end that can't agree on a
+//!   version with the other sends an error frame and hangs up. Commands come as a
+//!   [`ServerMessage::Request`] and get a [`NodeMessage::Ack`] back, when both ends do
+//!   [`Capabilities::COMMAND_ACKS`].
+//!
+//! Answers and proofs are only ever checked through [`answered_key`] and [`proof_matches`], in
+//! constant time, and the node keeps the sources that keep getting them wrong out with an
+//! [`AttemptLimiter`].
+
+#![no_std]
+
+mod greeting;
+mod handshake;
+mod introduction;
+mod keys;
+mod node;
+mod poke;
+mod server;
+mod session;
+mod verify;
+mod version;
+
+pub use greeting::{ Greeting, GREETING_LENGTH };
+pub use handshake::{ Handshake, PROOF_LENGTH };
+pub use introduction::{ Introduction, INTRODUCTION_LENGTH };
+pub use keys::node_key;
+pub use node::{ CommandResult, MachineState, NodeMessage, Reading };
+pub use poke::{ Poke, POKE_LENGTH };
+pub use server::{ Action, ServerMessage, TimingOverrides };
+pub use session::{
+    frame_length,
+    Cipher,
+    FrameDecoder,
+    Opener,
+    Role,
+    Sealer,
+    LENGTH_PREFIX,
+    MAX_FRAME_LENGTH,
+    MAX_PAYLOAD_LENGTH,
+    TAG_LENGTH,
+};
+pub use verify::{
+    answered_key,
+    constant_time_eq,
+    proof_matches,
+    AttemptLimiter,
+    Lockout,
+    FIRST_LOCKOUT_MS,
+    MAX_FAILED_ATTEMPTS,
+    MAX_LOCKOUT_MS,
+    TRACKED_SOURCES,
+};
+pub use version::{
+    Capabilities,
+    ErrorCode,
+    Hello,
+    HELLO_LENGTH,
+    MIN_PROTOCOL_VERSION,
+    PROTOCOL_VERSION,
+};
+
+pub const CHALLENGE_LENGTH: usize = 64;
+pub const ANSWER_LENGTH: usize = 32;
+pub const NONCE_LENGTH: usize = 32;
+pub const MAC_LENGTH: usize = 6;
+
+pub type Challenge = [u8; CHALLENGE_LENGTH];
+pub type Answer = [u8; ANSWER_LENGTH];
+pub type Nonce = [u8; NONCE_LENGTH];
+pub type MacAddress = [u8; MAC_LENGTH];
+
+/// Answer a discovery challenge with the shared secret key.
+pub fn answer(key: &[u8; 32], challenge: &Challenge) -> blake3::Hash {
+    blake3::keyed_hash(key, challenge)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The output buffer can't hold the whole frame.
+    BufferTooSmall,
+    /// The payload is over [`MAX_PAYLOAD_LENGTH`].
+    PayloadTooLong,
+    /// The message needs a capability the session didn't agree on.
+    NotAgreed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The payload starts with a flag byte that isn't a known message.
+    UnknownFlag(u8),
+    /// The payload is too short or too long for its flag.
+    BadLength,
+    /// The frame's tag doesn't check out: forged, replayed, reordered or plain corrupted.
+    BadTag,
+    /// The length prefix is over [`MAX_FRAME_LENGTH`].
+    FrameTooLong(usize),
+}
+
+fn write_frame(buffer: &mut [u8], frame: &[&[u8]]) -> Result<usize, EncodeError> {
+    let length = frame
+        .iter()
+        .map(|part| part.len())
+        .sum();
+    if buffer.len() < length {
+        return Err(EncodeError::BufferTooSmall);
+    }
+
+    let mut offset = 0;
+    for part in frame {
+        buffer[offset..offset + part.len()].copy_from_slice(part);
+        offset += part.len();
+    }
+
+    Ok(length)
+}
@@ -0,0 +1,335 @@
+use core::net::IpAddr;
+
+use crate::{ write_frame, Capabilities, DecodeError, EncodeError, ErrorCode };
+
+const FLAG_MACHINE_OFF: u8 = 0;
+const FLAG_MACHINE_ON: u8 = 1;
+// 2 was the per-request challenge, before sessions were sealed.
+const FLAG_RECONNECTS: u8 = 3;
+const FLAG_KEY_ROTATED: u8 = 4;
+const FLAG_LOCKED_OUT: u8 = 5;
+const FLAG_ERROR: u8 = 6;
+const FLAG_ACK: u8 = 7;
+// 8 was the state of a machine past the first, before every machine's went as a state.
+const FLAG_STATE: u8 = 9;
+const FLAG_READING: u8 = 10;
+
+const RESULT_EXECUTED: u8 = 0;
+const RESULT_ALREADY_IN_STATE: u8 = 1;
+const RESULT_AUTH_FAILED: u8 = 2;
+const RESULT_BUSY: u8 = 3;
+const RESULT_UNKNOWN_ACTION: u8 = 4;
+const RESULT_FAILED: u8 = 5;
+const RESULT_INVALID_TIMING: u8 = 6;
+const RESULT_UNKNOWN_CHANNEL: u8 = 7;
+const RESULT_ASLEEP: u8 = 8;
+
+/// What became of a [`crate::ServerMessage::Request`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandResult {
+    /// The switch got pressed.
+    Executed,
+    /// Nothing got pressed, the machine already is in the requested state.
+    AlreadyInState,
+    /// A frame from the server didn't check out, so whatever it asked for wasn't done. Always
+    /// acked with id 0, nothing in that frame can be trusted, its id included.
+    AuthFailed,
+    /// The switches are tied up, ask again in a moment.
+    Busy,
+    /// Not an action this node knows.
+    UnknownAction,
+    /// The switch got pressed, but the machine didn't end up in the requested state.
+    Failed,
+    /// Nothing got pressed, a timing override is out of the node's bounds.
+    InvalidTiming,
+    /// Nothing got pressed, the node has no machine on that channel.
+    UnknownChannel,
+    /// Nothing got pressed, the machine sleeps and a power press would only wake it. Wake it
+    /// first, or force it off.
+    Asleep,
+    /// A result from a newer node.
+    Unknown(u8),
+}
+
+impl From<u8> for CommandResult {
+    fn from(value: u8) -> Self {
+        match value {
+            RESULT_EXECUTED => CommandResult::Executed,
+            RESULT_ALREADY_IN_STATE => CommandResult::AlreadyInState,
+            RESULT_AUTH_FAILED => CommandResult::AuthFailed,
+            RESULT_BUSY => CommandResult::Busy,
+            RESULT_UNKNOWN_ACTION => CommandResult::UnknownAction,
+            RESULT_FAILED => CommandResult::Failed,
+            RESULT_INVALID_TIMING => CommandResult::InvalidTiming,
+            RESULT_UNKNOWN_CHANNEL => CommandResult::UnknownChannel,
+            RESULT_ASLEEP => CommandResult::Asleep,
+            unknown => CommandResult::Unknown(unknown),
+        }
+    }
+}
+
+impl From<CommandResult> for u8 {
+    fn from(result: CommandResult) -> Self {
+        match result {
+            CommandResult::Executed => RESULT_EXECUTED,
+            CommandResult::AlreadyInState => RESULT_ALREADY_IN_STATE,
+            CommandResult::AuthFailed => RESULT_AUTH_FAILED,
+            CommandResult::Busy => RESULT_BUSY,
+            CommandResult::UnknownAction => RESULT_UNKNOWN_ACTION,
+            CommandResult::Failed => RESULT_FAILED,
+            CommandResult::InvalidTiming => RESULT_INVALID_TIMING,
+            CommandResult::UnknownChannel => RESULT_UNKNOWN_CHANNEL,
+            CommandResult::Asleep => RESULT_ASLEEP,
+            CommandResult::Unknown(unknown) => unknown,
+        }
+    }
+}
+
+const STATE_OFF: u8 = 0;
+const STATE_ON: u8 = 1;
+const STATE_SLEEP: u8 = 2;
+const STATE_UNKNOWN: u8 = 3;
+
+/// What a machine is doing, as far as its node can tell from its state pins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MachineState {
+    Off,
+    On,
+    /// Suspended, the power LED blinks or the sleep pin says so.
+    Sleep,
+    /// Nothing to go by yet, the state pin hasn't settled since the node booted.
+    Unknown,
+}
+
+impl MachineState {
+    /// Whether the machine has power, a sleeping one does. None when there's no telling.
+    pub fn powered(self) -> Option<bool> {
+        match self {
+            MachineState::Off => Some(false),
+            MachineState::On | MachineState::Sleep => Some(true),
+            MachineState::Unknown => None,
+        }
+    }
+
+    /// Its byte on the wire, the same as `u8::from` but usable in a const, like a static's
+    /// initial value.
+    pub const fn to_byte(self) -> u8 {
+        match self {
+            MachineState::Off => STATE_OFF,
+            MachineState::On => STATE_ON,
+            MachineState::Sleep => STATE_SLEEP,
+            MachineState::Unknown => STATE_UNKNOWN,
+        }
+    }
+}
+
+impl From<bool> for MachineState {
+    fn from(powered: bool) -> Self {
+        if powered { MachineState::On } else { MachineState::Off }
+    }
+}
+
+impl From<u8> for MachineState {
+    fn from(value: u8) -> Self {
+        match value {
+            STATE_OFF => MachineState::Off,
+            STATE_ON => MachineState::On,
+            STATE_SLEEP => MachineState::Sleep,
+            _ => MachineState::Unknown,
+        }
+    }
+}
+
+impl From<MachineState> for u8 {
+    fn from(state: MachineState) -> Self {
+        state.to_byte()
+    }
+}
+
+/// What a node measures on a machine with analog sensing, as of the last sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reading {
+    /// The voltage on the state pin.
+    pub millivolts: u16,
+    /// The current through the shunt, when the machine has one.
+    pub milliamps: Option<u16>,
+}
+
+/// Everything the node sends during a session, each payload starting with a one byte flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeMessage {
+    /// `[0]`, the node's only machine is OFF. For servers with neither
+    /// [`crate::Capabilities::CHANNELS`] nor [`crate::Capabilities::MACHINE_STATES`], the rest
+    /// hear [`NodeMessage::State`].
+    MachineOff,
+    /// `[1]`, the same for ON.
+    MachineOn,
+    /// `[3, <u32 big endian>]`, how many times the node had to rejoin the WiFi since boot.
+    Reconnects(u32),
+    /// `[4, <saved>]`, answers [`crate::ServerMessage::RotateKey`]. Only once it's saved does the
+    /// node use the new key, with the old one still good for the grace window.
+    KeyRotated {
+        saved: bool,
+    },
+    /// `[5, <failures>, <secs, u32 big endian>, <address, 4 or 16 bytes>]`, some address kept
+    /// getting the challenge or the handshake wrong, the node won't hear from it for `secs`.
+    LockedOut {
+        address: IpAddr,
+        failures: u8,
+        secs: u32,
+    },
+    /// `[6, <code>]`, the node is hanging up on the server, and why.
+    Error(ErrorCode),
+    /// `[7, <id u16 big endian>, <result>]`, answers [`crate::ServerMessage::Request`].
+    Ack {
+        id: u16,
+        result: CommandResult,
+    },
+    /// `[9, <channel>, <state>]`, the state of any machine. Only for servers with
+    /// [`crate::Capabilities::CHANNELS`] or [`crate::Capabilities::MACHINE_STATES`], and only
+    /// ever OFF or ON without the latter.
+    State {
+        channel: u8,
+        state: MachineState,
+    },
+    /// `[10, <channel>, <millivolts u16 big endian>, <milliamps u16 big endian>]`, what the
+    /// node measures on a machine with analog sensing, the milliamps only with a shunt. Only
+    /// for servers with [`crate::Capabilities::ANALOG_READINGS`].
+    Reading {
+        channel: u8,
+        reading: Reading,
+    },
+}
+
+impl NodeMessage {
+    pub const MAX_LENGTH: usize = 1 + 1 + 4 + 16;
+
+    pub fn machine_state(powered: bool) -> Self {
+        if powered { NodeMessage::MachineOn } else { NodeMessage::MachineOff }
+    }
+
+    /// How to tell the server about the machine on `channel`, going by what it `agreed` to.
+    /// Servers without [`Capabilities::MACHINE_STATES`] only hear whether it has power, a
+    /// sleeping one does, and servers without [`Capabilities::CHANNELS`] only about the first
+    /// machine. None when there's nothing it would understand.
+    pub fn state_report(channel: u8, state: MachineState, agreed: Capabilities) -> Option<Self> {
+        let states = agreed.contains(Capabilities::MACHINE_STATES);
+        let state = if states { state } else { MachineState::from(state.powered()?) };
+        if states || agreed.contains(Capabilities::CHANNELS) {
+            return Some(NodeMessage::State { channel, state });
+        }
+        (channel == 0).then(|| NodeMessage::machine_state(state == MachineState::On))
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        match self {
+            NodeMessage::MachineOff | NodeMessage::MachineOn => 1,
+            NodeMessage::Reconnects(_) => 1 + 4,
+            NodeMessage::KeyRotated { .. } => 1 + 1,
+            NodeMessage::LockedOut { address: IpAddr::V4(_), .. } => 1 + 1 + 4 + 4,
+            NodeMessage::LockedOut { address: IpAddr::V6(_), .. } => 1 + 1 + 4 + 16,
+            NodeMessage::Error(_) => 1 + 1,
+            NodeMessage::Ack { .. } => 1 + 2 + 1,
+            NodeMessage::State { .. } => 1 + 1 + 1,
+            NodeMessage::Reading { reading, .. } => match reading.milliamps {
+                Some(_) => 1 + 1 + 2 + 2,
+                None => 1 + 1 + 2,
+            },
+        }
+    }
+
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, EncodeError> {
+        match self {
+            NodeMessage::MachineOff => write_frame(buffer, &[&[FLAG_MACHINE_OFF]]),
+            NodeMessage::MachineOn => write_frame(buffer, &[&[FLAG_MACHINE_ON]]),
+            NodeMessage::Reconnects(count) => {
+                write_frame(buffer, &[&[FLAG_RECONNECTS], &count.to_be_bytes()])
+            }
+            NodeMessage::KeyRotated { saved } => {
+                write_frame(buffer, &[&[FLAG_KEY_ROTATED, *saved as u8]])
+            }
+            NodeMessage::LockedOut { address, failures, secs } => {
+                let head: &[u8] = &[FLAG_LOCKED_OUT, *failures];
+                match address {
+                    IpAddr::V4(address) => {
+                        write_frame(buffer, &[head, &secs.to_be_bytes(), &address.octets()])
+                    }
+                    IpAddr::V6(address) => {
+                        write_frame(buffer, &[head, &secs.to_be_bytes(), &address.octets()])
+                    }
+                }
+            }
+            NodeMessage::Error(code) => write_frame(buffer, &[&[FLAG_ERROR, u8::from(*code)]]),
+            NodeMessage::Ack { id, result } => {
+                write_frame(buffer, &[&[FLAG_ACK], &id.to_be_bytes(), &[u8::from(*result)]])
+            }
+            NodeMessage::State { channel, state } => {
+                write_frame(buffer, &[&[FLAG_STATE, *channel, u8::from(*state)]])
+            }
+            NodeMessage::Reading { channel, reading } => {
+                let head: &[u8] = &[FLAG_READING, *channel];
+                let millivolts = reading.millivolts.to_be_bytes();
+                match reading.milliamps {
+                    Some(milliamps) => {
+                        write_frame(buffer, &[head, &millivolts, &milliamps.to_be_bytes()])
+                    }
+                    None => write_frame(buffer, &[head, &millivolts]),
+                }
+            }
+        }
+    }
+
+    /// Decode an opened payload, which holds exactly one message.
+    pub fn decode(payload: &[u8]) -> Result<Self, DecodeError> {
+        let (&flag, body) = payload.split_first().ok_or(DecodeError::BadLength)?;
+        match (flag, body) {
+            (FLAG_MACHINE_OFF, []) => Ok(NodeMessage::MachineOff),
+            (FLAG_MACHINE_ON, []) => Ok(NodeMessage::MachineOn),
+            (FLAG_RECONNECTS, &[a, b, c, d]) => {
+                Ok(NodeMessage::Reconnects(u32::from_be_bytes([a, b, c, d])))
+            }
+            (FLAG_KEY_ROTATED, &[saved]) => Ok(NodeMessage::KeyRotated { saved: saved != 0 }),
+            (FLAG_LOCKED_OUT, &[failures, a, b, c, d, ref address @ ..]) => {
+                let address = if let Ok(octets) = <[u8; 4]>::try_from(address) {
+                    IpAddr::from(octets)
+                } else if let Ok(octets) = <[u8; 16]>::try_from(address) {
+                    IpAddr::from(octets)
+                } else {
+                    return Err(DecodeError::BadLength);
+                };
+                let secs = u32::from_be_bytes([a, b, c, d]);
+                Ok(NodeMessage::LockedOut { address, failures, secs })
+            }
+            (FLAG_ERROR, &[code]) => Ok(NodeMessage::Error(ErrorCode::from(code))),
+            (FLAG_ACK, &[a, b, result]) => {
+                let id = u16::from_be_bytes([a, b]);
+                Ok(NodeMessage::Ack { id, result: CommandResult::from(result) })
+            }
+            (FLAG_STATE, &[channel, state]) => {
+                Ok(NodeMessage::State { channel, state: MachineState::from(state) })
+            }
+            (FLAG_READING, &[channel, a, b, ref milliamps @ ..]) => {
+                let milliamps = match *milliamps {
+                    [] => None,
+                    [c, d] => Some(u16::from_be_bytes([c, d])),
+                    _ => return Err(DecodeError::BadLength),
+                };
+                let millivolts = u16::from_be_bytes([a, b]);
+                Ok(NodeMessage::Reading { channel, reading: Reading { millivolts, milliamps } })
+            }
+            (
+                | FLAG_MACHINE_OFF
+                | FLAG_MACHINE_ON
+                | FLAG_RECONNECTS
+                | FLAG_KEY_ROTATED
+                | FLAG_LOCKED_OUT
+                | FLAG_ERROR
+                | FLAG_ACK
+                | FLAG_STATE
+                | FLAG_READING,
+                _,
+            ) => Err(DecodeError::BadLength),
+            (unknown, _) => Err(DecodeError::UnknownFlag(unknown)),
+        }
+    }
+}
@@ -0,0 +1,36 @@
+use crate::{ write_frame, Challenge, EncodeError, MacAddress, CHALLENGE_LENGTH, MAC_LENGTH };
+
+pub const POKE_LENGTH: usize = CHALLENGE_LENGTH + MAC_LENGTH;
+
+/// Multicast by the node to find its server: `[<challenge>, <mac>]`.
+///
+/// The MAC address tells the server which node key to answer the challenge with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Poke {
+    pub challenge: Challenge,
+    pub mac_address: MacAddress,
+}
+
+impl Poke {
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, EncodeError> {
+        write_frame(buffer, &[&self.challenge, &self.mac_address])
+    }
+
+    pub fn to_bytes(&self) -> [u8; POKE_LENGTH] {
+        let mut bytes = [0_u8; POKE_LENGTH];
+        // Can't fail, the buffer is exactly one poke.
+        let _ = self.encode(&mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; POKE_LENGTH]) -> Self {
+        let mut poke = Poke {
+            challenge: [0_u8; CHALLENGE_LENGTH],
+            mac_address: [0_u8; MAC_LENGTH],
+        };
+        let (challenge, mac_address) = bytes.split_at(CHALLENGE_LENGTH);
+        poke.challenge.copy_from_slice(challenge);
+        poke.mac_address.copy_from_slice(mac_address);
+        poke
+    }
+}
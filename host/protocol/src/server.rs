@@ -0,0 +1,195 @@
+use crate::{ write_frame, Capabilities, DecodeError, EncodeError, ErrorCode };
+
+const FLAG_COMMAND: u8 = 0;
+const FLAG_ROTATE_KEY: u8 = 1;
+const FLAG_ERROR: u8 = 2;
+const FLAG_REQUEST: u8 = 3;
+
+const ACTION_POWER_ON: u8 = 1;
+const ACTION_POWER_OFF: u8 = 2;
+const ACTION_RESET: u8 = 3;
+const ACTION_FORCE_OFF: u8 = 4;
+const ACTION_WAKE: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    PowerOn,
+    PowerOff,
+    Reset,
+    /// Hold the power switch until the machine cuts its power, for one that hung. Only for nodes
+    /// with [`crate::Capabilities::FORCE_OFF`].
+    ForceOff,
+    /// Press the power switch of a sleeping machine, and only of a sleeping one. Only for nodes
+    /// with [`crate::Capabilities::MACHINE_STATES`].
+    Wake,
+    /// Anything else, the node answers these with its current state.
+    Unknown(u8),
+}
+
+impl From<u8> for Action {
+    fn from(value: u8) -> Self {
+        match value {
+            ACTION_POWER_ON => Action::PowerOn,
+            ACTION_POWER_OFF => Action::PowerOff,
+            ACTION_RESET => Action::Reset,
+            ACTION_FORCE_OFF => Action::ForceOff,
+            ACTION_WAKE => Action::Wake,
+            unknown => Action::Unknown(unknown),
+        }
+    }
+}
+
+impl From<Action> for u8 {
+    fn from(action: Action) -> Self {
+        match action {
+            Action::PowerOn => ACTION_POWER_ON,
+            Action::PowerOff => ACTION_POWER_OFF,
+            Action::Reset => ACTION_RESET,
+            Action::ForceOff => ACTION_FORCE_OFF,
+            Action::Wake => ACTION_WAKE,
+            Action::Unknown(unknown) => unknown,
+        }
+    }
+}
+
+/// How the server wants one command pressed, in milliseconds, 0 leaves the node's own setting.
+/// Only for nodes with [`crate::Capabilities::TIMING_OVERRIDES`], which turn down anything out of
+/// their bounds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct TimingOverrides {
+    /// How long the switch is held: the power press, the reset press or the force off hold,
+    /// whichever the action is.
+    pub press_ms: u32,
+    /// How long the node waits after letting go, before doing anything else.
+    pub settle_ms: u32,
+    /// How long the node waits for the machine to follow the press.
+    pub verify_ms: u32,
+}
+
+impl TimingOverrides {
+    pub const NONE: TimingOverrides = TimingOverrides { press_ms: 0, settle_ms: 0, verify_ms: 0 };
+
+    pub fn is_none(&self) -> bool {
+        *self == TimingOverrides::NONE
+    }
+
+    fn to_be_bytes(self) -> [u8; 12] {
+        let mut bytes = [0_u8; 12];
+        bytes[..4].copy_from_slice(&self.press_ms.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.settle_ms.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.verify_ms.to_be_bytes());
+        bytes
+    }
+}
+
+/// Everything the server sends during a session, each payload starting with a one byte flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerMessage {
+    /// `[0, <action>]`, for nodes without [`crate::Capabilities::COMMAND_ACKS`].
+    Command {
+        action: Action,
+    },
+    /// `[1, <wrapped key>, <grace u32 big endian>]`, take a new key, see
+    /// [`crate::Handshake::wrap_key`]. The old one stays good for `grace_secs` more.
+    RotateKey {
+        wrapped: [u8; 32],
+        grace_secs: u32,
+    },
+    /// `[2, <code>]`, the server is hanging up on the node, and why.
+    Error(ErrorCode),
+    /// `[3, <id u16 big endian>, <action>]`, a command the node answers with a
+    /// [`crate::NodeMessage::Ack`] carrying the same id. Ids start at 1, 0 is for acks that can't
+    /// be matched to a request.
+    ///
+    /// Once [`crate::Capabilities::CHANNELS`] is agreed, the machine's channel always follows the
+    /// action, `[3, <id>, <action>, <channel>]`, the first machine's 0 included. Older nodes only
+    /// drive the one machine.
+    ///
+    /// With timings, `<press ms>, <settle ms>, <verify ms>` go at the very end, each a u32 big
+    /// endian. Without any, it stays the short form older nodes know. They're the only field that
+    /// may or may not be there, anything added later comes with a capability of its own.
+    Request {
+        id: u16,
+        action: Action,
+        channel: u8,
+        overrides: TimingOverrides,
+    },
+}
+
+impl ServerMessage {
+    pub const MAX_LENGTH: usize = 1 + 32 + 4;
+    const TIMINGS_LENGTH: usize = 3 * 4;
+
+    /// Encode for a session that `agreed` on those capabilities, they decide the form of a
+    /// request.
+    pub fn encode(&self, agreed: Capabilities, buffer: &mut [u8]) -> Result<usize, EncodeError> {
+        match self {
+            ServerMessage::Command { action } => {
+                write_frame(buffer, &[&[FLAG_COMMAND, u8::from(*action)]])
+            }
+            ServerMessage::RotateKey { wrapped, grace_secs } => {
+                write_frame(buffer, &[&[FLAG_ROTATE_KEY], wrapped, &grace_secs.to_be_bytes()])
+            }
+            ServerMessage::Error(code) => write_frame(buffer, &[&[FLAG_ERROR, u8::from(*code)]]),
+            ServerMessage::Request { id, action, channel, overrides } => {
+                let channel: &[u8] = if agreed.contains(Capabilities::CHANNELS) {
+                    &[*channel]
+                } else if *channel == 0 {
+                    &[]
+                } else {
+                    return Err(EncodeError::NotAgreed);
+                };
+                let timings: &[u8] = if overrides.is_none() { &[] } else { &overrides.to_be_bytes() };
+                write_frame(
+                    buffer,
+                    &[&[FLAG_REQUEST], &id.to_be_bytes(), &[u8::from(*action)], channel, timings]
+                )
+            }
+        }
+    }
+
+    /// Decode an opened payload, which holds exactly one message, from a session that `agreed` on
+    /// those capabilities.
+    pub fn decode(payload: &[u8], agreed: Capabilities) -> Result<Self, DecodeError> {
+        match payload {
+            &[FLAG_COMMAND, action] => Ok(ServerMessage::Command { action: Action::from(action) }),
+            &[FLAG_ROTATE_KEY, ref rest @ ..] if rest.len() == 32 + 4 => {
+                let mut wrapped = [0_u8; 32];
+                wrapped.copy_from_slice(&rest[..32]);
+                let mut grace_secs = [0_u8; 4];
+                grace_secs.copy_from_slice(&rest[32..]);
+                Ok(ServerMessage::RotateKey { wrapped, grace_secs: u32::from_be_bytes(grace_secs) })
+            }
+            &[FLAG_ERROR, code] => Ok(ServerMessage::Error(ErrorCode::from(code))),
+            &[FLAG_REQUEST, a, b, action, ref rest @ ..] => {
+                let (channel, rest) = if agreed.contains(Capabilities::CHANNELS) {
+                    let (&channel, rest) = rest.split_first().ok_or(DecodeError::BadLength)?;
+                    (channel, rest)
+                } else {
+                    (0, rest)
+                };
+                let overrides = match rest.len() {
+                    0 => TimingOverrides::NONE,
+                    ServerMessage::TIMINGS_LENGTH => {
+                        let field = |index: usize| {
+                            let mut field = [0_u8; 4];
+                            field.copy_from_slice(&rest[index * 4..index * 4 + 4]);
+                            u32::from_be_bytes(field)
+                        };
+                        TimingOverrides { press_ms: field(0), settle_ms: field(1), verify_ms: field(2) }
+                    }
+                    _ => {
+                        return Err(DecodeError::BadLength);
+                    }
+                };
+                let id = u16::from_be_bytes([a, b]);
+                Ok(ServerMessage::Request { id, action: Action::from(action), channel, overrides })
+            }
+            &[FLAG_COMMAND | FLAG_ROTATE_KEY | FLAG_ERROR | FLAG_REQUEST, ..] => {
+                Err(DecodeError::BadLength)
+            }
+            &[unknown, ..] => Err(DecodeError::UnknownFlag(unknown)),
+            [] => Err(DecodeError::BadLength),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use chacha20poly1305::{ aead::AeadInPlace, ChaCha20Poly1305, KeyInit };
+
+use crate::{ DecodeError, EncodeError };
+
+/// Frames are `[<payload length u16 big endian>, <payload>, <tag>]`, the payload being encrypted
+/// in place when the session is.
+pub const LENGTH_PREFIX: usize = 2;
+pub const TAG_LENGTH: usize = 16;
+/// Way more than any message needs, it only bounds the buffers.
+pub const MAX_PAYLOAD_LENGTH: usize = 128;
+pub const MAX_FRAME_LENGTH: usize = LENGTH_PREFIX + MAX_PAYLOAD_LENGTH + TAG_LENGTH;
+
+/// Which end of the session this is, each direction gets tagged apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Node,
+    Server,
+}
+
+impl Role {
+    fn direction(self) -> u8 {
+        match self {
+            Role::Node => 0,
+            Role::Server => 1,
+        }
+    }
+
+    fn peer(self) -> Role {
+        match self {
+            Role::Node => Role::Server,
+            Role::Server => Role::Node,
+        }
+    }
+}
+
+/// How the frames of a session are protected, the node asks for one in its introduction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// Tagged with keyed blake3, anyone on the network can still read them.
+    Plain,
+    /// Encrypted and tagged with ChaCha20-Poly1305.
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn name(self) -> &'static str {
+        match self {
+            Cipher::Plain => "plain",
+            Cipher::ChaCha20Poly1305 => "chacha20poly1305",
+        }
+    }
+}
+
+impl core::str::FromStr for Cipher {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        [Cipher::Plain, Cipher::ChaCha20Poly1305]
+            .into_iter()
+            .find(|cipher| cipher.name() == name)
+            .ok_or(())
+    }
+}
+
+// The session key, ready for whichever cipher the handshake settled on.
+#[derive(Clone)]
+enum Keys {
+    Plain([u8; 32]),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl Keys {
+    fn new(key: [u8; 32], cipher: Cipher) -> Self {
+        match cipher {
+            Cipher::Plain => Keys::Plain(key),
+            Cipher::ChaCha20Poly1305 => Keys::ChaCha20Poly1305(ChaCha20Poly1305::new(&key.into())),
+        }
+    }
+
+    // Over the direction, the frame's number in that direction and the payload.
+    // The counter never travels, both ends count on their own, TCP keeps the order.
+    fn seal(&self, direction: u8, counter: u64, payload: &mut [u8]) -> [u8; TAG_LENGTH] {
+        match self {
+            Keys::Plain(key) => plain_tag(key, direction, counter, payload),
+            Keys::ChaCha20Poly1305(cipher) => {
+                let mut tag = [0_u8; TAG_LENGTH];
+                // The payload never goes over the AEAD's limits, this can't fail.
+                if let Ok(sealed) = cipher.encrypt_in_place_detached(
+                    &aead_nonce(direction, counter),
+                    &[],
+                    payload
+                ) {
+                    tag.copy_from_slice(&sealed);
+                }
+                tag
+            }
+        }
+    }
+
+    fn open(
+        &self,
+        direction: u8,
+        counter: u64,
+        payload: &mut [u8],
+        received: &[u8]
+    ) -> Result<(), DecodeError> {
+        match self {
+            Keys::Plain(key) => {
+                let expected = plain_tag(key, direction, counter, payload);
+
+                // Constant time, a mismatch doesn't tell how far the tag was right.
+                let difference = expected
+                    .iter()
+                    .zip(received)
+                    .fold(0_u8, |difference, (expected, received)| difference | (expected ^ received));
+                if difference != 0 {
+                    return Err(DecodeError::BadTag);
+                }
+                Ok(())
+            }
+            // Checks the tag before decrypting anything, also in constant time.
+            Keys::ChaCha20Poly1305(cipher) =>
+                cipher
+                    .decrypt_in_place_detached(
+                        &aead_nonce(direction, counter),
+                        &[],
+                        payload,
+                        received.into()
+                    )
+                    .map_err(|_| DecodeError::BadTag),
+        }
+    }
+}
+
+fn plain_tag(key: &[u8; 32], direction: u8, counter: u64, payload: &[u8]) -> [u8; TAG_LENGTH] {
+    let hash = blake3::Hasher::new_keyed(key)
+        .update(&[direction])
+        .update(&counter.to_le_bytes())
+        .update(payload)
+        .finalize();
+    let mut tag = [0_u8; TAG_LENGTH];
+    tag.copy_from_slice(&hash.as_bytes()[..TAG_LENGTH]);
+    tag
+}
+
+// `[<direction>, 0, 0, 0, <counter u64 little endian>]`, never the same twice under one key.
+fn aead_nonce(direction: u8, counter: u64) -> chacha20poly1305::Nonce {
+    let mut nonce = [0_u8; 12];
+    nonce[0] = direction;
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce.into()
+}
+
+/// Seals the frames going out of one end of the session.
+#[derive(Clone)]
+pub struct Sealer {
+    keys: Keys,
+    direction: u8,
+    counter: u64,
+}
+
+impl Sealer {
+    pub fn new(key: [u8; 32], role: Role, cipher: Cipher) -> Self {
+        Sealer { keys: Keys::new(key, cipher), direction: role.direction(), counter: 0 }
+    }
+
+    /// Write the whole frame for `payload`, returns its length.
+    pub fn seal(&mut self, payload: &[u8], buffer: &mut [u8]) -> Result<usize, EncodeError> {
+        if payload.len() > MAX_PAYLOAD_LENGTH {
+            return Err(EncodeError::PayloadTooLong);
+        }
+        let length = LENGTH_PREFIX + payload.len() + TAG_LENGTH;
+        if buffer.len() < length {
+            return Err(EncodeError::BufferTooSmall);
+        }
+
+        buffer[..LENGTH_PREFIX].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+        buffer[LENGTH_PREFIX..length - TAG_LENGTH].copy_from_slice(payload);
+        let tag = self.keys.seal(
+            self.direction,
+            self.counter,
+            &mut buffer[LENGTH_PREFIX..length - TAG_LENGTH]
+        );
+        buffer[length - TAG_LENGTH..length].copy_from_slice(&tag);
+        self.counter += 1;
+        Ok(length)
+    }
+}
+
+/// Checks the frames coming in from the other end of the session.
+#[derive(Clone)]
+pub struct Opener {
+    keys: Keys,
+    direction: u8,
+    counter: u64,
+}
+
+impl Opener {
+    pub fn new(key: [u8; 32], role: Role, cipher: Cipher) -> Self {
+        Opener { keys: Keys::new(key, cipher), direction: role.peer().direction(), counter: 0 }
+    }
+
+    /// Check a frame past its length prefix, `[<payload>, <tag>]`, and hand back the payload,
+    /// decrypted in place if the session is encrypted.
+    ///
+    /// The counter only moves on a good frame, so a rejected one doesn't throw the rest off.
+    pub fn open<'a>(&mut self, body: &'a mut [u8]) -> Result<&'a [u8], DecodeError> {
+        if body.len() < TAG_LENGTH {
+            return Err(DecodeError::BadLength);
+        }
+        let (payload, received) = body.split_at_mut(body.len() - TAG_LENGTH);
+        self.keys.open(self.direction, self.counter, payload, received)?;
+
+        self.counter += 1;
+        Ok(payload)
+    }
+}
+
+/// How many bytes follow a frame's length prefix, tag included.
+pub fn frame_length(prefix: [u8; LENGTH_PREFIX]) -> Result<usize, DecodeError> {
+    let payload_length = u16::from_be_bytes(prefix) as usize;
+    if payload_length > MAX_PAYLOAD_LENGTH {
+        return Err(DecodeError::FrameTooLong(payload_length));
+    }
+    Ok(payload_length + TAG_LENGTH)
+}
+
+/// Reassembles frames from a byte stream, for ends that don't read frame by frame.
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+    buffer: [u8; MAX_FRAME_LENGTH],
+    filled: usize,
+    // The last frame was handed out, start over on the next call.
+    complete: bool,
+}
+
+impl FrameDecoder {
+    pub const fn new() -> Self {
+        FrameDecoder {
+            buffer: [0_u8; MAX_FRAME_LENGTH],
+            filled: 0,
+            complete: false,
+        }
+    }
+
+    /// Feed bytes until the first complete frame, returns how many bytes were consumed and the
+    /// frame past its length prefix, ready for [`Opener::open`].
+    ///
+    /// On an error the decoder is reset, but the stream is out of sync by then.
+    pub fn decode(&mut self, bytes: &[u8]) -> (usize, Result<Option<&mut [u8]>, DecodeError>) {
+        if self.complete {
+            self.filled = 0;
+            self.complete = false;
+        }
+
+        let mut consumed = 0;
+        loop {
+            let needed = if self.filled < LENGTH_PREFIX {
+                LENGTH_PREFIX
+            } else {
+                match frame_length([self.buffer[0], self.buffer[1]]) {
+                    Ok(length) => LENGTH_PREFIX + length,
+                    Err(error) => {
+                        self.filled = 0;
+                        return (consumed, Err(error));
+                    }
+                }
+            };
+
+            if self.filled == needed && needed > LENGTH_PREFIX {
+                self.complete = true;
+                return (consumed, Ok(Some(&mut self.buffer[LENGTH_PREFIX..self.filled])));
+            }
+            if consumed == bytes.len() {
+                return (consumed, Ok(None));
+            }
+
+            let taken = (needed - self.filled).min(bytes.len() - consumed);
+            self.buffer[self.filled..self.filled + taken].copy_from_slice(
+                &bytes[consumed..consumed + taken]
+            );
+            self.filled += taken;
+            consumed += taken;
+        }
+    }
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
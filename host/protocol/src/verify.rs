@@ -0,0 +1,171 @@
+use core::net::IpAddr;
+
+use crate::{ Answer, Challenge };
+
+/// Failed attempts a source gets before it's locked out.
+pub const MAX_FAILED_ATTEMPTS: u8 = 5;
+/// The first lockout, every one after that doubles up to [`MAX_LOCKOUT_MS`].
+pub const FIRST_LOCKOUT_MS: u64 = 30_000;
+pub const MAX_LOCKOUT_MS: u64 = 60 * 60 * 1000;
+/// How many sources are kept track of at once.
+pub const TRACKED_SOURCES: usize = 8;
+
+/// Compare without an early exit, so the time taken says nothing about where they differ.
+pub fn constant_time_eq<const N: usize>(a: &[u8; N], b: &[u8; N]) -> bool {
+    let difference = a
+        .iter()
+        .zip(b)
+        .fold(0_u8, |difference, (a, b)| difference | (a ^ b));
+    // Keeps the compiler from turning the fold back into an early exit.
+    core::hint::black_box(difference) == 0
+}
+
+/// Which of the node's keys the server answered the challenge with, None when neither.
+///
+/// Both answers always get worked out and compared, so the timing doesn't tell whether there's a
+/// previous key, or which one it was.
+pub fn answered_key(
+    challenge: &Challenge,
+    answer: &Answer,
+    key: [u8; 32],
+    previous_key: Option<[u8; 32]>
+) -> Option<[u8; 32]> {
+    let current = constant_time_eq(crate::answer(&key, challenge).as_bytes(), answer);
+    let previous = constant_time_eq(
+        crate::answer(&previous_key.unwrap_or(key), challenge).as_bytes(),
+        answer
+    );
+    match (current, previous, previous_key) {
+        (true, _, _) => Some(key),
+        (false, true, Some(previous_key)) => Some(previous_key),
+        _ => None,
+    }
+}
+
+/// Whether the other end proved the key, see [`crate::Handshake`].
+pub fn proof_matches(expected: &blake3::Hash, received: &[u8; 32]) -> bool {
+    constant_time_eq(expected.as_bytes(), received)
+}
+
+/// A source that went over [`MAX_FAILED_ATTEMPTS`], nothing from it is taken for `duration_ms`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lockout {
+    pub address: IpAddr,
+    pub failures: u8,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Source {
+    address: IpAddr,
+    failures: u8,
+    // Lockouts so far, each one doubles the next.
+    lockouts: u8,
+    locked_until: u64,
+    last_seen: u64,
+}
+
+/// Counts failed attempts per source address and locks out the ones that keep failing.
+///
+/// Time is whatever milliseconds the caller counts in, as long as they only go up. A source that
+/// keeps quiet for [`MAX_LOCKOUT_MS`] after its last try or lockout starts over with a clean slate.
+#[derive(Debug, Clone)]
+pub struct AttemptLimiter {
+    sources: [Option<Source>; TRACKED_SOURCES],
+}
+
+impl AttemptLimiter {
+    pub const fn new() -> Self {
+        AttemptLimiter { sources: [None; TRACKED_SOURCES] }
+    }
+
+    /// How much longer `address` stays locked out, None when it may try.
+    pub fn locked_out(&self, address: IpAddr, now_ms: u64) -> Option<u64> {
+        let source = self.sources
+            .iter()
+            .flatten()
+            .find(|source| source.address == address)?;
+        (source.locked_until > now_ms).then(|| source.locked_until - now_ms)
+    }
+
+    /// Count a failed attempt from `address`, returns the lockout it just earned, if any.
+    pub fn failed(&mut self, address: IpAddr, now_ms: u64) -> Option<Lockout> {
+        let source = self.source(address, now_ms);
+        source.last_seen = now_ms;
+        if source.locked_until > now_ms {
+            return None;
+        }
+
+        source.failures = source.failures.saturating_add(1);
+        if source.failures < MAX_FAILED_ATTEMPTS {
+            return None;
+        }
+
+        let duration_ms = FIRST_LOCKOUT_MS.saturating_mul(1 << source.lockouts.min(16)).min(
+            MAX_LOCKOUT_MS
+        );
+        let lockout = Lockout { address, failures: source.failures, duration_ms };
+        source.lockouts = source.lockouts.saturating_add(1);
+        source.locked_until = now_ms + duration_ms;
+        source.failures = 0;
+        Some(lockout)
+    }
+
+    /// `address` got it right, it starts over.
+    pub fn succeeded(&mut self, address: IpAddr) {
+        for slot in self.sources.iter_mut() {
+            if slot.is_some_and(|source| source.address == address) {
+                *slot = None;
+            }
+        }
+    }
+
+    fn source(&mut self, address: IpAddr, now_ms: u64) -> &mut Source {
+        let fresh = Source { address, failures: 0, lockouts: 0, locked_until: 0, last_seen: now_ms };
+
+        let known = self.sources
+            .iter()
+            .position(|slot| slot.is_some_and(|source| source.address == address));
+        let index = match known {
+            Some(index) => {
+                // Quiet long enough since its last try or lockout, forgive it.
+                let source = self.sources[index].as_mut().unwrap();
+                let since = source.last_seen.max(source.locked_until);
+                if now_ms.saturating_sub(since) >= MAX_LOCKOUT_MS {
+                    *source = fresh;
+                }
+                index
+            }
+            None => {
+                let index = self.sources
+                    .iter()
+                    .position(|slot| slot.is_none())
+                    .unwrap_or_else(|| self.evictable(now_ms));
+                self.sources[index] = Some(fresh);
+                index
+            }
+        };
+        self.sources[index].as_mut().unwrap()
+    }
+
+    // The table is full: drop the source seen the longest ago that isn't locked out, or the one
+    // whose lockout ends first when they all are.
+    fn evictable(&self, now_ms: u64) -> usize {
+        let sources = self.sources
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| Some((index, (*slot)?)));
+        let unlocked = sources
+            .clone()
+            .filter(|(_, source)| source.locked_until <= now_ms)
+            .min_by_key(|(_, source)| source.last_seen);
+        let soonest = sources.min_by_key(|(_, source)| source.locked_until);
+        unlocked.or(soonest).map(|(index, _)| index).unwrap_or(0)
+    }
+}
+
+impl Default for AttemptLimiter {
+    fn default() -> Self {
+        AttemptLimiter::new()
+    }
+}
@@ -0,0 +1,168 @@
+use core::fmt;
+
+use crate::{ write_frame, EncodeError };
+
+/// The protocol this crate speaks. 1 was everything before versions were exchanged.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// The oldest version this crate still speaks, anything older gets an explicit error.
+pub const MIN_PROTOCOL_VERSION: u8 = 2;
+
+pub const HELLO_LENGTH: usize = 1 + 4;
+
+/// What each end sends of itself in the handshake, inside its proof so nobody can strip
+/// features off in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub const fn new(capabilities: Capabilities) -> Self {
+        Hello { version: PROTOCOL_VERSION, capabilities }
+    }
+
+    /// What the session runs on: the lower version and the features both ends have. Fails when
+    /// that version is too old for this end.
+    pub fn agree(&self, other: &Hello) -> Result<Hello, ErrorCode> {
+        let version = self.version.min(other.version);
+        if version < MIN_PROTOCOL_VERSION {
+            return Err(ErrorCode::IncompatibleVersion);
+        }
+        Ok(Hello { version, capabilities: self.capabilities & other.capabilities })
+    }
+
+    pub fn encode(&self, buffer: &mut [u8]) -> Result<usize, EncodeError> {
+        write_frame(buffer, &[&[self.version], &self.capabilities.0.to_be_bytes()])
+    }
+
+    pub fn to_bytes(&self) -> [u8; HELLO_LENGTH] {
+        let mut bytes = [0_u8; HELLO_LENGTH];
+        // Can't fail, the buffer is exactly one hello.
+        let _ = self.encode(&mut bytes);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; HELLO_LENGTH]) -> Self {
+        let [version, a, b, c, d] = *bytes;
+        Hello { version, capabilities: Capabilities(u32::from_be_bytes([a, b, c, d])) }
+    }
+}
+
+/// Features an end has on top of the protocol version, one bit each. Unknown bits are kept as
+/// they are, the other end just never agrees to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// ChaCha20-Poly1305 sessions, see [`crate::Cipher`].
+    pub const ENCRYPTED_SESSIONS: Capabilities = Capabilities(1 << 0);
+    /// [`crate::ServerMessage::RotateKey`] and [`crate::NodeMessage::KeyRotated`].
+    pub const KEY_ROTATION: Capabilities = Capabilities(1 << 1);
+    /// [`crate::NodeMessage::LockedOut`].
+    pub const LOCKOUT_REPORTS: Capabilities = Capabilities(1 << 2);
+    /// [`crate::ServerMessage::Request`] and [`crate::NodeMessage::Ack`].
+    pub const COMMAND_ACKS: Capabilities = Capabilities(1 << 3);
+    /// [`crate::Action::ForceOff`].
+    pub const FORCE_OFF: Capabilities = Capabilities(1 << 4);
+    /// [`crate::TimingOverrides`] on [`crate::ServerMessage::Request`].
+    pub const TIMING_OVERRIDES: Capabilities = Capabilities(1 << 5);
+    /// [`crate::CommandResult::Executed`] on a power ON or OFF means the machine followed.
+    pub const VERIFIED_ACTIONS: Capabilities = Capabilities(1 << 6);
+    /// More than one machine per node, the channel goes on every [`crate::ServerMessage::Request`]
+    /// and [`crate::NodeMessage::State`].
+    pub const CHANNELS: Capabilities = Capabilities(1 << 7);
+    /// Sleeping and unknown machines in [`crate::NodeMessage::State`], and [`crate::Action::Wake`].
+    pub const MACHINE_STATES: Capabilities = Capabilities(1 << 8);
+    /// [`crate::NodeMessage::Reading`], what the node measures on its analog pins.
+    pub const ANALOG_READINGS: Capabilities = Capabilities(1 << 9);
+
+    const NAMES: &[(Capabilities, &str)] = &[
+        (Capabilities::ENCRYPTED_SESSIONS, "encrypted-sessions"),
+        (Capabilities::KEY_ROTATION, "key-rotation"),
+        (Capabilities::LOCKOUT_REPORTS, "lockout-reports"),
+        (Capabilities::COMMAND_ACKS, "command-acks"),
+        (Capabilities::FORCE_OFF, "force-off"),
+        (Capabilities::TIMING_OVERRIDES, "timing-overrides"),
+        (Capabilities::VERIFIED_ACTIONS, "verified-actions"),
+        (Capabilities::CHANNELS, "channels"),
+        (Capabilities::MACHINE_STATES, "machine-states"),
+        (Capabilities::ANALOG_READINGS, "analog-readings"),
+    ];
+
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl core::ops::BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        self.union(other)
+    }
+}
+
+/// The known ones by name, comma separated, then whatever bits are left in hex.
+impl fmt::Display for Capabilities {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rest = self.0;
+        let mut first = true;
+        for (capability, name) in Capabilities::NAMES {
+            if self.contains(*capability) {
+                formatter.write_str(if first { "" } else { ", " })?;
+                formatter.write_str(name)?;
+                rest &= !capability.0;
+                first = false;
+            }
+        }
+        if rest != 0 {
+            write!(formatter, "{}{rest:#x}", if first { "" } else { ", " })?;
+        } else if first {
+            formatter.write_str("none")?;
+        }
+        Ok(())
+    }
+}
+
+const ERROR_INCOMPATIBLE_VERSION: u8 = 1;
+
+/// Why an end is hanging up, sent in an error frame right before it does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// The versions in the handshake have nothing in common.
+    IncompatibleVersion,
+    /// Anything else, from a newer end.
+    Unknown(u8),
+}
+
+impl From<u8> for ErrorCode {
+    fn from(value: u8) -> Self {
+        match value {
+            ERROR_INCOMPATIBLE_VERSION => ErrorCode::IncompatibleVersion,
+            unknown => ErrorCode::Unknown(unknown),
+        }
+    }
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::IncompatibleVersion => ERROR_INCOMPATIBLE_VERSION,
+            ErrorCode::Unknown(unknown) => unknown,
+        }
+    }
+}
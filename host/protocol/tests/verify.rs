@@ -0,0 +1,89 @@
+use std::net::IpAddr;
+
+use pibow_protocol::{
+    AttemptLimiter,
+    FIRST_LOCKOUT_MS,
+    MAX_FAILED_ATTEMPTS,
+    MAX_LOCKOUT_MS,
+    TRACKED_SOURCES,
+};
+
+fn address(last: u8) -> IpAddr {
+    IpAddr::from([10, 0, 0, last])
+}
+
+// Fails `address` until it gets locked out, returns how long for.
+fn lock_out(limiter: &mut AttemptLimiter, address: IpAddr, now_ms: u64) -> u64 {
+    for _ in 1..MAX_FAILED_ATTEMPTS {
+        assert_eq!(limiter.failed(address, now_ms), None);
+    }
+    limiter.failed(address, now_ms).expect("locked out").duration_ms
+}
+
+#[test]
+fn answers_are_checked_against_both_keys() {
+    let challenge = [3_u8; 64];
+    let (key, previous_key) = ([1_u8; 32], [2_u8; 32]);
+    let answer = |key| *pibow_protocol::answer(&key, &challenge).as_bytes();
+
+    assert_eq!(pibow_protocol::answered_key(&challenge, &answer(key), key, None), Some(key));
+    let previous = answer(previous_key);
+    let answered = pibow_protocol::answered_key(&challenge, &previous, key, Some(previous_key));
+    assert_eq!(answered, Some(previous_key));
+    assert_eq!(pibow_protocol::answered_key(&challenge, &answer(previous_key), key, None), None);
+    assert_eq!(pibow_protocol::answered_key(&challenge, &[0_u8; 32], key, Some(previous_key)), None);
+
+    assert!(pibow_protocol::constant_time_eq(&[1, 2, 3], &[1, 2, 3]));
+    assert!(!pibow_protocol::constant_time_eq(&[1, 2, 3], &[1, 2, 4]));
+}
+
+#[test]
+fn lockouts_double_up_to_the_cap() {
+    let mut limiter = AttemptLimiter::new();
+    let source = address(1);
+    let mut now_ms = 1000;
+
+    assert_eq!(lock_out(&mut limiter, source, now_ms), FIRST_LOCKOUT_MS);
+    assert_eq!(limiter.locked_out(source, now_ms + 1), Some(FIRST_LOCKOUT_MS - 1));
+    // Attempts during the lockout don't count.
+    assert_eq!(limiter.failed(source, now_ms + 1), None);
+
+    now_ms += FIRST_LOCKOUT_MS;
+    assert_eq!(limiter.locked_out(source, now_ms), None);
+    assert_eq!(lock_out(&mut limiter, source, now_ms), FIRST_LOCKOUT_MS * 2);
+
+    // Coming right back after every lockout.
+    let mut duration_ms = FIRST_LOCKOUT_MS * 2;
+    for _ in 0..10 {
+        now_ms += duration_ms;
+        duration_ms = lock_out(&mut limiter, source, now_ms);
+        assert!(duration_ms <= MAX_LOCKOUT_MS);
+    }
+    assert_eq!(duration_ms, MAX_LOCKOUT_MS);
+}
+
+#[test]
+fn sources_are_counted_apart_and_forgiven() {
+    let mut limiter = AttemptLimiter::new();
+    lock_out(&mut limiter, address(1), 0);
+    assert_eq!(limiter.locked_out(address(2), 0), None);
+
+    // Getting it right wipes the slate.
+    limiter.succeeded(address(1));
+    assert_eq!(limiter.locked_out(address(1), 0), None);
+    assert_eq!(lock_out(&mut limiter, address(1), 0), FIRST_LOCKOUT_MS);
+
+    // So does keeping quiet long enough.
+    let later = FIRST_LOCKOUT_MS + MAX_LOCKOUT_MS;
+    assert_eq!(lock_out(&mut limiter, address(1), later), FIRST_LOCKOUT_MS);
+}
+
+#[test]
+fn a_full_table_keeps_the_locked_out_sources() {
+    let mut limiter = AttemptLimiter::new();
+    lock_out(&mut limiter, address(0), 0);
+    for last in 1..TRACKED_SOURCES as u8 + 4 {
+        limiter.failed(address(last), u64::from(last));
+    }
+    assert!(limiter.locked_out(address(0), 100).is_some());
+}
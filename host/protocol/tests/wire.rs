@@ -0,0 +1,454 @@
+use std::net::IpAddr;
+
+use pibow_protocol::{
+    Action,
+    Capabilities,
+    Cipher,
+    CommandResult,
+    DecodeError,
+    EncodeError,
+    ErrorCode,
+    FrameDecoder,
+    Greeting,
+    Handshake,
+    Hello,
+    Introduction,
+    MachineState,
+    NodeMessage,
+    Poke,
+    Reading,
+    Role,
+    ServerMessage,
+    TimingOverrides,
+    MAX_FRAME_LENGTH,
+};
+
+fn handshake() -> Handshake {
+    Handshake {
+        key: [5_u8; 32],
+        mac_address: [1, 2, 3, 4, 5, 6],
+        server_nonce: [8_u8; 32],
+        node_nonce: [9_u8; 32],
+        cipher: Cipher::Plain,
+        node_hello: Hello::new(Capabilities::KEY_ROTATION),
+    }
+}
+
+fn server_hello() -> Hello {
+    Hello::new(Capabilities::KEY_ROTATION | Capabilities::LOCKOUT_REPORTS)
+}
+
+fn encrypted() -> Handshake {
+    Handshake { cipher: Cipher::ChaCha20Poly1305, ..handshake() }
+}
+
+#[test]
+fn machine_state_is_a_single_byte() {
+    let mut buffer = [0xff_u8; 4];
+    assert_eq!(NodeMessage::MachineOff.encode(&mut buffer), Ok(1));
+    assert_eq!(buffer[0], 0);
+    assert_eq!(NodeMessage::machine_state(true).encode(&mut buffer), Ok(1));
+    assert_eq!(buffer[0], 1);
+    assert_eq!(NodeMessage::decode(&buffer[..1]), Ok(NodeMessage::MachineOn));
+}
+
+#[test]
+fn reconnects_are_a_big_endian_counter() {
+    let mut buffer = [0_u8; NodeMessage::MAX_LENGTH];
+    assert_eq!(NodeMessage::Reconnects(258).encode(&mut buffer), Ok(5));
+    assert_eq!(&buffer[..5], &[3, 0, 0, 1, 2]);
+    assert_eq!(NodeMessage::decode(&buffer[..5]), Ok(NodeMessage::Reconnects(258)));
+    assert_eq!(NodeMessage::decode(&buffer[..3]), Err(DecodeError::BadLength));
+    assert_eq!(NodeMessage::decode(&[11]), Err(DecodeError::UnknownFlag(11)));
+}
+
+#[test]
+fn command_is_flag_then_action() {
+    let command = ServerMessage::Command { action: Action::Reset };
+    let mut buffer = [0_u8; ServerMessage::MAX_LENGTH];
+    assert_eq!(command.encode(Capabilities::NONE, &mut buffer), Ok(2));
+    assert_eq!(&buffer[..2], &[0, 3]);
+    assert_eq!(ServerMessage::decode(&buffer[..2], Capabilities::NONE), Ok(command));
+    assert_eq!(Action::from(4), Action::ForceOff);
+    assert_eq!(Action::from(42), Action::Unknown(42));
+}
+
+#[test]
+fn introduction_is_mac_nonce_hello_then_proof() {
+    let introduction = Introduction {
+        mac_address: [1, 2, 3, 4, 5, 6],
+        nonce: [7_u8; 32],
+        hello: Hello { version: 2, capabilities: Capabilities(0x0102_0304) },
+        proof: [9_u8; 32],
+    };
+    let bytes = introduction.to_bytes();
+    assert_eq!(&bytes[..6], &[1, 2, 3, 4, 5, 6]);
+    assert_eq!(&bytes[6..38], &[7_u8; 32]);
+    assert_eq!(&bytes[38..43], &[2, 1, 2, 3, 4]);
+    assert_eq!(&bytes[43..], &[9_u8; 32]);
+    assert_eq!(Introduction::from_bytes(&bytes), introduction);
+    assert_eq!(introduction.encode(&mut [0_u8; 74]), Err(EncodeError::BufferTooSmall));
+
+    let greeting = Greeting { hello: server_hello(), proof: [3_u8; 32] };
+    let bytes = greeting.to_bytes();
+    assert_eq!(&bytes[..5], &[2, 0, 0, 0, 6]);
+    assert_eq!(Greeting::from_bytes(&bytes), greeting);
+}
+
+#[test]
+fn versions_and_capabilities_are_agreed_on() {
+    let node = Hello::new(Capabilities::KEY_ROTATION | Capabilities::ENCRYPTED_SESSIONS);
+    let agreed = node.agree(&server_hello()).unwrap();
+    assert_eq!(agreed, Hello::new(Capabilities::KEY_ROTATION));
+    assert!(agreed.capabilities.contains(Capabilities::KEY_ROTATION));
+    assert!(!agreed.capabilities.contains(Capabilities::LOCKOUT_REPORTS));
+
+    // A newer end settles on this version, an older one gets turned away.
+    let newer = Hello { version: 9, capabilities: Capabilities(1 << 31) };
+    assert_eq!(node.agree(&newer).map(|hello| hello.version), Ok(2));
+    let older = Hello { version: 1, capabilities: Capabilities::NONE };
+    assert_eq!(node.agree(&older), Err(ErrorCode::IncompatibleVersion));
+
+    assert_eq!(format!("{}", Capabilities::NONE), "none");
+    assert_eq!(format!("{}", Capabilities(0b11 | 1 << 20)), "encrypted-sessions, key-rotation, 0x100000");
+}
+
+#[test]
+fn errors_go_both_ways() {
+    let mut buffer = [0_u8; ServerMessage::MAX_LENGTH];
+    let error = ServerMessage::Error(ErrorCode::IncompatibleVersion);
+    assert_eq!(error.encode(Capabilities::NONE, &mut buffer), Ok(2));
+    assert_eq!(&buffer[..2], &[2, 1]);
+    assert_eq!(ServerMessage::decode(&buffer[..2], Capabilities::NONE), Ok(error));
+
+    assert_eq!(NodeMessage::decode(&[6, 1]), Ok(NodeMessage::Error(ErrorCode::IncompatibleVersion)));
+    assert_eq!(NodeMessage::decode(&[6, 9]), Ok(NodeMessage::Error(ErrorCode::Unknown(9))));
+    assert_eq!(NodeMessage::decode(&[6]), Err(DecodeError::BadLength));
+}
+
+#[test]
+fn requests_get_acked_with_their_id() {
+    let mut buffer = [0_u8; ServerMessage::MAX_LENGTH];
+    let request = ServerMessage::Request {
+        id: 0x0102,
+        action: Action::PowerOff,
+        channel: 0,
+        overrides: TimingOverrides::NONE,
+    };
+    let agreed = Capabilities::COMMAND_ACKS;
+    assert_eq!(request.encode(agreed, &mut buffer), Ok(4));
+    assert_eq!(&buffer[..4], &[3, 1, 2, 2]);
+    assert_eq!(ServerMessage::decode(&buffer[..4], agreed), Ok(request));
+    assert_eq!(ServerMessage::decode(&[3, 1, 2], agreed), Err(DecodeError::BadLength));
+
+    let mut buffer = [0_u8; NodeMessage::MAX_LENGTH];
+    let ack = NodeMessage::Ack { id: 0x0102, result: CommandResult::AlreadyInState };
+    assert_eq!(ack.encode(&mut buffer), Ok(ack.encoded_len()));
+    assert_eq!(&buffer[..4], &[7, 1, 2, 1]);
+    assert_eq!(NodeMessage::decode(&buffer[..4]), Ok(ack));
+
+    let results = [
+        CommandResult::Executed,
+        CommandResult::AlreadyInState,
+        CommandResult::AuthFailed,
+        CommandResult::Busy,
+        CommandResult::UnknownAction,
+        CommandResult::Failed,
+        CommandResult::InvalidTiming,
+        CommandResult::UnknownChannel,
+        CommandResult::Asleep,
+    ];
+    for (code, result) in results.into_iter().enumerate() {
+        assert_eq!(CommandResult::from(code as u8), result);
+        assert_eq!(u8::from(result), code as u8);
+    }
+    assert_eq!(CommandResult::from(9), CommandResult::Unknown(9));
+}
+
+#[test]
+fn requests_the_node_cant_make_out_get_acked_without_an_id() {
+    // A request cut short, and a flag from a newer server. Both opened fine, so nothing was forged.
+    let agreed = Capabilities::COMMAND_ACKS | Capabilities::CHANNELS;
+    assert_eq!(ServerMessage::decode(&[3, 1, 2, 1], agreed), Err(DecodeError::BadLength));
+    assert_eq!(ServerMessage::decode(&[9, 1], agreed), Err(DecodeError::UnknownFlag(9)));
+
+    let mut buffer = [0_u8; NodeMessage::MAX_LENGTH];
+    let ack = NodeMessage::Ack { id: 0, result: CommandResult::UnknownAction };
+    assert_eq!(ack.encode(&mut buffer), Ok(4));
+    assert_eq!(&buffer[..4], &[7, 0, 0, 4]);
+    assert_eq!(NodeMessage::decode(&buffer[..4]), Ok(ack));
+}
+
+#[test]
+fn requests_only_carry_timings_when_there_are_some() {
+    let agreed = Capabilities::TIMING_OVERRIDES;
+    let overrides = TimingOverrides { press_ms: 800, settle_ms: 0, verify_ms: 0x01020304 };
+    let request = ServerMessage::Request { id: 7, action: Action::PowerOn, channel: 0, overrides };
+    let mut buffer = [0_u8; ServerMessage::MAX_LENGTH];
+    assert_eq!(request.encode(agreed, &mut buffer), Ok(16));
+    assert_eq!(&buffer[..16], &[3, 0, 7, 1, 0, 0, 3, 32, 0, 0, 0, 0, 1, 2, 3, 4]);
+    assert_eq!(ServerMessage::decode(&buffer[..16], agreed), Ok(request));
+    assert_eq!(ServerMessage::decode(&buffer[..10], agreed), Err(DecodeError::BadLength));
+
+    // All zeros is the short form.
+    let overrides = TimingOverrides::NONE;
+    let request = ServerMessage::Request { id: 7, action: Action::PowerOn, channel: 0, overrides };
+    assert_eq!(request.encode(agreed, &mut buffer), Ok(4));
+}
+
+#[test]
+fn channels_always_follow_the_action_once_agreed() {
+    let agreed = Capabilities::CHANNELS | Capabilities::TIMING_OVERRIDES;
+    let mut buffer = [0_u8; ServerMessage::MAX_LENGTH];
+    let overrides = TimingOverrides::NONE;
+    let request = ServerMessage::Request { id: 7, action: Action::Reset, channel: 0, overrides };
+    assert_eq!(request.encode(agreed, &mut buffer), Ok(5));
+    assert_eq!(&buffer[..5], &[3, 0, 7, 3, 0]);
+    assert_eq!(ServerMessage::decode(&buffer[..5], agreed), Ok(request));
+    assert_eq!(ServerMessage::decode(&buffer[..4], agreed), Err(DecodeError::BadLength));
+
+    let overrides = TimingOverrides { press_ms: 1, ..TimingOverrides::NONE };
+    let request = ServerMessage::Request { id: 7, action: Action::Reset, channel: 3, overrides };
+    assert_eq!(request.encode(agreed, &mut buffer), Ok(17));
+    assert_eq!(&buffer[4..9], &[3, 0, 0, 0, 1]);
+    assert_eq!(ServerMessage::decode(&buffer[..17], agreed), Ok(request));
+    // The same bytes mean something else to a node that drives one machine.
+    assert_eq!(
+        ServerMessage::decode(&buffer[..17], Capabilities::TIMING_OVERRIDES),
+        Err(DecodeError::BadLength)
+    );
+
+    // Without channels, there's only the first machine.
+    let older = Capabilities::TIMING_OVERRIDES;
+    assert_eq!(request.encode(older, &mut buffer), Err(EncodeError::NotAgreed));
+}
+
+#[test]
+fn every_machine_reports_as_a_state_once_the_server_knows_channels() {
+    let mut buffer = [0_u8; NodeMessage::MAX_LENGTH];
+    let agreed = Capabilities::CHANNELS | Capabilities::MACHINE_STATES;
+    let report = NodeMessage::state_report(0, MachineState::Sleep, agreed).unwrap();
+    assert_eq!(report.encode(&mut buffer), Ok(3));
+    assert_eq!(&buffer[..3], &[9, 0, 2]);
+    assert_eq!(NodeMessage::decode(&buffer[..3]), Ok(report));
+    assert_eq!(NodeMessage::decode(&[9, 3]), Err(DecodeError::BadLength));
+
+    // Without machine states, a sleeping machine is just ON.
+    let channels = Capabilities::CHANNELS;
+    let report = NodeMessage::state_report(2, MachineState::Sleep, channels);
+    assert_eq!(report, Some(NodeMessage::State { channel: 2, state: MachineState::On }));
+    assert_eq!(NodeMessage::state_report(0, MachineState::Unknown, channels), None);
+    assert_eq!(Action::from(5), Action::Wake);
+
+    // The oldest servers only know the first machine, as a single byte.
+    let oldest = Capabilities::COMMAND_ACKS;
+    assert_eq!(NodeMessage::state_report(0, MachineState::On, oldest), Some(NodeMessage::MachineOn));
+    assert_eq!(NodeMessage::state_report(1, MachineState::On, oldest), None);
+    assert_eq!(NodeMessage::decode(&[8, 3, 1]), Err(DecodeError::UnknownFlag(8)));
+}
+
+#[test]
+fn readings_only_carry_milliamps_with_a_shunt() {
+    let mut buffer = [0_u8; NodeMessage::MAX_LENGTH];
+    let reading = Reading { millivolts: 1_850, milliamps: Some(2_400) };
+    let message = NodeMessage::Reading { channel: 1, reading };
+    assert_eq!(message.encode(&mut buffer), Ok(message.encoded_len()));
+    assert_eq!(&buffer[..6], &[10, 1, 0x07, 0x3a, 0x09, 0x60]);
+    assert_eq!(NodeMessage::decode(&buffer[..6]), Ok(message));
+
+    let reading = Reading { millivolts: 1_850, milliamps: None };
+    let message = NodeMessage::Reading { channel: 1, reading };
+    assert_eq!(message.encode(&mut buffer), Ok(4));
+    assert_eq!(NodeMessage::decode(&buffer[..4]), Ok(message));
+    assert_eq!(NodeMessage::decode(&buffer[..5]), Err(DecodeError::BadLength));
+}
+
+#[test]
+fn proofs_depend_on_both_nonces_and_the_side() {
+    let original = handshake();
+    assert_ne!(original.node_proof(), original.server_proof(&server_hello()));
+
+    let mut fresh = handshake();
+    fresh.node_nonce[0] ^= 1;
+    assert_ne!(original.node_proof(), fresh.node_proof());
+    assert_ne!(original.session_key(), fresh.session_key());
+
+    let mut fresh = handshake();
+    fresh.server_nonce[0] ^= 1;
+    assert_ne!(original.server_proof(&server_hello()), fresh.server_proof(&server_hello()));
+
+    // Neither hello can be swapped out on the way.
+    let mut fresh = handshake();
+    fresh.node_hello.capabilities = Capabilities::NONE;
+    assert_ne!(original.node_proof(), fresh.node_proof());
+    let downgraded = Hello::new(Capabilities::NONE);
+    assert_ne!(original.server_proof(&server_hello()), original.server_proof(&downgraded));
+}
+
+#[test]
+fn sealed_frames_open_once_and_in_order() {
+    let (mut node_sealer, _) = handshake().session(Role::Node);
+    let (_, mut server_opener) = handshake().session(Role::Server);
+
+    let mut first = [0_u8; MAX_FRAME_LENGTH];
+    let first_length = node_sealer.seal(&[1], &mut first).unwrap();
+    let mut second = [0_u8; MAX_FRAME_LENGTH];
+    let second_length = node_sealer.seal(&[0], &mut second).unwrap();
+
+    // Out of order, then the real order, then the first one replayed.
+    let mut reordered = second;
+    assert_eq!(server_opener.open(&mut reordered[2..second_length]), Err(DecodeError::BadTag));
+    let mut replayed = first;
+    assert_eq!(server_opener.open(&mut first[2..first_length]), Ok(&[1_u8][..]));
+    assert_eq!(server_opener.open(&mut second[2..second_length]), Ok(&[0_u8][..]));
+    assert_eq!(server_opener.open(&mut replayed[2..first_length]), Err(DecodeError::BadTag));
+}
+
+#[test]
+fn frames_dont_reflect_back() {
+    let (mut node_sealer, mut node_opener) = handshake().session(Role::Node);
+    let mut frame = [0_u8; MAX_FRAME_LENGTH];
+    let length = node_sealer.seal(&[0, 1], &mut frame).unwrap();
+    assert_eq!(node_opener.open(&mut frame[2..length]), Err(DecodeError::BadTag));
+}
+
+#[test]
+fn frame_decoder_stops_at_frame_boundaries() {
+    let (mut sealer, _) = handshake().session(Role::Server);
+    let (_, mut opener) = handshake().session(Role::Node);
+
+    let mut stream = [0_u8; 2 * MAX_FRAME_LENGTH];
+    let first = sealer.seal(&[0, 1], &mut stream).unwrap();
+    let second = sealer.seal(&[0, 2], &mut stream[first..]).unwrap();
+    let stream = &stream[..first + second];
+
+    let mut decoder = FrameDecoder::new();
+    let (consumed, frame) = decoder.decode(&stream[..5]);
+    assert_eq!((consumed, frame.map(|frame| frame.is_some())), (5, Ok(false)));
+
+    let (consumed, frame) = decoder.decode(&stream[5..]);
+    assert_eq!(consumed, first - 5);
+    assert_eq!(opener.open(frame.unwrap().unwrap()), Ok(&[0_u8, 1][..]));
+
+    let (consumed, frame) = decoder.decode(&stream[first..]);
+    assert_eq!(consumed, second);
+    assert_eq!(opener.open(frame.unwrap().unwrap()), Ok(&[0_u8, 2][..]));
+
+    let mut decoder = FrameDecoder::new();
+    let (_, frame) = decoder.decode(&[0xff, 0xff]);
+    assert_eq!(frame.map(|_| ()), Err(DecodeError::FrameTooLong(0xffff)));
+}
+
+#[test]
+fn plain_proofs_keep_their_layout() {
+    let handshake = handshake();
+    let proof = blake3::Hasher::new_keyed(&handshake.key)
+        .update(b"node")
+        .update(&handshake.mac_address)
+        .update(&handshake.server_nonce)
+        .update(&handshake.node_nonce)
+        .update(&handshake.node_hello.to_bytes())
+        .finalize();
+    assert_eq!(handshake.node_proof(), proof);
+    assert_ne!(encrypted().node_proof(), proof);
+    assert_ne!(encrypted().session_key(), handshake.session_key());
+}
+
+#[test]
+fn the_server_settles_on_the_cipher_the_node_proved() {
+    let node = encrypted();
+    let introduction = Introduction {
+        mac_address: node.mac_address,
+        nonce: node.node_nonce,
+        hello: node.node_hello,
+        proof: *node.node_proof().as_bytes(),
+    };
+    let both = [Cipher::Plain, Cipher::ChaCha20Poly1305];
+
+    let server = Handshake::negotiate(&node.key, node.server_nonce, &introduction, &both).unwrap();
+    assert_eq!(server.cipher, Cipher::ChaCha20Poly1305);
+    assert_eq!(server.server_proof(&server_hello()), node.server_proof(&server_hello()));
+
+    // Like an older server, or one that was told to stay plain.
+    let plain = [Cipher::Plain];
+    assert!(Handshake::negotiate(&node.key, node.server_nonce, &introduction, &plain).is_none());
+    assert!(Handshake::negotiate(&[6_u8; 32], node.server_nonce, &introduction, &both).is_none());
+}
+
+#[test]
+fn encrypted_frames_hide_the_payload() {
+    let (mut sealer, _) = encrypted().session(Role::Server);
+    let (_, mut opener) = encrypted().session(Role::Node);
+
+    let mut frame = [0_u8; MAX_FRAME_LENGTH];
+    let length = sealer.seal(&[0, 1, 0, 1], &mut frame).unwrap();
+    assert_eq!(&frame[..2], &[0, 4]);
+    assert_ne!(&frame[2..6], &[0, 1, 0, 1]);
+
+    let mut tampered = frame;
+    tampered[3] ^= 1;
+    assert_eq!(opener.open(&mut tampered[2..length]), Err(DecodeError::BadTag));
+    assert_eq!(opener.open(&mut frame[2..length]), Ok(&[0_u8, 1, 0, 1][..]));
+
+    // A plain end can't make anything of it.
+    let (_, mut plain) = handshake().session(Role::Node);
+    let length = sealer.seal(&[0, 1], &mut frame).unwrap();
+    assert_eq!(plain.open(&mut frame[2..length]), Err(DecodeError::BadTag));
+}
+
+#[test]
+fn poke_is_challenge_then_mac() {
+    let poke = Poke { challenge: [3_u8; 64], mac_address: [1, 2, 3, 4, 5, 6] };
+    let bytes = poke.to_bytes();
+    assert_eq!(&bytes[..64], &[3_u8; 64]);
+    assert_eq!(&bytes[64..], &[1, 2, 3, 4, 5, 6]);
+    assert_eq!(Poke::from_bytes(&bytes), poke);
+}
+
+#[test]
+fn node_keys_differ_per_node_and_generation() {
+    let master_key = [4_u8; 32];
+    let first = pibow_protocol::node_key(&master_key, &[1, 2, 3, 4, 5, 6], 0);
+    assert_eq!(first, pibow_protocol::node_key(&master_key, &[1, 2, 3, 4, 5, 6], 0));
+    assert_ne!(first, pibow_protocol::node_key(&master_key, &[1, 2, 3, 4, 5, 7], 0));
+    assert_ne!(first, pibow_protocol::node_key(&master_key, &[1, 2, 3, 4, 5, 6], 1));
+    assert_ne!(first, master_key);
+}
+
+#[test]
+fn rotated_keys_travel_wrapped() {
+    let new_key = [0x42_u8; 32];
+    let wrapped = handshake().wrap_key(&new_key);
+    assert_ne!(wrapped, new_key);
+    assert_eq!(handshake().unwrap_key(&wrapped), new_key);
+    // Another session wraps it differently.
+    assert_ne!(encrypted().wrap_key(&new_key), wrapped);
+
+    let rotate = ServerMessage::RotateKey { wrapped, grace_secs: 600 };
+    let mut buffer = [0_u8; ServerMessage::MAX_LENGTH];
+    assert_eq!(rotate.encode(Capabilities::NONE, &mut buffer), Ok(37));
+    assert_eq!(buffer[0], 1);
+    assert_eq!(&buffer[33..], &[0, 0, 2, 88]);
+    assert_eq!(ServerMessage::decode(&buffer, Capabilities::NONE), Ok(rotate));
+    assert_eq!(ServerMessage::decode(&buffer[..36], Capabilities::NONE), Err(DecodeError::BadLength));
+
+    assert_eq!(NodeMessage::decode(&[4, 1]), Ok(NodeMessage::KeyRotated { saved: true }));
+}
+
+#[test]
+fn lockouts_carry_the_address() {
+    let lockout = NodeMessage::LockedOut {
+        address: IpAddr::from([192, 168, 1, 9]),
+        failures: 5,
+        secs: 60,
+    };
+    let mut buffer = [0_u8; NodeMessage::MAX_LENGTH];
+    assert_eq!(lockout.encode(&mut buffer), Ok(10));
+    assert_eq!(&buffer[..10], &[5, 5, 0, 0, 0, 60, 192, 168, 1, 9]);
+    assert_eq!(NodeMessage::decode(&buffer[..10]), Ok(lockout));
+    assert_eq!(NodeMessage::decode(&buffer[..9]), Err(DecodeError::BadLength));
+
+    let lockout = NodeMessage::LockedOut { address: IpAddr::from([0xfe_u8; 16]), failures: 5, secs: 60 };
+    assert_eq!(lockout.encode(&mut buffer), Ok(NodeMessage::MAX_LENGTH));
+    assert_eq!(NodeMessage::decode(&buffer), Ok(lockout));
+}
@@ -0,0 +1,100 @@
+use std::{ net::Ipv4Addr, path::PathBuf };
+
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use pibow_protocol::Cipher;
+
+// Same defaults as the firmware's build settings (build.rs).
+const DEFAULT_MULTICAST_IP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 127);
+const DEFAULT_MULTICAST_PORT: u16 = 4265;
+const DEFAULT_NODE_PORT: u16 = 5325;
+const DEFAULT_SERVER_PORT: u16 = 7325;
+const DEFAULT_KEY_BOOK: &str = "pibow-keys.txt";
+
+pub const USAGE: &str =
+    "Usage: pibow-server --master-key <base64 key> [--keys <path>] [--multicast-ip <ip>] [--multicast-port <port>] [--interface <ip>] [--node-port <port>] [--server-port <port>] [--allow-plain <true|false>]
+
+Every node's key is derived from the master key and its MAC address, the master key never leaves the server.
+It can also be given with the PIBOW_MASTER_KEY environment variable. The keys file (pibow-keys.txt by default)
+tracks the keys issued and revoked from the console.
+Nodes pick whether their session is encrypted, --allow-plain false turns away the ones that don't.";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub master_key: [u8; 32],
+    pub key_book: PathBuf,
+    pub multicast_ip: Ipv4Addr,
+    pub multicast_port: u16,
+    // The local address of the interface to join the multicast group on, any by default.
+    pub interface: Ipv4Addr,
+    pub node_port: u16,
+    pub server_port: u16,
+    // The ciphers nodes may ask for, the encrypted one is always there.
+    pub ciphers: Vec<Cipher>,
+}
+
+impl Config {
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut key = std::env::var("PIBOW_MASTER_KEY").ok();
+        let mut config = Config {
+            master_key: [0_u8; 32],
+            key_book: PathBuf::from(DEFAULT_KEY_BOOK),
+            multicast_ip: DEFAULT_MULTICAST_IP,
+            multicast_port: DEFAULT_MULTICAST_PORT,
+            interface: Ipv4Addr::UNSPECIFIED,
+            node_port: DEFAULT_NODE_PORT,
+            server_port: DEFAULT_SERVER_PORT,
+            ciphers: vec![Cipher::ChaCha20Poly1305, Cipher::Plain],
+        };
+
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("Missing value for {flag}"))?;
+            match flag.as_str() {
+                "--master-key" => {
+                    key = Some(value);
+                }
+                "--keys" => {
+                    config.key_book = PathBuf::from(value);
+                }
+                "--multicast-ip" => {
+                    config.multicast_ip = parse(&flag, &value)?;
+                }
+                "--multicast-port" => {
+                    config.multicast_port = parse(&flag, &value)?;
+                }
+                "--interface" => {
+                    config.interface = parse(&flag, &value)?;
+                }
+                "--node-port" => {
+                    config.node_port = parse(&flag, &value)?;
+                }
+                "--server-port" => {
+                    config.server_port = parse(&flag, &value)?;
+                }
+                "--allow-plain" => {
+                    if !parse::<bool>(&flag, &value)? {
+                        config.ciphers.retain(|cipher| *cipher != Cipher::Plain);
+                    }
+                }
+                _ => {
+                    return Err(format!("Unknown option {flag}"));
+                }
+            }
+        }
+
+        let key = key.ok_or("Missing the master key")?;
+        config.master_key = decode_key(&key)?;
+
+        Ok(config)
+    }
+}
+
+pub fn decode_key(key: &str) -> Result<[u8; 32], String> {
+    STANDARD.decode(key.trim())
+        .map_err(|error| format!("The master key isn't valid base64: {error}"))?
+        .try_into()
+        .map_err(|_| "The master key must be 32 bytes".to_string())
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value for {flag}: {value}"))
+}
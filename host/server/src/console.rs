@@ -0,0 +1,234 @@
+use std::io::BufRead;
+
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use pibow_portal::{ access_point_name, access_point_password };
+use pibow_protocol::{ Action, MacAddress, TimingOverrides };
+
+use crate::{
+    keys::{ self, KeyBook },
+    registry::{ format_mac, format_reading, machine, parse_mac, state_name, Registry },
+};
+
+// How long the key before a rotation stays good, unless told otherwise.
+const DEFAULT_GRACE_SECS: u32 = 24 * 60 * 60;
+
+const HELP: &str = "Commands:
+  list                 Show every connected node, its machine states and readings
+  on <mac>             Power the machine ON
+  off <mac>            Power the machine OFF
+  reset <mac>          Press the reset switch
+  force-off <mac>      Hold the power switch until the machine cuts out, for a hung one
+  wake <mac>           Press the power switch of a sleeping machine, only if it sleeps
+                       Each of these takes channel=<n> after the MAC for any machine but
+                       the node's first, and press=<ms>, settle=<ms> and verify=<ms> to
+                       time that one command differently than the node would
+  issue <mac>          Print the key to give a node, a new one if it was revoked
+  revoke <mac>         Pull a node's key and drop its session
+  rotate <mac> [secs]  Move a connected node to its next key, the old one stays good for
+                       the given seconds (a day by default)
+  keys                 Show the keys issued and revoked so far
+  setup-password <mac> Print the name and password of a node's setup access point
+  help                 Show this";
+
+/// Read commands from stdin until it closes.
+pub fn run(registry: &Registry, keys: &KeyBook) {
+    println!("{HELP}");
+
+    for line in std::io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+
+        let action = match command {
+            "list" => {
+                list(registry);
+                continue;
+            }
+            "help" => {
+                println!("{HELP}");
+                continue;
+            }
+            "keys" => {
+                list_keys(keys);
+                continue;
+            }
+            "issue" | "revoke" | "setup-password" => {
+                match words.next().and_then(parse_mac) {
+                    Some(mac_address) if command == "issue" => issue(keys, &mac_address),
+                    Some(mac_address) if command == "revoke" => revoke(registry, keys, &mac_address),
+                    Some(mac_address) => setup_password(keys, &mac_address),
+                    None => println!("Usage: {command} <mac>, like {command} 28:cd:c1:00:00:01"),
+                }
+                continue;
+            }
+            "rotate" => {
+                let mac_address = words.next().and_then(parse_mac);
+                let grace_secs = match words.next() {
+                    Some(grace_secs) => grace_secs.parse().ok(),
+                    None => Some(DEFAULT_GRACE_SECS),
+                };
+                match (mac_address, grace_secs) {
+                    (Some(mac_address), Some(grace_secs)) => {
+                        rotate(registry, keys, &mac_address, grace_secs);
+                    }
+                    _ => {
+                        println!("Usage: rotate <mac> [grace secs], like rotate 28:cd:c1:00:00:01 3600");
+                    }
+                }
+                continue;
+            }
+            "on" => Action::PowerOn,
+            "off" => Action::PowerOff,
+            "reset" => Action::Reset,
+            "force-off" => Action::ForceOff,
+            "wake" => Action::Wake,
+            _ => {
+                println!("Unknown command, try `help`");
+                continue;
+            }
+        };
+
+        let Some(mac_address) = words.next().and_then(parse_mac) else {
+            println!("Usage: {command} <mac>, like {command} 28:cd:c1:00:00:01");
+            continue;
+        };
+        let Some((channel, overrides)) = parse_options(words) else {
+            println!("Options go like channel=1 press=800 settle=0 verify=30000, in milliseconds");
+            continue;
+        };
+
+        let machine = machine(&format_mac(&mac_address), channel);
+        match registry.request(&mac_address, action, channel, overrides) {
+            Ok(Some(id)) => println!("Sent {action:?} to {machine} as request {id}"),
+            Ok(None) => println!("Sent {action:?} to {machine}"),
+            Err(error) => println!("{error}"),
+        }
+    }
+}
+
+// `<name>=<value>` words, the channel and the timings. The node checks the timings against its
+// own bounds.
+fn parse_options<'a>(words: impl Iterator<Item = &'a str>) -> Option<(u8, TimingOverrides)> {
+    let mut channel = 0;
+    let mut overrides = TimingOverrides::NONE;
+    for word in words {
+        let (name, value) = word.split_once('=')?;
+        match name {
+            "channel" => channel = value.parse().ok()?,
+            "press" => overrides.press_ms = value.parse().ok()?,
+            "settle" => overrides.settle_ms = value.parse().ok()?,
+            "verify" => overrides.verify_ms = value.parse().ok()?,
+            _ => {
+                return None;
+            }
+        }
+    }
+    Some((channel, overrides))
+}
+
+fn list(registry: &Registry) {
+    let nodes = registry.list();
+    if nodes.is_empty() {
+        println!("No node connected");
+        return;
+    }
+
+    for node in nodes {
+        let first = match node.states.first() {
+            Some((0, state)) => state_name(*state),
+            _ => "unknown",
+        };
+        let reconnects = node.reconnects
+            .map(|reconnects| format!("  {reconnects} reconnects"))
+            .unwrap_or_default();
+        println!(
+            "{}  {:<21}  {first:<7}{reconnects}",
+            format_mac(&node.mac_address),
+            node.address
+        );
+        // Every other machine on its own line, under the first one's state.
+        for (channel, state) in node.states.iter().filter(|(channel, _)| *channel != 0) {
+            println!("{:>36} {channel:<3}  {}", "channel", state_name(*state));
+        }
+        // Then what the node measures, for machines it senses through its ADC.
+        for (channel, reading) in &node.readings {
+            println!("{:>36} {channel:<3}  {}", "reading", format_reading(reading));
+        }
+    }
+}
+
+fn issue(keys: &KeyBook, mac_address: &MacAddress) {
+    match keys.issue(mac_address) {
+        Ok((key, entry)) => {
+            println!(
+                "Key for {}, generation {}: {}",
+                format_mac(mac_address),
+                entry.generation,
+                STANDARD.encode(key)
+            );
+            println!("Set it on the node with `key set` in its shell, or build it in");
+        }
+        Err(error) => println!("Can't save the keys: {error}"),
+    }
+}
+
+fn revoke(registry: &Registry, keys: &KeyBook, mac_address: &MacAddress) {
+    if let Err(error) = keys.revoke(mac_address) {
+        println!("Can't save the keys: {error}");
+        return;
+    }
+    println!("Revoked {}, `issue` it again for a new key", format_mac(mac_address));
+    if registry.disconnect(mac_address) {
+        println!("Dropped its session");
+    }
+}
+
+fn rotate(registry: &Registry, keys: &KeyBook, mac_address: &MacAddress, grace_secs: u32) {
+    let Some((generation, new_key)) = keys.next_key(mac_address) else {
+        println!("{} is revoked, `issue` it a key first", format_mac(mac_address));
+        return;
+    };
+    // The key book only moves on once the node acknowledges it saved the new key.
+    match registry.rotate(mac_address, generation, &new_key, grace_secs) {
+        Ok(()) => println!("Sent generation {generation} to {}", format_mac(mac_address)),
+        Err(error) => println!("{error}"),
+    }
+}
+
+// Derived from the key the node holds, the same way the node does it.
+fn setup_password(keys: &KeyBook, mac_address: &MacAddress) {
+    let Some(key) = keys.keys(mac_address).first().copied() else {
+        println!("{} is revoked, `issue` it a key first", format_mac(mac_address));
+        return;
+    };
+    println!(
+        "Setup access point: {}, password {}",
+        access_point_name(mac_address),
+        access_point_password(&key)
+    );
+}
+
+fn list_keys(keys: &KeyBook) {
+    let entries = keys.list();
+    if entries.is_empty() {
+        println!("Every node is on its first key");
+        return;
+    }
+
+    for (mac_address, entry) in entries {
+        let grace = entry.grace_until
+            .filter(|until| *until > keys::now())
+            .map(|until| format!("  previous key good for {}s", until - keys::now()))
+            .unwrap_or_default();
+        println!(
+            "{}  generation {}{}{grace}",
+            format_mac(&mac_address),
+            entry.generation,
+            if entry.revoked { "  revoked" } else { "" }
+        );
+    }
+}
@@ -0,0 +1,56 @@
+use std::{
+    io::Write,
+    net::{ Ipv4Addr, SocketAddr, TcpStream, UdpSocket },
+    thread,
+    time::Duration,
+};
+
+use pibow_protocol::{ Poke, POKE_LENGTH };
+
+use crate::{ config::Config, keys::KeyBook };
+
+/// Answer every node poking the multicast group, so they connect back to us.
+pub fn run(config: &Config, keys: &KeyBook) -> std::io::Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, config.multicast_port))?;
+    socket.join_multicast_v4(&config.multicast_ip, &config.interface)?;
+    println!("Listening for nodes on {}:{}", config.multicast_ip, config.multicast_port);
+
+    // One byte bigger, so oversized datagrams don't pass as pokes.
+    let mut datagram = [0_u8; POKE_LENGTH + 1];
+    loop {
+        let (length, source) = socket.recv_from(&mut datagram)?;
+        if length != POKE_LENGTH {
+            continue;
+        }
+
+        let mut poke = [0_u8; POKE_LENGTH];
+        poke.copy_from_slice(&datagram[..POKE_LENGTH]);
+        let poke = Poke::from_bytes(&poke);
+        // Revoked nodes get nothing, not even a wrong answer.
+        let answers: Vec<_> = keys
+            .keys(&poke.mac_address)
+            .iter()
+            .map(|key| pibow_protocol::answer(key, &poke.challenge))
+            .collect();
+        if answers.is_empty() {
+            continue;
+        }
+        let node_address = SocketAddr::new(source.ip(), config.node_port);
+
+        // The node only waits 2 seconds for us, don't let a slow one hold up the others.
+        thread::spawn(move || {
+            // One connection per key the node may hold, it hangs up on the wrong ones and stops
+            // listening after the right one. Only the current key is worth complaining about.
+            for (index, answer) in answers.iter().enumerate() {
+                let result = TcpStream::connect_timeout(&node_address, Duration::from_secs(2)).and_then(
+                    |mut stream| stream.write_all(answer.as_bytes())
+                );
+                if let Err(error) = result
+                    && index == 0
+                {
+                    eprintln!("Can't answer the node at {node_address}: {error}");
+                }
+            }
+        });
+    }
+}
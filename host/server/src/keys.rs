@@ -0,0 +1,207 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{ Path, PathBuf },
+    sync::Mutex,
+    time::{ SystemTime, UNIX_EPOCH },
+};
+
+use pibow_protocol::MacAddress;
+
+use crate::registry::{ format_mac, parse_mac };
+
+/// Which key every node holds, out of the master key and a small file kept next to the server.
+///
+/// A node that was never issued a key explicitly is on generation 0, so a fresh fleet only needs
+/// the master key. The file has one node per line: `<mac> <generation>`, plus `revoked` once the
+/// node's key was pulled, or `grace <unix seconds>` while the generation before is still good
+/// after a rotation.
+pub struct KeyBook {
+    master_key: [u8; 32],
+    path: PathBuf,
+    entries: Mutex<HashMap<MacAddress, Entry>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub generation: u32,
+    pub revoked: bool,
+    // Until when the generation before this one is still taken, in seconds since the epoch.
+    pub grace_until: Option<u64>,
+}
+
+impl KeyBook {
+    /// Read the key book back, a missing file is an empty one.
+    pub fn load(master_key: [u8; 32], path: PathBuf) -> Result<Self, String> {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(error) => {
+                return Err(format!("Can't read {}: {error}", path.display()));
+            }
+        };
+
+        let mut entries = HashMap::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let bad_line = || {
+                format!(
+                    "{}:{} isn't `<mac> <generation> [revoked] [grace <unix seconds>]`",
+                    path.display(),
+                    index + 1
+                )
+            };
+
+            let mut words = line.split_whitespace();
+            let mac_address = words.next().and_then(parse_mac).ok_or_else(bad_line)?;
+            let generation = words
+                .next()
+                .and_then(|generation| generation.parse().ok())
+                .ok_or_else(bad_line)?;
+            let mut entry = Entry { generation, revoked: false, grace_until: None };
+            while let Some(word) = words.next() {
+                match word {
+                    "revoked" => {
+                        entry.revoked = true;
+                    }
+                    "grace" => {
+                        let until = words.next().and_then(|until| until.parse().ok());
+                        entry.grace_until = Some(until.ok_or_else(bad_line)?);
+                    }
+                    _ => {
+                        return Err(bad_line());
+                    }
+                }
+            }
+            entries.insert(mac_address, entry);
+        }
+
+        Ok(KeyBook { master_key, path, entries: Mutex::new(entries) })
+    }
+
+    /// The keys a node may be holding, the current one first, then the one before while a
+    /// rotation's grace window lasts. None at all once it's revoked.
+    pub fn keys(&self, mac_address: &MacAddress) -> Vec<[u8; 32]> {
+        let entry = self.entries.lock().unwrap().get(mac_address).copied().unwrap_or_default();
+        if entry.revoked {
+            return Vec::new();
+        }
+
+        let key = |generation| pibow_protocol::node_key(&self.master_key, mac_address, generation);
+        let mut keys = vec![key(entry.generation)];
+        let in_grace = entry.grace_until.is_some_and(|until| now() < until);
+        if in_grace && entry.generation > 0 {
+            keys.push(key(entry.generation - 1));
+        }
+        keys
+    }
+
+    /// The generation and key a node rotates to next, None once it's revoked.
+    pub fn next_key(&self, mac_address: &MacAddress) -> Option<(u32, [u8; 32])> {
+        let entry = self.entries.lock().unwrap().get(mac_address).copied().unwrap_or_default();
+        if entry.revoked {
+            return None;
+        }
+        let generation = entry.generation + 1;
+        Some((generation, pibow_protocol::node_key(&self.master_key, mac_address, generation)))
+    }
+
+    /// The node saved the key of `generation`, the one before stays good for `grace_secs`.
+    pub fn rotated(
+        &self,
+        mac_address: &MacAddress,
+        generation: u32,
+        grace_secs: u32
+    ) -> std::io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = Entry {
+            generation,
+            revoked: false,
+            grace_until: Some(now() + grace_secs as u64),
+        };
+        self.update(&mut entries, mac_address, entry)
+    }
+
+    /// Hand out a node's key. A revoked node gets the next generation, the old key stays dead.
+    pub fn issue(&self, mac_address: &MacAddress) -> std::io::Result<([u8; 32], Entry)> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut entry = entries.get(mac_address).copied().unwrap_or_default();
+        if entry.revoked {
+            entry.generation += 1;
+            entry.revoked = false;
+            entry.grace_until = None;
+        }
+        self.update(&mut entries, mac_address, entry)?;
+
+        Ok((pibow_protocol::node_key(&self.master_key, mac_address, entry.generation), entry))
+    }
+
+    pub fn revoke(&self, mac_address: &MacAddress) -> std::io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut entry = entries.get(mac_address).copied().unwrap_or_default();
+        entry.revoked = true;
+        // The key before goes with it.
+        entry.grace_until = None;
+        self.update(&mut entries, mac_address, entry)
+    }
+
+    // Only takes the change once it's on disk.
+    fn update(
+        &self,
+        entries: &mut HashMap<MacAddress, Entry>,
+        mac_address: &MacAddress,
+        entry: Entry
+    ) -> std::io::Result<()> {
+        let mut updated = entries.clone();
+        updated.insert(*mac_address, entry);
+        save(&self.path, &updated)?;
+        *entries = updated;
+        Ok(())
+    }
+
+    pub fn list(&self) -> Vec<(MacAddress, Entry)> {
+        let mut entries: Vec<(MacAddress, Entry)> = self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(mac_address, entry)| (*mac_address, *entry))
+            .collect();
+        entries.sort_by_key(|(mac_address, _)| *mac_address);
+        entries
+    }
+}
+
+// Written aside and renamed over, a crash halfway never leaves half a key book.
+fn save(path: &Path, entries: &HashMap<MacAddress, Entry>) -> std::io::Result<()> {
+    let mut sorted: Vec<_> = entries.iter().collect();
+    sorted.sort_by_key(|(mac_address, _)| **mac_address);
+
+    let mut text = String::from(
+        "# Pibow node keys: <mac> <generation> [revoked] [grace <unix seconds>]\n"
+    );
+    for (mac_address, entry) in sorted {
+        text.push_str(&format_mac(mac_address));
+        text.push_str(&format!(" {}", entry.generation));
+        if entry.revoked {
+            text.push_str(" revoked");
+        }
+        if let Some(until) = entry.grace_until {
+            text.push_str(&format!(" grace {until}"));
+        }
+        text.push('\n');
+    }
+
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".new");
+    fs::write(&temporary, text)?;
+    fs::rename(&temporary, path)
+}
+
+/// Seconds since the epoch, what grace windows are kept in.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or(0)
+}
@@ -0,0 +1,9 @@
+//! The reference server's parts, `main.rs` wires them together. Kept as a library so the tests
+//! can drive them.
+
+pub mod config;
+pub mod console;
+pub mod discovery;
+pub mod keys;
+pub mod registry;
+pub mod session;
@@ -0,0 +1,59 @@
+//! Reference server for Pibow nodes.
+//!
+//! Discovers nodes through the multicast group, keeps a session with each of them and takes
+//! power commands from stdin. It also holds the fleet's master key, issuing and revoking the keys
+//! of single nodes.
+
+use std::{ process::ExitCode, sync::Arc, thread };
+
+use pibow_server::{
+    config::{ self, Config },
+    console,
+    discovery,
+    keys::KeyBook,
+    registry::Registry,
+    session,
+};
+
+fn main() -> ExitCode {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}\n\n{}", config::USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let keys = match KeyBook::load(config.master_key, config.key_book.clone()) {
+        Ok(keys) => Arc::new(keys),
+        Err(error) => {
+            eprintln!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let registry = Arc::new(Registry::new());
+
+    {
+        let config = config.clone();
+        let keys = keys.clone();
+        thread::spawn(move || {
+            if let Err(error) = discovery::run(&config, &keys) {
+                eprintln!("Discovery stopped: {error}");
+            }
+        });
+    }
+    {
+        let config = config.clone();
+        let keys = keys.clone();
+        let registry = registry.clone();
+        thread::spawn(move || {
+            if let Err(error) = session::run(&config, keys, registry) {
+                eprintln!("Session listener stopped: {error}");
+            }
+        });
+    }
+
+    console::run(&registry, &keys);
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,349 @@
+use std::{
+    collections::{ BTreeMap, HashMap },
+    fmt::Write as _,
+    io::Write,
+    net::{ Shutdown, SocketAddr, TcpStream },
+    sync::{ mpsc::{ self, Receiver, Sender }, Mutex },
+    thread,
+    time::Duration,
+};
+
+use pibow_protocol::{
+    Action,
+    Capabilities,
+    Handshake,
+    MacAddress,
+    MachineState,
+    Reading,
+    Sealer,
+    ServerMessage,
+    TimingOverrides,
+    MAX_FRAME_LENGTH,
+};
+
+// How long a node gets to take a frame before its session is cut.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Every node currently holding a session with this server, keyed by MAC address.
+pub struct Registry {
+    nodes: Mutex<HashMap<MacAddress, Node>>,
+    next_session: Mutex<u64>,
+}
+
+struct Node {
+    // Tells a replaced session apart from the current one.
+    session: u64,
+    address: SocketAddr,
+    // By channel, only the ones the node reported so far.
+    states: BTreeMap<u8, MachineState>,
+    // The same for what the node measures, on machines it senses through its ADC.
+    readings: BTreeMap<u8, Reading>,
+    reconnects: Option<u32>,
+    // Kept to wrap rotated keys under this session.
+    handshake: Handshake,
+    // What the node and this server agreed on in the handshake.
+    capabilities: Capabilities,
+    // The generation and grace window of a rotation the node didn't acknowledge yet.
+    rotation: Option<(u32, u32)>,
+    // Requests the node didn't ack yet, by id, along with their channel.
+    requests: HashMap<u16, (Action, u8)>,
+    next_request: u16,
+    // Seals everything going to the node, frames have to go out in the order they were sealed.
+    sealer: Sealer,
+    // Sealed frames for the node's writer thread, which keeps that order. Nothing gets written
+    // under the registry's lock, a node that doesn't read would hold up every other one.
+    frames: Sender<Vec<u8>>,
+    // Only to cut the session.
+    stream: TcpStream,
+}
+
+pub struct NodeSummary {
+    pub mac_address: MacAddress,
+    pub address: SocketAddr,
+    /// The machine state on every channel reported so far, in channel order.
+    pub states: Vec<(u8, MachineState)>,
+    /// The last reading on every channel that has one, in channel order.
+    pub readings: Vec<(u8, Reading)>,
+    pub reconnects: Option<u32>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            nodes: Mutex::new(HashMap::new()),
+            next_session: Mutex::new(0),
+        }
+    }
+
+    /// Start tracking a node, kicking out any older session from the same MAC. Frames to it go out
+    /// through `writer`, on a thread of its own.
+    pub fn register(
+        &self,
+        mac_address: MacAddress,
+        address: SocketAddr,
+        handshake: Handshake,
+        capabilities: Capabilities,
+        sealer: Sealer,
+        writer: TcpStream
+    ) -> std::io::Result<u64> {
+        let stream = writer.try_clone()?;
+        let (frames, queued) = mpsc::channel();
+        thread::spawn(move || write_frames(writer, queued, address));
+
+        let session = {
+            let mut next_session = self.next_session.lock().unwrap();
+            *next_session += 1;
+            *next_session
+        };
+
+        let node = Node {
+            session,
+            address,
+            states: BTreeMap::new(),
+            readings: BTreeMap::new(),
+            reconnects: None,
+            handshake,
+            capabilities,
+            rotation: None,
+            requests: HashMap::new(),
+            next_request: 0,
+            sealer,
+            frames,
+            stream,
+        };
+        if let Some(old) = self.nodes.lock().unwrap().insert(mac_address, node) {
+            let _ = old.stream.shutdown(Shutdown::Both);
+        }
+
+        Ok(session)
+    }
+
+    /// Cut a node's session, whichever it is. Returns false when it wasn't connected.
+    pub fn disconnect(&self, mac_address: &MacAddress) -> bool {
+        match self.nodes.lock().unwrap().remove(mac_address) {
+            Some(node) => {
+                let _ = node.stream.shutdown(Shutdown::Both);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn unregister(&self, mac_address: &MacAddress, session: u64) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if nodes.get(mac_address).is_some_and(|node| node.session == session) {
+            nodes.remove(mac_address);
+        }
+    }
+
+    /// Returns true when the state actually changed.
+    pub fn set_state(
+        &self,
+        mac_address: &MacAddress,
+        session: u64,
+        channel: u8,
+        state: MachineState
+    ) -> bool {
+        let mut nodes = self.nodes.lock().unwrap();
+        match nodes.get_mut(mac_address) {
+            Some(node) if node.session == session => {
+                node.states.insert(channel, state) != Some(state)
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_reading(&self, mac_address: &MacAddress, session: u64, channel: u8, reading: Reading) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Some(node) = nodes.get_mut(mac_address).filter(|node| node.session == session) {
+            node.readings.insert(channel, reading);
+        }
+    }
+
+    pub fn set_reconnects(&self, mac_address: &MacAddress, session: u64, reconnects: u32) {
+        let mut nodes = self.nodes.lock().unwrap();
+        if let Some(node) = nodes.get_mut(mac_address).filter(|node| node.session == session) {
+            node.reconnects = Some(reconnects);
+        }
+    }
+
+    /// Send a node a command, returns the id its ack comes back with. None when the node
+    /// doesn't ack commands, it only reports its state then.
+    pub fn request(
+        &self,
+        mac_address: &MacAddress,
+        action: Action,
+        channel: u8,
+        overrides: TimingOverrides
+    ) -> Result<Option<u16>, String> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(mac_address).ok_or("No such node connected")?;
+        if action == Action::ForceOff && !node.capabilities.contains(Capabilities::FORCE_OFF) {
+            return Err("The node can't force its machine off".to_string());
+        }
+        if action == Action::Wake && !node.capabilities.contains(Capabilities::MACHINE_STATES) {
+            return Err("The node can't tell a sleeping machine apart".to_string());
+        }
+        if !overrides.is_none() && !node.capabilities.contains(Capabilities::TIMING_OVERRIDES) {
+            return Err("The node doesn't take timings from the server".to_string());
+        }
+        if channel != 0 && !node.capabilities.contains(Capabilities::CHANNELS) {
+            return Err("The node only drives the one machine".to_string());
+        }
+        let unreachable = |error| format!("Can't reach the node: {error}");
+        if !node.capabilities.contains(Capabilities::COMMAND_ACKS) {
+            send(node, ServerMessage::Command { action }).map_err(unreachable)?;
+            return Ok(None);
+        }
+
+        // 0 is for acks that can't be matched to a request.
+        node.next_request = node.next_request.checked_add(1).unwrap_or(1);
+        let id = node.next_request;
+        send(node, ServerMessage::Request { id, action, channel, overrides }).map_err(unreachable)?;
+        node.requests.insert(id, (action, channel));
+        Ok(Some(id))
+    }
+
+    /// The request a node just acked and its channel, if this session sent it.
+    pub fn take_request(
+        &self,
+        mac_address: &MacAddress,
+        session: u64,
+        id: u16
+    ) -> Option<(Action, u8)> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(mac_address).filter(|node| node.session == session)?;
+        node.requests.remove(&id)
+    }
+
+    /// Hand a node the key of `generation`, wrapped under its session.
+    pub fn rotate(
+        &self,
+        mac_address: &MacAddress,
+        generation: u32,
+        new_key: &[u8; 32],
+        grace_secs: u32
+    ) -> Result<(), String> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(mac_address).ok_or("No such node connected")?;
+        if !node.capabilities.contains(Capabilities::KEY_ROTATION) {
+            return Err("The node doesn't do key rotation".to_string());
+        }
+        let wrapped = node.handshake.wrap_key(new_key);
+        send(node, ServerMessage::RotateKey { wrapped, grace_secs }).map_err(|error| {
+            format!("Can't reach the node: {error}")
+        })?;
+        node.rotation = Some((generation, grace_secs));
+        Ok(())
+    }
+
+    /// The rotation a node just acknowledged, if this session sent one.
+    pub fn take_rotation(&self, mac_address: &MacAddress, session: u64) -> Option<(u32, u32)> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let node = nodes.get_mut(mac_address).filter(|node| node.session == session)?;
+        node.rotation.take()
+    }
+
+    pub fn list(&self) -> Vec<NodeSummary> {
+        let mut summaries: Vec<NodeSummary> = self.nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(mac_address, node)| NodeSummary {
+                mac_address: *mac_address,
+                address: node.address,
+                states: node.states
+                    .iter()
+                    .map(|(channel, state)| (*channel, *state))
+                    .collect(),
+                readings: node.readings
+                    .iter()
+                    .map(|(channel, reading)| (*channel, *reading))
+                    .collect(),
+                reconnects: node.reconnects,
+            })
+            .collect();
+        summaries.sort_by_key(|summary| summary.mac_address);
+        summaries
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+fn send(node: &mut Node, message: ServerMessage) -> std::io::Result<()> {
+    let mut payload = [0_u8; ServerMessage::MAX_LENGTH];
+    let mut frame = [0_u8; MAX_FRAME_LENGTH];
+    // Both buffers fit any server message, only what the node didn't agree to fails.
+    let length = message.encode(node.capabilities, &mut payload).map_err(|error| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{error:?}"))
+    })?;
+    let length = node.sealer.seal(&payload[..length], &mut frame).unwrap_or(0);
+    node.frames.send(frame[..length].to_vec()).map_err(|_| {
+        std::io::Error::new(std::io::ErrorKind::BrokenPipe, "its session is being cut")
+    })
+}
+
+// Write a node's frames in the order they were sealed, until its session goes. One that stops
+// reading gets cut, its session's reader then unregisters it.
+fn write_frames(mut writer: TcpStream, frames: Receiver<Vec<u8>>, address: SocketAddr) {
+    let _ = writer.set_write_timeout(Some(WRITE_TIMEOUT));
+    for frame in frames {
+        if let Err(error) = writer.write_all(&frame) {
+            eprintln!("Can't write to the node at {address}, cutting its session: {error}");
+            let _ = writer.shutdown(Shutdown::Both);
+            return;
+        }
+    }
+}
+
+/// How a machine shows up in the output, the node's first one goes by the node's MAC alone.
+pub fn machine(mac: &str, channel: u8) -> String {
+    match channel {
+        0 => mac.to_string(),
+        channel => format!("{mac} channel {channel}"),
+    }
+}
+
+/// How a machine state shows up in the output.
+pub fn state_name(state: MachineState) -> &'static str {
+    match state {
+        MachineState::Off => "OFF",
+        MachineState::On => "ON",
+        MachineState::Sleep => "asleep",
+        MachineState::Unknown => "unknown",
+    }
+}
+
+pub fn format_reading(reading: &Reading) -> String {
+    match reading.milliamps {
+        Some(milliamps) => format!("{} mV, {milliamps} mA", reading.millivolts),
+        None => format!("{} mV", reading.millivolts),
+    }
+}
+
+pub fn format_mac(mac_address: &MacAddress) -> String {
+    let mut formatted = String::new();
+    for (index, byte) in mac_address.iter().enumerate() {
+        if index > 0 {
+            formatted.push(':');
+        }
+        let _ = write!(formatted, "{byte:02x}");
+    }
+    formatted
+}
+
+pub fn parse_mac(text: &str) -> Option<MacAddress> {
+    let mut mac_address = [0_u8; 6];
+    let mut parts = text.split([':', '-']);
+    for byte in mac_address.iter_mut() {
+        *byte = u8::from_str_radix(parts.next()?, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(mac_address)
+}
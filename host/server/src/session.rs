@@ -0,0 +1,341 @@
+use std::{
+    io::{ Read, Write },
+    net::{ Ipv4Addr, SocketAddr, TcpListener, TcpStream },
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use pibow_protocol::{
+    Action,
+    Capabilities,
+    Cipher,
+    CommandResult,
+    DecodeError,
+    FrameDecoder,
+    Greeting,
+    Handshake,
+    Hello,
+    Introduction,
+    MachineState,
+    NodeMessage,
+    Opener,
+    Role,
+    Sealer,
+    ServerMessage,
+    INTRODUCTION_LENGTH,
+    MAX_FRAME_LENGTH,
+    MIN_PROTOCOL_VERSION,
+};
+
+use crate::{
+    config::Config,
+    keys::KeyBook,
+    registry::{ format_mac, machine, state_name, Registry },
+};
+
+// Everything this server does on top of the protocol version, the encrypted sessions are
+// always taken.
+const CAPABILITIES: Capabilities = Capabilities::ENCRYPTED_SESSIONS.union(Capabilities::KEY_ROTATION)
+    .union(Capabilities::LOCKOUT_REPORTS)
+    .union(Capabilities::COMMAND_ACKS)
+    .union(Capabilities::FORCE_OFF)
+    .union(Capabilities::TIMING_OVERRIDES)
+    .union(Capabilities::VERIFIED_ACTIONS)
+    .union(Capabilities::CHANNELS)
+    .union(Capabilities::MACHINE_STATES)
+    .union(Capabilities::ANALOG_READINGS);
+
+/// Accept the nodes connecting back after discovery, one thread per node.
+pub fn run(config: &Config, keys: Arc<KeyBook>, registry: Arc<Registry>) -> std::io::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, config.server_port))?;
+    println!("Waiting for node sessions on port {}", config.server_port);
+    serve(listener, &config.ciphers, keys, registry);
+    Ok(())
+}
+
+/// The same on a listener that's already bound, taking the nodes that ask for one of `ciphers`.
+pub fn serve(listener: TcpListener, ciphers: &[Cipher], keys: Arc<KeyBook>, registry: Arc<Registry>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                eprintln!("Can't accept a node: {error}");
+                continue;
+            }
+        };
+
+        let keys = keys.clone();
+        let registry = registry.clone();
+        let ciphers = ciphers.to_vec();
+        thread::spawn(move || {
+            let address = match stream.peer_addr() {
+                Ok(address) => address,
+                Err(_) => {
+                    return;
+                }
+            };
+            if let Err(error) = handle(stream, address, &keys, &ciphers, &registry) {
+                eprintln!("Session with {address} ended: {error}");
+            }
+        });
+    }
+}
+
+fn handle(
+    mut stream: TcpStream,
+    address: SocketAddr,
+    keys: &KeyBook,
+    ciphers: &[Cipher],
+    registry: &Registry
+) -> std::io::Result<()> {
+    // Send a fresh nonce, the node has to prove the key over it and its own nonce.
+    let server_nonce: pibow_protocol::Nonce = rand::random();
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(&server_nonce)?;
+
+    let mut introduction = [0_u8; INTRODUCTION_LENGTH];
+    stream.read_exact(&mut introduction)?;
+    let introduction = Introduction::from_bytes(&introduction);
+    let node_keys = keys.keys(&introduction.mac_address);
+    if node_keys.is_empty() {
+        eprintln!("Node {} at {address} is revoked, dropping it", format_mac(&introduction.mac_address));
+        return Ok(());
+    }
+
+    // The node's proof also tells which cipher it wants, and which key it holds. Right after a
+    // rotation it may still be on the one before.
+    let negotiated = node_keys.iter().enumerate().find_map(|(index, key)| {
+        let handshake = Handshake::negotiate(key, server_nonce, &introduction, ciphers)?;
+        Some((index, handshake))
+    });
+    let Some((key_index, handshake)) = negotiated else {
+        eprintln!("Node at {address} failed the handshake, dropping it");
+        return Ok(());
+    };
+    // Now prove the key back, the node takes nothing from this server before that.
+    let hello = Hello::new(CAPABILITIES);
+    let greeting = Greeting { hello, proof: *handshake.server_proof(&hello).as_bytes() };
+    stream.write_all(&greeting.to_bytes())?;
+
+    let (mut sealer, opener) = handshake.session(Role::Server);
+    let mac_address = introduction.mac_address;
+    let mac = format_mac(&mac_address);
+
+    // Tell a node too old for this server why it's being hung up on.
+    let agreed = match hello.agree(&introduction.hello) {
+        Ok(agreed) => agreed,
+        Err(code) => {
+            eprintln!(
+                "Node {mac} speaks protocol {}, this server needs {MIN_PROTOCOL_VERSION}, dropping it",
+                introduction.hello.version
+            );
+            return send(&mut stream, &mut sealer, ServerMessage::Error(code));
+        }
+    };
+
+    let cipher = handshake.cipher;
+    let session = registry.register(
+        mac_address,
+        address,
+        handshake,
+        agreed.capabilities,
+        sealer,
+        stream.try_clone()?
+    )?;
+    println!(
+        "Node {mac} connected from {address}, {} session, protocol {} ({})",
+        cipher.name(),
+        agreed.version,
+        agreed.capabilities
+    );
+    if key_index > 0 {
+        println!("Node {mac} is still on its previous key, `rotate` it again before the grace ends");
+    }
+
+    stream.set_read_timeout(None)?;
+    let result = read_messages(
+        &mut stream,
+        &mac_address,
+        session,
+        agreed.capabilities,
+        opener,
+        keys,
+        registry
+    );
+
+    registry.unregister(&mac_address, session);
+    println!("Node {mac} disconnected");
+    result
+}
+
+fn read_messages(
+    stream: &mut TcpStream,
+    mac_address: &pibow_protocol::MacAddress,
+    session: u64,
+    capabilities: Capabilities,
+    mut opener: Opener,
+    keys: &KeyBook,
+    registry: &Registry
+) -> std::io::Result<()> {
+    let mac = format_mac(mac_address);
+    let verified = capabilities.contains(Capabilities::VERIFIED_ACTIONS);
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = [0_u8; 256];
+
+    loop {
+        let length = stream.read(&mut buffer)?;
+        if length == 0 {
+            return Ok(());
+        }
+
+        let mut bytes = &buffer[..length];
+        while !bytes.is_empty() {
+            let (consumed, frame) = decoder.decode(bytes);
+            bytes = &bytes[consumed..];
+
+            let frame = match frame {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    continue;
+                }
+                Err(error) => {
+                    return Err(invalid(error));
+                }
+            };
+            // A frame that doesn't open means someone's meddling with the stream, drop it all.
+            let message = opener.open(frame).and_then(NodeMessage::decode).map_err(invalid)?;
+
+            match message {
+                NodeMessage::MachineOff
+                | NodeMessage::MachineOn
+                | NodeMessage::State { .. } => {
+                    let (channel, state) = match message {
+                        NodeMessage::State { channel, state } => (channel, state),
+                        _ => (0, MachineState::from(message == NodeMessage::MachineOn)),
+                    };
+                    if registry.set_state(mac_address, session, channel, state) {
+                        println!("Node {} is now {}", machine(&mac, channel), state_name(state));
+                    }
+                }
+                // Readings come every few seconds, they only show in the list.
+                NodeMessage::Reading { channel, reading } => {
+                    registry.set_reading(mac_address, session, channel, reading);
+                }
+                NodeMessage::Reconnects(reconnects) => {
+                    registry.set_reconnects(mac_address, session, reconnects);
+                    if reconnects > 0 {
+                        println!("Node {mac} rejoined its WiFi {reconnects} times since boot");
+                    }
+                }
+                NodeMessage::KeyRotated { saved } => {
+                    let rotation = registry.take_rotation(mac_address, session);
+                    let Some((generation, grace_secs)) = rotation else {
+                        continue;
+                    };
+                    if !saved {
+                        println!("Node {mac} couldn't save its new key, it stays on the old one");
+                        continue;
+                    }
+                    match keys.rotated(mac_address, generation, grace_secs) {
+                        Ok(()) => println!("Node {mac} rotated to generation {generation}"),
+                        // The node holds the new key now, it can still come back on the old one.
+                        Err(error) => {
+                            println!("Node {mac} rotated but the keys can't be saved: {error}");
+                        }
+                    }
+                }
+                NodeMessage::LockedOut { address, failures, secs } => {
+                    println!("Node {mac} locked out {address} for {secs}s, {failures} failed attempts");
+                }
+                NodeMessage::Error(code) => {
+                    println!("Node {mac} is hanging up: {code:?}");
+                    return Ok(());
+                }
+                NodeMessage::Ack { result: CommandResult::AuthFailed, .. } => {
+                    println!("A frame to node {mac} didn't check out on its end, it did nothing");
+                }
+                // Which request it was is in the frame the node couldn't read.
+                NodeMessage::Ack { id: 0, result } => {
+                    println!("Node {mac} couldn't make out a frame ({result:?}), it did nothing");
+                }
+                NodeMessage::Ack { id, result } => {
+                    let Some((action, channel)) = registry.take_request(mac_address, session, id) else {
+                        continue;
+                    };
+                    let request = Request { id, action, channel };
+                    acked(registry, mac_address, session, &mac, request, result, verified);
+                }
+            }
+        }
+    }
+}
+
+// A request the node just acked.
+struct Request {
+    id: u16,
+    action: Action,
+    channel: u8,
+}
+
+fn acked(
+    registry: &Registry,
+    mac_address: &pibow_protocol::MacAddress,
+    session: u64,
+    mac: &str,
+    Request { id, action, channel }: Request,
+    result: CommandResult,
+    verified: bool
+) {
+    let mac = machine(mac, channel);
+    match result {
+        // The node saw the machine follow, no need to wait for its state report.
+        CommandResult::Executed if verified && action != Action::Reset => {
+            let state = MachineState::from(matches!(action, Action::PowerOn | Action::Wake));
+            registry.set_state(mac_address, session, channel, state);
+            let state = state_name(state);
+            println!("Node {mac} did {action:?}, the machine is {state} (request {id})");
+        }
+        CommandResult::Executed => println!("Node {mac} did {action:?} (request {id})"),
+        // Either ON or OFF, whichever it is the state report tells.
+        CommandResult::AlreadyInState if action == Action::Wake => {
+            println!("Node {mac} isn't asleep, nothing pressed (request {id})");
+        }
+        CommandResult::AlreadyInState => {
+            // Only power ON, OFF and force off can find the machine there already.
+            let state = MachineState::from(action == Action::PowerOn);
+            registry.set_state(mac_address, session, channel, state);
+            let state = state_name(state);
+            println!("Node {mac} is already {state}, nothing pressed (request {id})");
+        }
+        CommandResult::Busy => {
+            println!("Node {mac} is busy, {action:?} wasn't done, try again (request {id})");
+        }
+        CommandResult::Failed => {
+            println!("Node {mac} pressed for {action:?}, but the machine didn't follow (request {id})");
+        }
+        CommandResult::InvalidTiming => {
+            println!("Node {mac} turned down the timings for {action:?} (request {id})");
+        }
+        CommandResult::UnknownAction => println!("Node {mac} doesn't know {action:?} (request {id})"),
+        CommandResult::UnknownChannel => println!("Node {mac} isn't wired to anything (request {id})"),
+        CommandResult::Asleep => println!("Node {mac} is asleep, `wake` or `force-off` it (request {id})"),
+        CommandResult::AuthFailed | CommandResult::Unknown(_) => {
+            println!("Node {mac} answered {action:?} with {result:?} (request {id})");
+        }
+    }
+}
+
+fn send(stream: &mut TcpStream, sealer: &mut Sealer, message: ServerMessage) -> std::io::Result<()> {
+    let mut payload = [0_u8; ServerMessage::MAX_LENGTH];
+    let mut frame = [0_u8; MAX_FRAME_LENGTH];
+    // Both buffers fit any server message, and it's only ever the error that needs nothing agreed,
+    // this can't fail.
+    let length = message.encode(Capabilities::NONE, &mut payload).unwrap_or(0);
+    let length = sealer.seal(&payload[..length], &mut frame).unwrap_or(0);
+    stream.write_all(&frame[..length])
+}
+
+fn invalid(error: DecodeError) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{error:?}"))
+}
@@ -0,0 +1,77 @@
+use std::{ fs, path::PathBuf };
+
+use pibow_server::keys::{ now, Entry, KeyBook };
+
+const MASTER_KEY: [u8; 32] = [7_u8; 32];
+const NODE: [u8; 6] = [2, 0, 0, 0, 0, 1];
+const OTHER_NODE: [u8; 6] = [2, 0, 0, 0, 0, 2];
+
+// A key book of its own for every test, they run side by side.
+fn key_book_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("pibow-keys-{}-{name}.txt", std::process::id()));
+    let _ = fs::remove_file(&path);
+    path
+}
+
+#[test]
+fn a_missing_key_book_puts_every_node_on_generation_zero() {
+    let path = key_book_path("missing");
+    let keys = KeyBook::load(MASTER_KEY, path).unwrap();
+    assert_eq!(keys.keys(&NODE), vec![pibow_protocol::node_key(&MASTER_KEY, &NODE, 0)]);
+    assert!(keys.list().is_empty());
+}
+
+#[test]
+fn key_book_round_trips() {
+    let path = key_book_path("round-trip");
+    let keys = KeyBook::load(MASTER_KEY, path.clone()).unwrap();
+    keys.rotated(&NODE, 1, 600).unwrap();
+    keys.revoke(&OTHER_NODE).unwrap();
+    // Back from revoked, on the next generation.
+    let (key, entry) = keys.issue(&OTHER_NODE).unwrap();
+    assert_eq!(key, pibow_protocol::node_key(&MASTER_KEY, &OTHER_NODE, 1));
+    assert_eq!(entry, Entry { generation: 1, revoked: false, grace_until: None });
+
+    let loaded = KeyBook::load(MASTER_KEY, path.clone()).unwrap();
+    assert_eq!(loaded.list(), keys.list());
+    // Still in the grace window, the key before is good too.
+    let expected = vec![
+        pibow_protocol::node_key(&MASTER_KEY, &NODE, 1),
+        pibow_protocol::node_key(&MASTER_KEY, &NODE, 0)
+    ];
+    assert_eq!(loaded.keys(&NODE), expected);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn revoked_nodes_and_expired_grace_windows_lose_their_keys() {
+    let path = key_book_path("revoked");
+    let text = format!("02:00:00:00:00:01 3 grace {}\n02:00:00:00:00:02 1 revoked\n", now() - 1);
+    fs::write(&path, text).unwrap();
+
+    let keys = KeyBook::load(MASTER_KEY, path.clone()).unwrap();
+    assert_eq!(keys.keys(&NODE), vec![pibow_protocol::node_key(&MASTER_KEY, &NODE, 3)]);
+    assert!(keys.keys(&OTHER_NODE).is_empty());
+    assert_eq!(keys.next_key(&OTHER_NODE), None);
+    fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn bad_lines_name_the_line() {
+    let path = key_book_path("bad-lines");
+    let bad_lines = [
+        "02:00:00:00:00",
+        "02:00:00:00:00:01",
+        "02:00:00:00:00:01 first",
+        "02:00:00:00:00:01 1 grace",
+        "02:00:00:00:00:01 1 grace soon",
+        "02:00:00:00:00:01 1 lost",
+    ];
+    for line in bad_lines {
+        // Comments and blank lines count too.
+        fs::write(&path, format!("# Pibow node keys\n\n{line}\n")).unwrap();
+        let error = KeyBook::load(MASTER_KEY, path.clone()).err().unwrap();
+        assert!(error.contains(".txt:3 isn't"), "{line}: {error}");
+    }
+    fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,141 @@
+use std::{
+    io::{ Read, Write },
+    net::{ TcpListener, TcpStream },
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use pibow_protocol::{
+    Action,
+    Capabilities,
+    Cipher,
+    FrameDecoder,
+    Greeting,
+    Handshake,
+    Hello,
+    Introduction,
+    MachineState,
+    NodeMessage,
+    Opener,
+    Role,
+    Sealer,
+    ServerMessage,
+    TimingOverrides,
+    GREETING_LENGTH,
+    MAX_FRAME_LENGTH,
+};
+use pibow_server::{ keys::KeyBook, registry::Registry, session };
+
+const MASTER_KEY: [u8; 32] = [7_u8; 32];
+const NODE: [u8; 6] = [2, 0, 0, 0, 0, 1];
+
+// A server on a port of its own, with an empty key book: every node is on generation 0.
+fn server(name: &str) -> (u16, Arc<Registry>) {
+    let path = std::env::temp_dir().join(format!("pibow-keys-{}-{name}.txt", std::process::id()));
+    let keys = Arc::new(KeyBook::load(MASTER_KEY, path).unwrap());
+    let registry = Arc::new(Registry::new());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let ciphers = [Cipher::Plain, Cipher::ChaCha20Poly1305];
+    let serving = registry.clone();
+    thread::spawn(move || session::serve(listener, &ciphers, keys, serving));
+    (port, registry)
+}
+
+// Go through the handshake the way a node does, None when the server hangs up instead.
+fn connect(port: u16, key: [u8; 32]) -> Option<(TcpStream, Handshake, Greeting)> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut server_nonce = [0_u8; 32];
+    stream.read_exact(&mut server_nonce).unwrap();
+
+    let handshake = Handshake {
+        key,
+        mac_address: NODE,
+        server_nonce,
+        node_nonce: [9_u8; 32],
+        cipher: Cipher::ChaCha20Poly1305,
+        node_hello: Hello::new(
+            Capabilities::COMMAND_ACKS | Capabilities::CHANNELS | Capabilities::MACHINE_STATES
+        ),
+    };
+    let introduction = Introduction {
+        mac_address: NODE,
+        nonce: handshake.node_nonce,
+        hello: handshake.node_hello,
+        proof: *handshake.node_proof().as_bytes(),
+    };
+    stream.write_all(&introduction.to_bytes()).unwrap();
+
+    let mut greeting = [0_u8; GREETING_LENGTH];
+    stream.read_exact(&mut greeting).ok()?;
+    Some((stream, handshake, Greeting::from_bytes(&greeting)))
+}
+
+fn send(stream: &mut TcpStream, sealer: &mut Sealer, message: NodeMessage) {
+    let mut payload = [0_u8; NodeMessage::MAX_LENGTH];
+    let mut frame = [0_u8; MAX_FRAME_LENGTH];
+    let length = message.encode(&mut payload).unwrap();
+    let length = sealer.seal(&payload[..length], &mut frame).unwrap();
+    stream.write_all(&frame[..length]).unwrap();
+}
+
+fn receive(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    opener: &mut Opener,
+    agreed: Capabilities
+) -> ServerMessage {
+    let mut byte = [0_u8; 1];
+    loop {
+        stream.read_exact(&mut byte).unwrap();
+        if let (_, Ok(Some(frame))) = decoder.decode(&byte) {
+            return ServerMessage::decode(opener.open(frame).unwrap(), agreed).unwrap();
+        }
+    }
+}
+
+#[test]
+fn a_node_reports_its_machines_and_takes_requests() {
+    let (port, registry) = server("session");
+    let key = pibow_protocol::node_key(&MASTER_KEY, &NODE, 0);
+    let (mut stream, handshake, greeting) = connect(port, key).unwrap();
+    assert!(pibow_protocol::proof_matches(&handshake.server_proof(&greeting.hello), &greeting.proof));
+    let agreed = handshake.node_hello.agree(&greeting.hello).unwrap().capabilities;
+    assert!(agreed.contains(Capabilities::CHANNELS | Capabilities::MACHINE_STATES));
+    let (mut sealer, mut opener) = handshake.session(Role::Node);
+
+    send(&mut stream, &mut sealer, NodeMessage::State { channel: 1, state: MachineState::Sleep });
+    // The session reads on a thread of its own.
+    let mut states = Vec::new();
+    for _ in 0..500 {
+        states = registry.list().into_iter().flat_map(|node| node.states).collect();
+        if !states.is_empty() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(states, vec![(1, MachineState::Sleep)]);
+
+    let id = registry.request(&NODE, Action::Wake, 1, TimingOverrides::NONE).unwrap();
+    assert_eq!(id, Some(1));
+    let mut decoder = FrameDecoder::new();
+    let request = receive(&mut stream, &mut decoder, &mut opener, agreed);
+    let expected = ServerMessage::Request {
+        id: 1,
+        action: Action::Wake,
+        channel: 1,
+        overrides: TimingOverrides::NONE,
+    };
+    assert_eq!(request, expected);
+}
+
+#[test]
+fn a_node_with_the_wrong_key_gets_no_greeting() {
+    let (port, registry) = server("wrong-key");
+    let key = pibow_protocol::node_key(&MASTER_KEY, &NODE, 1);
+    assert!(connect(port, key).is_none());
+    assert!(registry.list().is_empty());
+}
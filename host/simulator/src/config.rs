@@ -0,0 +1,144 @@
+use std::net::Ipv4Addr;
+
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use pibow_config::MAX_CHANNELS;
+use pibow_power::Timings;
+use pibow_protocol::{ Cipher, PROTOCOL_VERSION };
+
+// Same defaults as the firmware's build settings (build.rs).
+const DEFAULT_MULTICAST_IP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 127);
+const DEFAULT_MULTICAST_PORT: u16 = 4265;
+const DEFAULT_NODE_PORT: u16 = 5325;
+const DEFAULT_SERVER_PORT: u16 = 7325;
+const DEFAULT_FAULT_TOLERANCE: usize = 5;
+
+pub const USAGE: &str =
+    "Usage: pibow-simulator --master-key <base64 key> [--count <nodes>] [--address <ip>] [--multicast-ip <ip>] [--multicast-port <port>] [--node-port <port>] [--server-port <port>] [--cipher <plain|chacha20poly1305>] [--protocol-version <version>] [--force-off-hold <ms>] [--retry-press <true|false>] [--ignored-presses <count>] [--channels <machines per node>] [--asleep <true|false>] [--analog <true|false>]
+
+Every node needs its own address since they all open the same node port. The first node takes --address
+(127.0.0.2 by default), the next ones count up from there. Each node holds its first key derived from the
+server's master key, which can also be given with the PIBOW_MASTER_KEY environment variable. Sessions are plain unless --cipher says otherwise, like the firmware's default.
+--protocol-version makes the nodes claim another version in the handshake, to try the server's version
+checks. The simulated machines cut out after the power switch is held for 4 seconds, a --force-off-hold
+shorter than that makes force offs fail. --ignored-presses makes every machine shrug off that many power
+presses first, like one that fails to boot, --retry-press true has the nodes press once more when the
+machine doesn't follow. --channels wires every node to that many machines (4 at most), the ones past
+the first only for servers that know about channels. --asleep true starts every machine suspended, its
+power LED on, for a power press to wake it up. --analog true senses every machine through the ADC with a
+shunt in its supply, so the nodes report what they measure to servers that take readings.";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub master_key: [u8; 32],
+    pub count: u16,
+    pub address: Ipv4Addr,
+    pub multicast_ip: Ipv4Addr,
+    pub multicast_port: u16,
+    pub node_port: u16,
+    pub server_port: u16,
+    pub fault_tolerance: usize,
+    pub cipher: Cipher,
+    pub protocol_version: u8,
+    pub timings: Timings,
+    pub ignored_presses: usize,
+    pub channels: usize,
+    pub asleep: bool,
+    pub analog: bool,
+}
+
+impl Config {
+    pub fn from_args(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut key = std::env::var("PIBOW_MASTER_KEY").ok();
+        let mut config = Config {
+            master_key: [0_u8; 32],
+            count: 1,
+            address: Ipv4Addr::new(127, 0, 0, 2),
+            multicast_ip: DEFAULT_MULTICAST_IP,
+            multicast_port: DEFAULT_MULTICAST_PORT,
+            node_port: DEFAULT_NODE_PORT,
+            server_port: DEFAULT_SERVER_PORT,
+            fault_tolerance: DEFAULT_FAULT_TOLERANCE,
+            cipher: Cipher::Plain,
+            protocol_version: PROTOCOL_VERSION,
+            timings: Timings::default(),
+            ignored_presses: 0,
+            channels: 1,
+            asleep: false,
+            analog: false,
+        };
+
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| format!("Missing value for {flag}"))?;
+            match flag.as_str() {
+                "--master-key" => {
+                    key = Some(value);
+                }
+                "--count" => {
+                    config.count = parse(&flag, &value)?;
+                }
+                "--address" => {
+                    config.address = parse(&flag, &value)?;
+                }
+                "--multicast-ip" => {
+                    config.multicast_ip = parse(&flag, &value)?;
+                }
+                "--multicast-port" => {
+                    config.multicast_port = parse(&flag, &value)?;
+                }
+                "--node-port" => {
+                    config.node_port = parse(&flag, &value)?;
+                }
+                "--server-port" => {
+                    config.server_port = parse(&flag, &value)?;
+                }
+                "--cipher" => {
+                    config.cipher = parse(&flag, &value)?;
+                }
+                "--protocol-version" => {
+                    config.protocol_version = parse(&flag, &value)?;
+                }
+                "--force-off-hold" => {
+                    config.timings.force_off_hold_ms = parse(&flag, &value)?;
+                }
+                "--retry-press" => {
+                    config.timings.retry_press = parse(&flag, &value)?;
+                }
+                "--ignored-presses" => {
+                    config.ignored_presses = parse(&flag, &value)?;
+                }
+                "--asleep" => {
+                    config.asleep = parse(&flag, &value)?;
+                }
+                "--analog" => {
+                    config.analog = parse(&flag, &value)?;
+                }
+                "--channels" => {
+                    config.channels = parse(&flag, &value)?;
+                    if !(1..=MAX_CHANNELS).contains(&config.channels) {
+                        return Err(format!("A node drives 1 to {MAX_CHANNELS} machines"));
+                    }
+                }
+                _ => {
+                    return Err(format!("Unknown option {flag}"));
+                }
+            }
+        }
+
+        let key = key.ok_or("Missing the master key")?;
+        config.master_key = STANDARD.decode(key.trim())
+            .map_err(|error| format!("The master key isn't valid base64: {error}"))?
+            .try_into()
+            .map_err(|_| "The master key must be 32 bytes".to_string())?;
+
+        Ok(config)
+    }
+
+    /// The address of the nth simulated node, counting from zero.
+    pub fn node_address(&self, index: u16) -> Ipv4Addr {
+        Ipv4Addr::from_bits(self.address.to_bits() + u32::from(index))
+    }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("Invalid value for {flag}: {value}"))
+}
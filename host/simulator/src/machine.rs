@@ -0,0 +1,211 @@
+use std::{ sync::Mutex, thread, time::{ Duration, Instant } };
+
+use pibow_power::PowerInterface;
+use pibow_protocol::{ MachineState, Reading };
+
+// Rough ATX behaviour, good enough to see the state change on the server.
+const MIN_PRESS: Duration = Duration::from_millis(50);
+const BOOT_TIME: Duration = Duration::from_secs(1);
+const SHUTDOWN_TIME: Duration = Duration::from_secs(2);
+const FORCE_OFF_HOLD: Duration = Duration::from_secs(4);
+
+/// A fake computer wired to the relays: power and reset switches in, power LED and sleep
+/// signal out.
+pub struct Machine {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    powered: bool,
+    // Suspended, the power LED stays on and a power press wakes it up.
+    asleep: bool,
+    // Booting, waking up or shutting down, lands on the state at the given time.
+    transition: Option<(Instant, bool)>,
+    power_pressed_at: Option<Instant>,
+    // How many more power presses to shrug off, like a machine that fails to boot.
+    ignored_presses: usize,
+    // Sensed through the ADC, with a shunt in its supply.
+    analog: bool,
+}
+
+impl Inner {
+    fn update(&mut self, now: Instant) {
+        if let Some((at, powered)) = self.transition
+            && now >= at
+        {
+            self.powered = powered;
+            self.asleep = false;
+            self.transition = None;
+        }
+
+        // Holding the power switch kills the machine, no matter what the OS thinks.
+        if let Some(pressed_at) = self.power_pressed_at
+            && self.powered
+            && now - pressed_at >= FORCE_OFF_HOLD
+        {
+            self.powered = false;
+            self.asleep = false;
+            self.transition = None;
+        }
+    }
+}
+
+impl Machine {
+    /// An asleep machine has power.
+    pub fn new(powered: bool, asleep: bool, ignored_presses: usize, analog: bool) -> Self {
+        Machine {
+            inner: Mutex::new(Inner {
+                powered: powered || asleep,
+                asleep,
+                transition: None,
+                power_pressed_at: None,
+                ignored_presses,
+                analog,
+            }),
+        }
+    }
+
+    /// What the state pin reads.
+    pub fn is_on(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.update(Instant::now());
+        inner.powered
+    }
+
+    /// What the power LED and the sleep signal tell together.
+    pub fn state(&self) -> MachineState {
+        let mut inner = self.inner.lock().unwrap();
+        inner.update(Instant::now());
+        if inner.asleep { MachineState::Sleep } else { MachineState::from(inner.powered) }
+    }
+
+    /// What an analog state pin and a shunt read, None for a machine that isn't sensed that way.
+    pub fn reading(&self) -> Option<Reading> {
+        if !self.inner.lock().unwrap().analog {
+            return None;
+        }
+        // Roughly an LED header and a desktop's 12 V rail.
+        let (millivolts, milliamps) = match self.state() {
+            MachineState::On => (2_100, 3_200),
+            MachineState::Sleep => (2_100, 450),
+            MachineState::Off | MachineState::Unknown => (300, 15),
+        };
+        Some(Reading { millivolts, milliamps: Some(milliamps) })
+    }
+
+    pub fn set_power_switch(&self, pressed: bool) {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.update(now);
+
+        if pressed {
+            inner.power_pressed_at.get_or_insert(now);
+            return;
+        }
+
+        let Some(pressed_at) = inner.power_pressed_at.take() else {
+            return;
+        };
+        let held = now - pressed_at;
+        if held < MIN_PRESS || held >= FORCE_OFF_HOLD || inner.transition.is_some() {
+            return;
+        }
+        if inner.ignored_presses > 0 {
+            inner.ignored_presses -= 1;
+            return;
+        }
+
+        // Waking up takes about as long as booting, minus the firmware.
+        if inner.asleep {
+            inner.transition = Some((now + BOOT_TIME, true));
+        } else if inner.powered {
+            inner.transition = Some((now + SHUTDOWN_TIME, false));
+        } else {
+            inner.transition = Some((now + BOOT_TIME, true));
+        }
+    }
+
+    /// A switch is held, or the machine is booting or shutting down and ignores the power switch.
+    pub fn busy(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.update(Instant::now());
+        inner.power_pressed_at.is_some() || inner.transition.is_some()
+    }
+
+    pub fn set_reset_switch(&self, _pressed: bool) {
+        // The machine reboots but the power LED stays the same, nothing to see from here.
+    }
+}
+
+// The simulator is all threads, so blocking in here is fine.
+impl PowerInterface for &Machine {
+    async fn press_power(&mut self, press_ms: u32) {
+        self.set_power_switch(true);
+        thread::sleep(Duration::from_millis(press_ms as u64));
+        self.set_power_switch(false);
+    }
+
+    async fn press_reset(&mut self, press_ms: u32) {
+        self.set_reset_switch(true);
+        thread::sleep(Duration::from_millis(press_ms as u64));
+        self.set_reset_switch(false);
+    }
+
+    async fn hold_power(&mut self, hold_ms: u32) -> bool {
+        let until = Instant::now() + Duration::from_millis(hold_ms as u64);
+        self.set_power_switch(true);
+        while Machine::is_on(self) && Instant::now() < until {
+            thread::sleep(Duration::from_millis(50));
+        }
+        self.set_power_switch(false);
+        !Machine::is_on(self)
+    }
+
+    fn is_on(&mut self) -> bool {
+        Machine::is_on(self)
+    }
+
+    fn state(&mut self) -> MachineState {
+        Machine::state(self)
+    }
+
+    fn reading(&mut self) -> Option<Reading> {
+        Machine::reading(self)
+    }
+
+    fn busy(&mut self) -> bool {
+        Machine::busy(self)
+    }
+
+    async fn wait_for_change(&mut self) {
+        let state = Machine::state(self);
+        while Machine::state(self) == state {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    async fn wait_for_state(&mut self, powered: bool, timeout_ms: u32) -> bool {
+        let until = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        while Machine::is_on(self) != powered && Instant::now() < until {
+            thread::sleep(Duration::from_millis(50));
+        }
+        Machine::is_on(self) == powered
+    }
+
+    async fn wait_for_machine_state(&mut self, state: MachineState, timeout_ms: u32) -> bool {
+        let until = Instant::now() + Duration::from_millis(timeout_ms as u64);
+        while Machine::state(self) != state && Instant::now() < until {
+            thread::sleep(Duration::from_millis(50));
+        }
+        Machine::state(self) == state
+    }
+
+    fn release(&mut self) {
+        self.set_power_switch(false);
+        self.set_reset_switch(false);
+    }
+
+    async fn delay(&mut self, ms: u32) {
+        thread::sleep(Duration::from_millis(ms as u64));
+    }
+}
@@ -0,0 +1,31 @@
+//! Runs the node phases on Linux against simulated machines, so a server can be tested without
+//! flashing any hardware.
+
+mod config;
+mod machine;
+mod node;
+mod phases;
+
+use std::{ process::ExitCode, thread };
+
+use crate::{ config::Config, node::Node };
+
+fn main() -> ExitCode {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{error}\n\n{}", config::USAGE);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    thread::scope(|scope| {
+        for index in 0..config.count {
+            let node = Node::new(&config, index);
+            node.log(&format!("Simulating a node on {}", node.address));
+            scope.spawn(move || node.run());
+        }
+    });
+
+    ExitCode::SUCCESS
+}
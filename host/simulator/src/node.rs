@@ -0,0 +1,142 @@
+use std::{
+    net::{ IpAddr, Ipv4Addr },
+    sync::{ atomic::{ AtomicBool, Ordering }, Mutex },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use pibow_protocol::{ AttemptLimiter, Lockout, MacAddress };
+
+use crate::{
+    config::Config,
+    machine::Machine,
+    phases::{ listen_answer, poke_server, server_contact },
+};
+
+/// One simulated Pico W with the machines it's wired to.
+pub struct Node {
+    pub config: Config,
+    pub address: Ipv4Addr,
+    pub mac_address: MacAddress,
+    // Like a node that was handed its key, it never sees the master key.
+    key_ring: Mutex<KeyRing>,
+    attempts: Mutex<Attempts>,
+    started: Instant,
+    /// By channel.
+    pub machines: Vec<Machine>,
+}
+
+// Same as the firmware's: the previous key is only good until its grace window runs out.
+struct KeyRing {
+    current: [u8; 32],
+    previous: Option<([u8; 32], Instant)>,
+}
+
+// Same limits as the firmware's, with the lockouts the server hasn't heard about yet.
+#[derive(Default)]
+struct Attempts {
+    limiter: AttemptLimiter,
+    unreported: Vec<Lockout>,
+}
+
+impl Node {
+    pub fn new(config: &Config, index: u16) -> Self {
+        let [high, low] = (index + 1).to_be_bytes();
+        // Locally administered, so it can't clash with real hardware.
+        let mac_address = [0x02, 0x00, 0x00, 0x00, high, low];
+        Node {
+            config: config.clone(),
+            address: config.node_address(index),
+            mac_address,
+            key_ring: Mutex::new(KeyRing {
+                current: pibow_protocol::node_key(&config.master_key, &mac_address, 0),
+                previous: None,
+            }),
+            attempts: Mutex::new(Attempts::default()),
+            started: Instant::now(),
+            machines: (0..config.channels)
+                .map(|_| Machine::new(false, config.asleep, config.ignored_presses, config.analog))
+                .collect(),
+        }
+    }
+
+    pub fn log(&self, message: &str) {
+        let [a, b, c, d, e, f] = self.mac_address;
+        println!("[{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}] {message}");
+    }
+
+    /// The current key, and the previous one while it's still good.
+    pub fn keys(&self) -> ([u8; 32], Option<[u8; 32]>) {
+        let mut ring = self.key_ring.lock().unwrap();
+        if ring.previous.is_some_and(|(_, until)| Instant::now() >= until) {
+            ring.previous = None;
+        }
+        (ring.current, ring.previous.map(|(key, _)| key))
+    }
+
+    /// Take the key the server rotated to, there's no flash to fail on here.
+    pub fn rotate(&self, old_key: [u8; 32], new_key: [u8; 32], grace_secs: u32) {
+        let until = Instant::now() + Duration::from_secs(grace_secs as u64);
+        *self.key_ring.lock().unwrap() = KeyRing {
+            current: new_key,
+            previous: (grace_secs > 0).then_some((old_key, until)),
+        };
+    }
+
+    fn now_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    pub fn locked_out(&self, address: IpAddr) -> bool {
+        self.attempts.lock().unwrap().limiter.locked_out(address, self.now_ms()).is_some()
+    }
+
+    /// Count a failed attempt, `what` says what it got wrong for the log.
+    pub fn failed(&self, address: IpAddr, what: &str) {
+        let mut attempts = self.attempts.lock().unwrap();
+        let Some(lockout) = attempts.limiter.failed(address, self.now_ms()) else {
+            return;
+        };
+        attempts.unreported.push(lockout);
+        self.log(&format!(
+            "Locked out {address} for {}s after {} bad {what}",
+            lockout.duration_ms / 1000,
+            lockout.failures
+        ));
+    }
+
+    pub fn succeeded(&self, address: IpAddr) {
+        self.attempts.lock().unwrap().limiter.succeeded(address);
+    }
+
+    pub fn take_unreported(&self) -> Vec<Lockout> {
+        std::mem::take(&mut self.attempts.lock().unwrap().unreported)
+    }
+
+    /// Same loop as the firmware's main, minus the board and WiFi setup.
+    pub fn run(&self) {
+        loop {
+            // Create a hash challenge and cast it to the UDP channel.
+            let challenge: pibow_protocol::Challenge = rand::random();
+            let keys = self.keys();
+
+            // Poke the server until it answers the challenge.
+            let stop_poking = AtomicBool::new(false);
+            let found = thread::scope(|scope| {
+                scope.spawn(|| poke_server::invoke(self, &challenge, &stop_poking));
+                let found = listen_answer::invoke(self, &challenge, keys);
+                stop_poking.store(true, Ordering::Relaxed);
+                found
+            });
+
+            let Some((server_address, key)) = found else {
+                // Most likely the address isn't usable, don't spin on it.
+                thread::sleep(Duration::from_secs(2));
+                continue;
+            };
+
+            self.log("Found the server");
+            server_contact::invoke(self, server_address, key);
+        }
+    }
+}
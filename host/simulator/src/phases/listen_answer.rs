@@ -0,0 +1,48 @@
+use std::{ io::Read, net::{ IpAddr, TcpListener }, time::Duration };
+
+use pibow_protocol::{ Challenge, ANSWER_LENGTH };
+
+use crate::node::Node;
+
+// Returns the server's address and which of the keys it answered with.
+pub fn invoke(
+    node: &Node,
+    challenge: &Challenge,
+    keys: ([u8; 32], Option<[u8; 32]>)
+) -> Option<(IpAddr, [u8; 32])> {
+    let listener = match TcpListener::bind((node.address, node.config.node_port)) {
+        Ok(listener) => listener,
+        Err(error) => {
+            node.log(&format!("Can't listen for answers: {error}"));
+            return None;
+        }
+    };
+
+    loop {
+        let Ok((mut socket, remote_endpoint)) = listener.accept() else {
+            continue;
+        };
+        // Whoever kept guessing wrong doesn't even get read.
+        if node.locked_out(remote_endpoint.ip()) {
+            continue;
+        }
+        let _ = socket.set_read_timeout(Some(Duration::from_secs(2)));
+
+        let mut challenge_answer = [0_u8; ANSWER_LENGTH];
+        if socket.read_exact(&mut challenge_answer).is_err() {
+            node.failed(remote_endpoint.ip(), "answers");
+            continue;
+        }
+
+        let (key, previous_key) = keys;
+        let answered = pibow_protocol::answered_key(challenge, &challenge_answer, key, previous_key);
+        let Some(answered_key) = answered else {
+            node.failed(remote_endpoint.ip(), "answers");
+            continue;
+        };
+        node.succeeded(remote_endpoint.ip());
+
+        // Disconnect the server. We'll connect to it.
+        return Some((remote_endpoint.ip(), answered_key));
+    }
+}
@@ -0,0 +1,3 @@
+pub mod poke_server;
+pub mod listen_answer;
+pub mod server_contact;
@@ -0,0 +1,32 @@
+use std::{
+    net::UdpSocket,
+    sync::atomic::{ AtomicBool, Ordering },
+    thread,
+    time::{ Duration, Instant },
+};
+
+use pibow_protocol::{ Challenge, Poke };
+
+use crate::node::Node;
+
+pub fn invoke(node: &Node, challenge: &Challenge, stop: &AtomicBool) {
+    let announcer = match UdpSocket::bind((node.address, node.config.node_port)) {
+        Ok(announcer) => announcer,
+        Err(error) => {
+            node.log(&format!("Can't open the UDP socket: {error}"));
+            return;
+        }
+    };
+
+    let multicast_addr = (node.config.multicast_ip, node.config.multicast_port);
+    let poke = Poke { challenge: *challenge, mac_address: node.mac_address }.to_bytes();
+
+    while !stop.load(Ordering::Relaxed) {
+        let _ = announcer.send_to(&poke, multicast_addr);
+
+        let sent_at = Instant::now();
+        while !stop.load(Ordering::Relaxed) && sent_at.elapsed() < Duration::from_secs(2) {
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    io::{ ErrorKind, Read, Write },
+    net::{ IpAddr, SocketAddr, TcpStream },
+    time::Duration,
+};
+
+use embassy_futures::block_on;
+use pibow_power::Outcome;
+use pibow_protocol::{
+    Capabilities,
+    CommandResult,
+    FrameDecoder,
+    Greeting,
+    Handshake,
+    Hello,
+    Introduction,
+    NodeMessage,
+    Role,
+    Sealer,
+    ServerMessage,
+    TimingOverrides,
+    GREETING_LENGTH,
+    MAX_FRAME_LENGTH,
+    NONCE_LENGTH,
+};
+
+use crate::node::Node;
+
+// Same as the firmware's.
+const CAPABILITIES: Capabilities = Capabilities::ENCRYPTED_SESSIONS.union(Capabilities::KEY_ROTATION)
+    .union(Capabilities::LOCKOUT_REPORTS)
+    .union(Capabilities::COMMAND_ACKS)
+    .union(Capabilities::FORCE_OFF)
+    .union(Capabilities::TIMING_OVERRIDES)
+    .union(Capabilities::VERIFIED_ACTIONS)
+    .union(Capabilities::CHANNELS)
+    .union(Capabilities::MACHINE_STATES)
+    .union(Capabilities::ANALOG_READINGS);
+
+// How long to leave the server be after one end hung up on the other, same as the firmware's.
+const HUNG_UP_RETRY: Duration = Duration::from_secs(60);
+
+// How often the state pin gets checked while waiting for the server.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn send(socket: &mut TcpStream, sealer: &mut Sealer, message: NodeMessage) -> std::io::Result<()> {
+    let mut payload = [0_u8; NodeMessage::MAX_LENGTH];
+    let mut frame = [0_u8; MAX_FRAME_LENGTH];
+    let length = message.encode(&mut payload).unwrap_or(0);
+    let length = sealer.seal(&payload[..length], &mut frame).unwrap_or(0);
+    socket.write_all(&frame[..length])
+}
+
+pub fn invoke(node: &Node, server_address: IpAddr, key: [u8; 32]) {
+    let endpoint = SocketAddr::new(server_address, node.config.server_port);
+    let mut socket = match TcpStream::connect_timeout(&endpoint, Duration::from_secs(5)) {
+        Ok(socket) => socket,
+        Err(_) => {
+            node.log("Can't connect to server endpoint");
+            return;
+        }
+    };
+
+    // Read the server's nonce, prove the key over it and this node's own nonce.
+    let mut server_nonce = [0_u8; NONCE_LENGTH];
+    let _ = socket.set_read_timeout(Some(Duration::from_secs(10)));
+    if socket.read_exact(&mut server_nonce).is_err() {
+        node.log("Can't obtain the nonce from server.");
+        return;
+    }
+    let handshake = Handshake {
+        key,
+        mac_address: node.mac_address,
+        server_nonce,
+        node_nonce: rand::random(),
+        cipher: node.config.cipher,
+        node_hello: Hello { version: node.config.protocol_version, capabilities: CAPABILITIES },
+    };
+    let introduction = Introduction {
+        mac_address: node.mac_address,
+        nonce: handshake.node_nonce,
+        hello: handshake.node_hello,
+        proof: *handshake.node_proof().as_bytes(),
+    };
+    if socket.write_all(&introduction.to_bytes()).is_err() {
+        node.log("Can't introduce to the server, folding...");
+        return;
+    }
+
+    // The server has to prove the key back before anything it says counts.
+    let mut greeting = [0_u8; GREETING_LENGTH];
+    if socket.read_exact(&mut greeting).is_err() {
+        node.log("Can't obtain the proof from server, it may speak an older protocol.");
+        return;
+    }
+    let greeting = Greeting::from_bytes(&greeting);
+    if !pibow_protocol::proof_matches(&handshake.server_proof(&greeting.hello), &greeting.proof) {
+        node.log("Server failed the handshake, folding...");
+        node.failed(server_address, "handshakes");
+        return;
+    }
+    let (mut sealer, mut opener) = handshake.session(Role::Node);
+
+    // Hang up on a server too old for this node, and tell it why.
+    let agreed = match handshake.node_hello.agree(&greeting.hello) {
+        Ok(agreed) => agreed,
+        Err(code) => {
+            node.log(
+                &format!(
+                    "Can't speak protocol {} with a server on {}, folding...",
+                    handshake.node_hello.version,
+                    greeting.hello.version
+                )
+            );
+            let _ = send(&mut socket, &mut sealer, NodeMessage::Error(code));
+            std::thread::sleep(HUNG_UP_RETRY);
+            return;
+        }
+    };
+    node.log(&format!("Speaking protocol {} ({})", agreed.version, agreed.capabilities));
+
+    // Simulated nodes never lose their WiFi.
+    if send(&mut socket, &mut sealer, NodeMessage::Reconnects(0)).is_err() {
+        node.log("Can't report the reconnects to the server, folding...");
+        return;
+    }
+
+    // Only now that the server proved the key, tell it who got locked out since last time.
+    let lockouts = if agreed.capabilities.contains(Capabilities::LOCKOUT_REPORTS) {
+        node.take_unreported()
+    } else {
+        Vec::new()
+    };
+    for lockout in lockouts {
+        let locked_out = NodeMessage::LockedOut {
+            address: lockout.address,
+            failures: lockout.failures,
+            secs: (lockout.duration_ms / 1000) as u32,
+        };
+        if send(&mut socket, &mut sealer, locked_out).is_err() {
+            node.log("Can't report the lockouts to the server, folding...");
+            return;
+        }
+    }
+
+    // From here on reads time out quickly, so the state pin can be watched in between.
+    let _ = socket.set_read_timeout(Some(POLL_INTERVAL));
+
+    let mut faults: usize = 0;
+    // A server without channels only hears about the first machine.
+    let channels = agreed.capabilities.contains(Capabilities::CHANNELS);
+    let watched = if channels { node.machines.len() } else { 1 };
+    let mut reported_states: Vec<Option<NodeMessage>> = vec![None; watched];
+    let readings = agreed.capabilities.contains(Capabilities::ANALOG_READINGS);
+    let mut reported_readings: Vec<Option<NodeMessage>> = vec![None; watched];
+    let mut decoder = FrameDecoder::new();
+    let mut buffer = [0_u8; 256];
+    // Whether the server hung up on purpose, no point coming right back then.
+    let mut hung_up = false;
+
+    'session: loop {
+        for (channel, reported_state) in reported_states.iter_mut().enumerate() {
+            // Whatever the server understands of it, asleep is just ON to older ones.
+            let state = node.machines[channel].state();
+            let report = NodeMessage::state_report(channel as u8, state, agreed.capabilities);
+            let Some(report) = report.filter(|report| *reported_state != Some(*report)) else {
+                continue;
+            };
+            *reported_state = Some(report);
+            if send(&mut socket, &mut sealer, report).is_err() {
+                node.log("Can't report the machine's state, breaking...");
+                break 'session;
+            }
+        }
+        // The simulated readings only move along with the state.
+        for (channel, reported_reading) in reported_readings.iter_mut().enumerate() {
+            let Some(reading) = node.machines[channel].reading().filter(|_| readings) else {
+                continue;
+            };
+            let reading = NodeMessage::Reading { channel: channel as u8, reading };
+            if *reported_reading == Some(reading) {
+                continue;
+            }
+            *reported_reading = Some(reading);
+            if send(&mut socket, &mut sealer, reading).is_err() {
+                node.log("Can't report the machine's reading, breaking...");
+                break 'session;
+            }
+        }
+
+        let length = match socket.read(&mut buffer) {
+            Ok(0) => {
+                node.log("Server closed the session");
+                break;
+            }
+            Ok(length) => length,
+            Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                continue;
+            }
+            Err(_) => {
+                node.log("Can't obtain a frame from server, breaking...");
+                break;
+            }
+        };
+
+        let mut bytes = &buffer[..length];
+        while !bytes.is_empty() {
+            let (consumed, frame) = decoder.decode(bytes);
+            bytes = &bytes[consumed..];
+            let frame = match frame {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    continue;
+                }
+                Err(_) => {
+                    node.log("The server's stream is out of sync, breaking...");
+                    break 'session;
+                }
+            };
+
+            let Ok(payload) = opener.open(frame) else {
+                node.log("A frame from the server doesn't check out, folding...");
+                node.failed(server_address, "frames");
+                faults += 1;
+                // Whatever it asked for didn't happen, say so.
+                if agreed.capabilities.contains(Capabilities::COMMAND_ACKS) {
+                    let ack = NodeMessage::Ack { id: 0, result: CommandResult::AuthFailed };
+                    if send(&mut socket, &mut sealer, ack).is_err() {
+                        break 'session;
+                    }
+                }
+                if faults > node.config.fault_tolerance {
+                    break 'session;
+                }
+                continue;
+            };
+
+            let message = ServerMessage::decode(payload, agreed.capabilities);
+            let (id, action, channel, overrides) = match message {
+                Ok(ServerMessage::Command { action }) => (None, action, 0, TimingOverrides::NONE),
+                Ok(ServerMessage::Request { id, action, channel, overrides }) => {
+                    (Some(id), action, channel, overrides)
+                }
+                Ok(ServerMessage::Error(code)) => {
+                    node.log(&format!("The server is hanging up: {code:?}"));
+                    hung_up = true;
+                    break 'session;
+                }
+                Ok(ServerMessage::RotateKey { wrapped, grace_secs }) => {
+                    node.rotate(key, handshake.unwrap_key(&wrapped), grace_secs);
+                    node.log(&format!("Rotated the key, the old one stays good {grace_secs}s"));
+                    if send(&mut socket, &mut sealer, NodeMessage::KeyRotated { saved: true }).is_err() {
+                        break 'session;
+                    }
+                    continue;
+                }
+                Err(_) => {
+                    node.log("The server sent something unknown, skipping it");
+                    // It checked out, so it's likely a command this node can't make out.
+                    if agreed.capabilities.contains(Capabilities::COMMAND_ACKS) {
+                        let ack = NodeMessage::Ack { id: 0, result: CommandResult::UnknownAction };
+                        if send(&mut socket, &mut sealer, ack).is_err() {
+                            break 'session;
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            node.log(&format!("Server requested {action:?} on channel {channel}"));
+            let turned_down = |result| NodeMessage::Ack { id: id.unwrap_or(0), result };
+            let Some(mut machine) = node.machines.get(channel as usize) else {
+                if send(&mut socket, &mut sealer, turned_down(CommandResult::UnknownChannel)).is_err() {
+                    break 'session;
+                }
+                continue;
+            };
+            let Ok(timings) = node.config.timings.with_overrides(action, &overrides) else {
+                if send(&mut socket, &mut sealer, turned_down(CommandResult::InvalidTiming)).is_err() {
+                    break 'session;
+                }
+                continue;
+            };
+            let outcome = block_on(pibow_power::execute(action, &mut machine, &timings));
+            if outcome == Outcome::Failed {
+                node.log("The machine didn't follow the switch");
+            }
+            let reply = match (id, outcome) {
+                (Some(id), outcome) => NodeMessage::Ack { id, result: CommandResult::from(outcome) },
+                // Nothing pressed, send back the latest state to sync a server that takes no acks.
+                (None, Outcome::AlreadyInState { powered }) => NodeMessage::machine_state(powered),
+                (None, _) => {
+                    continue;
+                }
+            };
+            if send(&mut socket, &mut sealer, reply).is_err() {
+                break 'session;
+            }
+        }
+    }
+
+    node.log("Session closed");
+    if hung_up {
+        std::thread::sleep(HUNG_UP_RETRY);
+    }
+}
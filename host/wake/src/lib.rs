@@ -0,0 +1,96 @@
+//! Wake-on-LAN magic packets, so whatever already sends them (NAS and homelab dashboards, `wakeonlan`
+//! scripts) can power a machine ON through its node, pibow server or not.
+//!
+//! A magic packet is `[0xff × 6, <MAC address × 16>]`, and a 6 byte SecureOn password after that
+//! for machines that want one. It usually comes over UDP to port 7 or 9, but senders wrap it in
+//! whatever they like, so it gets looked for anywhere in the payload. Nothing in here touches the
+//! network.
+
+#![no_std]
+
+/// Where magic packets go, echo and discard.
+pub const WAKE_PORTS: [u16; 2] = [7, 9];
+
+pub const MAC_LENGTH: usize = 6;
+pub const PASSWORD_LENGTH: usize = 6;
+
+const SYNC: [u8; 6] = [0xff; 6];
+const REPETITIONS: usize = 16;
+
+/// A bare magic packet, without a password.
+pub const MAGIC_PACKET_LENGTH: usize = SYNC.len() + REPETITIONS * MAC_LENGTH;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MagicPacket {
+    pub mac_address: [u8; MAC_LENGTH],
+    /// The 6 bytes right after the last repetition, None when the payload ends before that.
+    pub password: Option<[u8; PASSWORD_LENGTH]>,
+}
+
+impl MagicPacket {
+    /// The first magic packet in `payload`, None when there's none.
+    pub fn find(payload: &[u8]) -> Option<Self> {
+        (0..payload.len()).find_map(|start| MagicPacket::starting(&payload[start..]))
+    }
+
+    fn starting(bytes: &[u8]) -> Option<Self> {
+        let (sync, rest) = bytes.split_at_checked(SYNC.len())?;
+        if sync != SYNC {
+            return None;
+        }
+        let (repeated, rest) = rest.split_at_checked(REPETITIONS * MAC_LENGTH)?;
+        let (mac_address, _) = repeated.split_first_chunk::<MAC_LENGTH>()?;
+        if !repeated.chunks_exact(MAC_LENGTH).all(|repetition| repetition == mac_address) {
+            return None;
+        }
+        let password = rest.first_chunk::<PASSWORD_LENGTH>().copied();
+        Some(MagicPacket { mac_address: *mac_address, password })
+    }
+
+    /// Whether it wakes the machine at `mac_address`, which might want a `password`. Compared in
+    /// constant time, the password is all that keeps anyone on the network from powering it ON.
+    pub fn wakes(
+        &self,
+        mac_address: &[u8; MAC_LENGTH],
+        password: Option<&[u8; PASSWORD_LENGTH]>
+    ) -> bool {
+        if self.mac_address != *mac_address {
+            return false;
+        }
+        let Some(password) = password else {
+            return true;
+        };
+        let Some(given) = self.password else {
+            return false;
+        };
+        given.iter().zip(password).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+    }
+
+    pub fn encode(&self, buffer: &mut [u8]) -> Option<usize> {
+        let length = MAGIC_PACKET_LENGTH + self.password.map_or(0, |_| PASSWORD_LENGTH);
+        let buffer = buffer.get_mut(..length)?;
+        buffer[..SYNC.len()].copy_from_slice(&SYNC);
+        let (repeated, password) = buffer[SYNC.len()..].split_at_mut(REPETITIONS * MAC_LENGTH);
+        for repetition in repeated.chunks_exact_mut(MAC_LENGTH) {
+            repetition.copy_from_slice(&self.mac_address);
+        }
+        if let Some(given) = self.password {
+            password.copy_from_slice(&given);
+        }
+        Some(length)
+    }
+}
+
+/// A MAC address or SecureOn password as people write them, `aa:bb:cc:dd:ee:ff` or with dashes.
+pub fn parse_hex_address(text: &str) -> Option<[u8; MAC_LENGTH]> {
+    let mut address = [0_u8; MAC_LENGTH];
+    let mut parts = text.split([':', '-']);
+    for byte in address.iter_mut() {
+        let part = parts.next().filter(|part| part.len() == 2)?;
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(address)
+}
@@ -0,0 +1,47 @@
+use pibow_wake::{ parse_hex_address, MagicPacket, MAGIC_PACKET_LENGTH };
+
+const MAC: [u8; 6] = [0x02, 0x00, 0x5e, 0x10, 0x00, 0x01];
+
+#[test]
+fn magic_packets_are_found_anywhere_in_the_payload() {
+    let packet = MagicPacket { mac_address: MAC, password: None };
+    let mut payload = [0_u8; 128];
+    // Some senders put a header of their own in front, with a stray 0xff in it.
+    payload[..3].copy_from_slice(&[0x42, 0xff, 0x00]);
+    let length = packet.encode(&mut payload[3..]).unwrap();
+    assert_eq!(length, MAGIC_PACKET_LENGTH);
+    assert_eq!(MagicPacket::find(&payload[..3 + length]), Some(packet));
+    assert!(packet.wakes(&MAC, None));
+    assert!(!packet.wakes(&[0x02, 0, 0, 0, 0, 0x01], None));
+
+    // One repetition off and it's no magic packet at all.
+    payload[3 + 6 + 5 * 6] ^= 1;
+    assert_eq!(MagicPacket::find(&payload[..3 + length]), None);
+    assert_eq!(MagicPacket::find(&payload[..50]), None);
+}
+
+#[test]
+fn secureon_passwords_have_to_match() {
+    let password = [1, 2, 3, 4, 5, 6];
+    let packet = MagicPacket { mac_address: MAC, password: Some(password) };
+    let mut payload = [0_u8; 128];
+    let length = packet.encode(&mut payload).unwrap();
+    assert_eq!(length, MAGIC_PACKET_LENGTH + 6);
+    let found = MagicPacket::find(&payload[..length]).unwrap();
+    assert!(found.wakes(&MAC, Some(&password)));
+    assert!(!found.wakes(&MAC, Some(&[1, 2, 3, 4, 5, 7])));
+    // A machine without a password doesn't care about one.
+    assert!(found.wakes(&MAC, None));
+
+    let bare = MagicPacket::find(&payload[..MAGIC_PACKET_LENGTH]).unwrap();
+    assert!(!bare.wakes(&MAC, Some(&password)));
+}
+
+#[test]
+fn addresses_parse_with_colons_or_dashes() {
+    assert_eq!(parse_hex_address("02:00:5e:10:00:01"), Some(MAC));
+    assert_eq!(parse_hex_address("02-00-5E-10-00-01"), Some(MAC));
+    assert_eq!(parse_hex_address("02:00:5e:10:00"), None);
+    assert_eq!(parse_hex_address("02:00:5e:10:00:01:ff"), None);
+    assert_eq!(parse_hex_address("2:00:5e:10:00:01"), None);
+}
@@ -1,6 +1,10 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 8K
+
+    /* Last two sectors are kept out of the image, the node's config record lives there */
+    /* Keep in sync with CONFIG_SLOTS in src/storage.rs                                 */
+    CONFIG : ORIGIN = 0x101FE000, LENGTH = 8K
 
     /* Pick one of the two options for RAM layout     */
 
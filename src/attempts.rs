@@ -0,0 +1,64 @@
+use core::{ fmt::Write, net::IpAddr };
+
+use embassy_net::IpAddress;
+use embassy_sync::{ blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex };
+use embassy_time::Instant;
+use heapless::{ String, Vec };
+use pibow_protocol::{ AttemptLimiter, Lockout };
+
+use crate::phases::board;
+
+// Lockouts kept for the next session with the server, the oldest go when there's no room.
+const UNREPORTED: usize = 4;
+
+// Every failed answer or handshake, counted per source address.
+struct Attempts {
+    limiter: AttemptLimiter,
+    unreported: Vec<Lockout, UNREPORTED>,
+}
+
+static ATTEMPTS: Mutex<CriticalSectionRawMutex, Attempts> = Mutex::new(Attempts {
+    limiter: AttemptLimiter::new(),
+    unreported: Vec::new(),
+});
+
+// Whether the address is still locked out, nothing it sends should even be read.
+pub async fn locked_out(address: IpAddress) -> bool {
+    let now_ms = Instant::now().as_millis();
+    ATTEMPTS.lock().await.limiter.locked_out(IpAddr::from(address), now_ms).is_some()
+}
+
+// Count a failed attempt, `what` says what it got wrong for the serial log.
+pub async fn failed(address: IpAddress, what: &str) {
+    let now_ms = Instant::now().as_millis();
+    let lockout = {
+        let mut attempts = ATTEMPTS.lock().await;
+        let Some(lockout) = attempts.limiter.failed(IpAddr::from(address), now_ms) else {
+            return;
+        };
+        if attempts.unreported.is_full() {
+            attempts.unreported.remove(0);
+        }
+        let _ = attempts.unreported.push(lockout);
+        lockout
+    };
+
+    let mut notice: String<96> = String::new();
+    let _ = write!(
+        notice,
+        "Locked out {} for {}s after {} bad {what}",
+        lockout.address,
+        lockout.duration_ms / 1000,
+        lockout.failures
+    );
+    board::serial_log(&notice);
+}
+
+pub async fn succeeded(address: IpAddress) {
+    ATTEMPTS.lock().await.limiter.succeeded(IpAddr::from(address));
+}
+
+// The lockouts the server hasn't heard about yet, only ever handed to a server that proved the key.
+pub async fn take_unreported() -> Vec<Lockout, UNREPORTED> {
+    core::mem::take(&mut ATTEMPTS.lock().await.unreported)
+}
@@ -1,31 +1,34 @@
-use embassy_rp::gpio::Level;
+// The Wifi, secret key, ports, multicast group, fault tolerance and channels come from build.rs,
+// out of pibow.toml or the PIBOW_* environment variables. They're only defaults though:
+// the config stored in flash (see storage.rs) takes over once it has been saved.
+include!(concat!(env!("OUT_DIR"), "/config.rs"));
 
-// Secret hash key must be shared with the server.
-// Use build.py script to generate and obtain a random key.
-pub const WIFI_NETWORK: &str = "ssid";
-pub const WIFI_PASSWORD: &str = "password";
-pub const SECRET_HASH_KEY: &[u8; 32] = &[0_u8; 32];
+// Backoff between failed Wifi joins, doubling from the first up to the max.
+pub const WIFI_RETRY_FIRST_SECS: u64 = 1;
+pub const WIFI_RETRY_MAX_SECS: u64 = 60;
 
-pub const CHALLENGE_LENGTH: usize = 64;
-pub const ANSWER_LENGTH: usize = 32;
+// Failed joins in a row before giving up and opening the setup access point, a few minutes of
+// backing off.
+pub const WIFI_JOIN_ATTEMPTS: u32 = 8;
 
-// The server poke destination.
-pub const MULTICAST_IP: u32 = 3758096511; // 224.0.0.127
-pub const MULTICAST_PORT: u16 = 4265;
+// The provisioning access point, its name and password come from pibow_portal.
+pub const PORTAL_CHANNEL: u8 = 6;
+// Reboot and try the stored Wifi again after this long, the access point might just have been down.
+pub const PORTAL_TIMEOUT_SECS: u64 = 600;
 
-// The port used on the node (this will open both TCP & UDP).
-pub const NODE_PORT: u16 = 5325;
+// How long to wait before asking again when the entropy source fails its health test.
+pub const ENTROPY_RETRY_SECS: u64 = 5;
 
-// The port used on server to let node connect to.
-pub const SERVER_PORT: u16 = 7325;
+// How long to leave a server be after one end hung up on the other, a version mismatch won't go away
+// on its own.
+pub const HUNG_UP_RETRY_SECS: u64 = 60;
 
-// Fault tolerance from server before disconnecting for good.
-pub const FAULT_TOLERANCE: usize = 5;
+// How often the server hears what the analog pins measure, when it changed at all.
+pub const READING_INTERVAL_SECS: u64 = 5;
+
+// How often the link gets checked for drops.
+pub const LINK_CHECK_MILLIS: u64 = 500;
 
 // The buffer for socket, not the receiving buffer for messages.
 // All actions in here needs at most 150 bytes. Chose 512 for safety, that's all.
 pub const STACK_BUFFER_SIZE: usize = 512;
-
-// Adjust this based on how your relay module works.
-pub const ACTIVATE_RELAY: Level = Level::Low;
-pub const DEACTIVATE_RELAY: Level = Level::High;
\ No newline at end of file
@@ -0,0 +1,105 @@
+use embassy_rp::clocks::RoscRng;
+use embassy_sync::{ blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex };
+use embassy_time::Instant;
+use pibow_entropy::{ Csprng, Pool, Unhealthy };
+use portable_atomic::{ AtomicBool, Ordering };
+
+use crate::phases::board;
+
+// Raw ring oscillator bytes for the first seed, and for every reseed after that. The ROSC is
+// weak, only count on a bit or so of entropy per byte.
+const SEED_SAMPLES: usize = 512;
+const RESEED_SAMPLES: usize = 128;
+
+// The generator, and whatever got stirred in since it was last reseeded.
+struct Entropy {
+    rng: Option<Csprng>,
+    pending: Option<Pool>,
+}
+
+static ENTROPY: Mutex<CriticalSectionRawMutex, Entropy> = Mutex::new(Entropy {
+    rng: None,
+    pending: None,
+});
+
+// Whether the ring oscillator passed its last health test, for the shell's status.
+static HEALTHY: AtomicBool = AtomicBool::new(false);
+
+pub fn healthy() -> bool {
+    HEALTHY.load(Ordering::Relaxed)
+}
+
+// Seed the generator, before anything draws from it.
+pub async fn init() {
+    let mut pool = sample(Pool::new(), SEED_SAMPLES);
+    let mut entropy = ENTROPY.lock().await;
+    match pool.seed() {
+        Ok(seed) => {
+            entropy.rng = Some(Csprng::from_seed(seed));
+            entropy.pending = Some(Pool::new());
+        }
+        Err(Unhealthy) => {
+            board::serial_log("The ring oscillator looks stuck, no challenges until it recovers");
+            pool.retest();
+            entropy.pending = Some(pool);
+        }
+    }
+}
+
+// Mix in something hard to guess.
+pub async fn stir(bytes: &[u8]) {
+    let mut entropy = ENTROPY.lock().await;
+    let pending = entropy.pending.get_or_insert_with(Pool::new);
+    pending.stir(bytes);
+    pending.stir(&Instant::now().as_ticks().to_le_bytes());
+}
+
+// Mix in how long the radio took over something, since `started`. Down to the timer tick, that's
+// up to the chip and the air around it.
+pub async fn stir_elapsed(started: Instant) {
+    stir(&started.elapsed().as_ticks().to_le_bytes()).await;
+}
+
+// Random bytes for challenges and nonces, reseeded with fresh samples first. Refused when the
+// ring oscillator fails its health test, nothing guessable goes out.
+pub async fn fill(dest: &mut [u8]) -> Result<(), Unhealthy> {
+    let mut entropy = ENTROPY.lock().await;
+    let mut pool = sample(entropy.pending.take().unwrap_or_default(), RESEED_SAMPLES);
+    let seed = match pool.seed() {
+        Ok(seed) => seed,
+        Err(Unhealthy) => {
+            // Whatever got stirred in so far stays for the next try, only the samples start over.
+            pool.retest();
+            entropy.pending = Some(pool);
+            return Err(Unhealthy);
+        }
+    };
+
+    let rng = entropy.rng.get_or_insert_with(|| Csprng::from_seed(seed));
+    rng.reseed(&seed);
+    rng.fill_bytes(dest);
+    Ok(())
+}
+
+// The network stack's seed. It only picks ports and sequence numbers, so it never gets refused.
+pub async fn net_seed() -> u64 {
+    let mut entropy = ENTROPY.lock().await;
+    let pending = entropy.pending.take().unwrap_or_default();
+    // Even a stuck oscillator leaves the timings, good enough for this.
+    let seed = sample(pending, RESEED_SAMPLES).seed_unchecked();
+
+    let rng = entropy.rng.get_or_insert_with(|| Csprng::from_seed(seed));
+    rng.reseed(&seed);
+    rng.next_u64()
+}
+
+// Pull raw bytes off the ring oscillator, along with when each one came in: the ROSC drifts
+// against the timer, so the ticks jitter too.
+fn sample(mut pool: Pool, count: usize) -> Pool {
+    for _ in 0..count {
+        pool.add_sample(RoscRng::next_u8());
+        pool.stir(&[Instant::now().as_ticks() as u8]);
+    }
+    HEALTHY.store(pool.seed().is_ok(), Ordering::Relaxed);
+    pool
+}
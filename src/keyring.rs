@@ -0,0 +1,135 @@
+use embassy_sync::{ blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex };
+use embassy_time::{ Duration, Instant };
+use pibow_config::NodeConfig;
+
+use crate::{ phases::board, storage };
+
+// How often what's left of the grace window gets written back to flash, and what every boot takes off
+// it. The node can't tell how long it was off, so rebooting mustn't start the window over.
+const GRACE_CHECKPOINT_SECS: u32 = 900;
+
+// The keys the node answers to. The previous one is only good until its grace window runs out, which
+// the flash record keeps track of across reboots.
+struct KeyRing {
+    current: [u8; 32],
+    previous: Option<([u8; 32], Instant)>,
+    // When the grace window left was last written back.
+    checkpoint: Instant,
+    // The config the node booted with, what the key fields get written back with when nothing
+    // was saved since.
+    booted: Option<&'static NodeConfig>,
+}
+
+static KEY_RING: Mutex<CriticalSectionRawMutex, KeyRing> = Mutex::new(KeyRing {
+    current: [0_u8; 32],
+    previous: None,
+    checkpoint: Instant::from_ticks(0),
+    booted: None,
+});
+
+pub async fn init(config: &'static NodeConfig) {
+    let grace_secs = config.previous_key_grace_secs.saturating_sub(GRACE_CHECKPOINT_SECS);
+    {
+        let mut ring = KEY_RING.lock().await;
+        ring.booted = Some(config);
+        ring.current = config.secret_key;
+        ring.previous = grace(config.previous_key, grace_secs);
+        ring.checkpoint = Instant::now();
+    }
+
+    if config.previous_key_grace_secs != 0 {
+        save_grace(grace_secs).await;
+    }
+}
+
+fn grace(key: [u8; 32], grace_secs: u32) -> Option<([u8; 32], Instant)> {
+    if grace_secs == 0 {
+        return None;
+    }
+    Some((key, Instant::now() + Duration::from_secs(grace_secs as u64)))
+}
+
+// The current key, and the previous one while it's still good.
+pub async fn keys() -> ([u8; 32], Option<[u8; 32]>) {
+    let (current, previous, checkpoint) = {
+        let mut ring = KEY_RING.lock().await;
+        let now = Instant::now();
+        let left = ring.previous.map(|(_, until)| until.saturating_duration_since(now).as_secs());
+        if left == Some(0) {
+            ring.previous = None;
+        }
+        let since = now.saturating_duration_since(ring.checkpoint);
+        let due = since >= Duration::from_secs(GRACE_CHECKPOINT_SECS as u64);
+        let checkpoint = left.filter(|left| *left == 0 || due);
+        if checkpoint.is_some() {
+            ring.checkpoint = now;
+        }
+        (ring.current, ring.previous.map(|(key, _)| key), checkpoint)
+    };
+
+    if let Some(left) = checkpoint {
+        save_grace(left as u32).await;
+    }
+    (current, previous)
+}
+
+// Seconds left on the previous key, if there's one.
+pub async fn previous_remaining() -> Option<u64> {
+    let ring = KEY_RING.lock().await;
+    ring.previous.map(|(_, until)| until.saturating_duration_since(Instant::now()).as_secs())
+}
+
+// Take the key the server rotated to, only once it's safe in flash. The key the node had stays good
+// for the grace window, whichever of the two the session was opened with.
+pub async fn rotate(new_key: [u8; 32], grace_secs: u32) -> Result<(), ()> {
+    let mut ring = KEY_RING.lock().await;
+    let old_key = ring.current;
+
+    let mut stored = record(ring.booted).await?;
+    stored.secret_key = new_key;
+    stored.previous_key = old_key;
+    stored.previous_key_grace_secs = grace_secs;
+    storage::save(&stored).await?;
+
+    ring.current = new_key;
+    ring.previous = grace(old_key, grace_secs);
+    ring.checkpoint = Instant::now();
+    Ok(())
+}
+
+// Write back what's left of the grace window, so it doesn't come back whole on the next boot. Once
+// it's over, the previous key goes for good.
+async fn save_grace(grace_secs: u32) {
+    if grace_secs == 0 {
+        board::serial_log("The grace window of the previous key is over, dropping it");
+    }
+
+    let booted = KEY_RING.lock().await.booted;
+    let Ok(mut stored) = record(booted).await else {
+        return;
+    };
+    if stored.previous_key_grace_secs == 0 {
+        return;
+    }
+    if grace_secs == 0 {
+        stored.previous_key = [0_u8; 32];
+    }
+    stored.previous_key_grace_secs = grace_secs;
+    if let Err(_) = storage::save(&stored).await {
+        board::serial_log("Can't write the previous key's grace window to flash");
+    }
+}
+
+// The config to change the key fields of and save. Whatever got saved last, shell changes included,
+// or what the node booted with when nothing was. Never the build's defaults over a damaged record,
+// that would wipe the Wifi, the channels and the server settings along with it.
+async fn record(booted: Option<&'static NodeConfig>) -> Result<NodeConfig, ()> {
+    match storage::stored().await {
+        Ok(Some(stored)) => Ok(stored),
+        Ok(None) => booted.cloned().ok_or(()),
+        Err(()) => {
+            board::serial_log("The stored config is damaged, not saving the keys over it");
+            Err(())
+        }
+    }
+}
@@ -2,17 +2,45 @@
 #![no_main]
 #![allow(async_fn_in_trait)]
 
+mod attempts;
 mod consts;
+mod entropy;
+mod keyring;
 mod phases;
+mod relay;
+mod shell;
+mod storage;
+mod wake_on_lan;
 
 use core::panic::PanicInfo;
 
+use cyw43::Control;
+use defmt::unwrap;
 use embassy_executor::Spawner;
-use embassy_futures::select::{ select, Either };
-use embassy_rp::{ clocks::RoscRng, gpio::{ Input, Output, Pull } };
+use embassy_futures::select::{ select, select3, Either, Either3 };
+use embassy_net::Stack;
+use embassy_time::{ Instant, Timer };
+use heapless::Vec;
+use pibow_config::{ NodeConfig, MAX_CHANNELS };
+use pibow_power::PowerInterface;
+use pibow_protocol::{ MacAddress, CHALLENGE_LENGTH };
+use pibow_wake::WAKE_PORTS;
+use static_cell::StaticCell;
 use crate::{
-    consts::{ CHALLENGE_LENGTH, DEACTIVATE_RELAY, SECRET_HASH_KEY },
-    phases::{ board, connect_wifi, listen_answer, poke_server, server_contact, setup_stack },
+    consts::ENTROPY_RETRY_SECS,
+    phases::{
+        board,
+        connect_wifi,
+        listen_answer,
+        poke_server,
+        provision,
+        server_contact,
+        setup_stack,
+        watch_link,
+    },
+    relay::{ self, GpioPower, Pins },
+    shell,
+    wake_on_lan::Pressing,
 };
 
 use ::{ defmt_rtt as _ };
@@ -26,7 +54,14 @@ fn panic(_info: &PanicInfo) -> ! {
 async fn main(spawner: Spawner) {
     let peripherals = embassy_rp::init(Default::default());
 
+    // Load the config before anything else gets going, the consts are only defaults.
+    storage::init(peripherals.FLASH).await;
+    static CONFIG: StaticCell<NodeConfig> = StaticCell::new();
+    let config: &'static NodeConfig = CONFIG.init(storage::load().await);
+    keyring::init(config).await;
+
     // Initialize the board.
+    let started = Instant::now();
     let (mut control, net_device) = board::initialize(
         spawner,
         (
@@ -40,52 +75,153 @@ async fn main(spawner: Spawner) {
         peripherals.USB
     ).await;
 
+    // Before anything draws random numbers, the network stack's seed first.
+    entropy::init().await;
+    // Loading the Wifi chip's firmware and bringing it up doesn't take the same time twice.
+    entropy::stir_elapsed(started).await;
+
     // Initialize the Wifi stack.
     let stack = setup_stack::invoke(spawner, net_device).await;
 
-    // Conenct to the Wifi.
-    connect_wifi::invoke(&mut control, &stack).await;
-
+    // Its key is issued for this MAC address, the shell shows it.
     let mac_address = control.address().await;
+    // Not secret, but it tells nodes booting at the same time apart.
+    entropy::stir(&mac_address).await;
+
+    // The shell has to be up before joining, a node with the wrong credentials never gets past that.
+    unwrap!(spawner.spawn(shell::shell_task(stack, config, mac_address)));
+    // Magic packets get taken from the start, they wait for the machines to be wired.
+    for port in WAKE_PORTS {
+        unwrap!(spawner.spawn(wake_on_lan::listen_task(stack, config, port)));
+    }
 
-    let mut power_switch = Output::new(peripherals.PIN_14, DEACTIVATE_RELAY);
-    let mut reset_switch = Output::new(peripherals.PIN_15, DEACTIVATE_RELAY);
-    let mut machine_state = Input::new(peripherals.PIN_16, Pull::Down);
+    join(&mut control, stack, config, mac_address).await;
+
+    // Every GPIO a channel can take, the Wifi chip has 23 to 25 and 29.
+    let mut pins: Pins = [
+        Some(peripherals.PIN_0.into()),
+        Some(peripherals.PIN_1.into()),
+        Some(peripherals.PIN_2.into()),
+        Some(peripherals.PIN_3.into()),
+        Some(peripherals.PIN_4.into()),
+        Some(peripherals.PIN_5.into()),
+        Some(peripherals.PIN_6.into()),
+        Some(peripherals.PIN_7.into()),
+        Some(peripherals.PIN_8.into()),
+        Some(peripherals.PIN_9.into()),
+        Some(peripherals.PIN_10.into()),
+        Some(peripherals.PIN_11.into()),
+        Some(peripherals.PIN_12.into()),
+        Some(peripherals.PIN_13.into()),
+        Some(peripherals.PIN_14.into()),
+        Some(peripherals.PIN_15.into()),
+        Some(peripherals.PIN_16.into()),
+        Some(peripherals.PIN_17.into()),
+        Some(peripherals.PIN_18.into()),
+        Some(peripherals.PIN_19.into()),
+        Some(peripherals.PIN_20.into()),
+        Some(peripherals.PIN_21.into()),
+        Some(peripherals.PIN_22.into()),
+        None,
+        None,
+        None,
+        Some(peripherals.PIN_26.into()),
+        Some(peripherals.PIN_27.into()),
+        Some(peripherals.PIN_28.into()),
+    ];
+    // Before wiring, a channel might read its state pin through it.
+    relay::init_adc(peripherals.ADC);
+    let mut powers: Vec<GpioPower, MAX_CHANNELS> = Vec::new();
+    let settings = relay::sense_settings(config);
+    for (index, channel) in config.channels.iter().enumerate() {
+        let Some(power) = GpioPower::wire(index, channel, settings, &mut pins).await else {
+            // The channels after it would shift down, leave them out too.
+            board::serial_log("A channel's pins are taken or don't exist, leaving it out");
+            break;
+        };
+        let _ = powers.push(power);
+    }
 
+    let pressing = Pressing::default();
     loop {
-        // Create a hash challenge and cast it to the UDP channel.
-        let mut challenge = [0_u8; CHALLENGE_LENGTH];
-        for index in 0..CHALLENGE_LENGTH {
-            challenge[index] = RoscRng::next_u8();
-        }
-        let expected_answer = blake3::keyed_hash(SECRET_HASH_KEY, &challenge);
-
-        // Create a UDP multicast socket to poke the server.
-        // It will be dropped by executor after listen_answer was selected when a good server contacted it.
-        // In case the poke_server finishes first, just redo this process.
-        // Receive the remote address of the server. We will then connect back to this under a defined port.
-        let expect_server_address = select(
-            poke_server::invoke(stack, &challenge),
-            listen_answer::invoke(stack, expected_answer)
-        ).await;
-
-        let server_address = match expect_server_address {
-            Either::First(_) => {
-                continue;
+        // One round of discovery and session, dropped as a whole when the Wifi goes away.
+        let round = async {
+            // Create a hash challenge and cast it to the UDP channel.
+            let mut challenge = [0_u8; CHALLENGE_LENGTH];
+            if let Err(_) = entropy::fill(&mut challenge).await {
+                // A guessable challenge is worse than none, wait for the source to come back.
+                board::serial_log("The entropy source failed its health test, no challenge");
+                Timer::after_secs(ENTROPY_RETRY_SECS).await;
+                return;
             }
-            Either::Second(server_address) => server_address,
+            // Rotated keys take over right away, no need to reboot.
+            let keys = keyring::keys().await;
+
+            // Create a UDP multicast socket to poke the server.
+            // It will be dropped by executor after listen_answer was selected when a good server contacted it.
+            // In case the poke_server finishes first, just redo this process.
+            // Receive the remote address of the server. We will then connect back to this under a defined port.
+            let discovery = async {
+                let answered = select(
+                    poke_server::invoke(stack, config, &challenge, mac_address),
+                    listen_answer::invoke(stack, config, &challenge, keys)
+                ).await;
+                // A server answering doesn't cut a magic packet's press short.
+                pressing.done().await;
+                answered
+            };
+            // No session to take magic packets yet, they get their power ON in the meantime.
+            let timings = relay::timings(config);
+            let serving = wake_on_lan::serve(&mut powers, &timings, &pressing);
+            let Either::First(expect_server_address) = select(discovery, serving).await else {
+                return;
+            };
+
+            let (server_address, key) = match expect_server_address {
+                Either::First(_) => {
+                    return;
+                }
+                Either::Second(answered) => answered,
+            };
+
+            // Found connection, light up!
+            control.gpio_set(0, true).await;
+
+            server_contact::invoke(stack, config, server_address, mac_address, key, &mut powers).await;
         };
 
-        // Found connection, light up!
-        control.gpio_set(0, true).await;
-
-        server_contact::invoke(
-            stack,
-            server_address,
-            mac_address,
-            &mut power_switch,
-            &mut reset_switch,
-            &mut machine_state
-        ).await;
+        let cut = select3(round, watch_link::invoke(stack), shell::portal_requested()).await;
+        if let Either3::First(_) = cut {
+            continue;
+        }
+        // The round might have been cut in the middle of a press.
+        for power in powers.iter_mut() {
+            power.release();
+        }
+        control.gpio_set(0, false).await;
+        control.leave().await;
+
+        if let Either3::Third(_) = cut {
+            provision::invoke(&mut control, stack, config, mac_address).await;
+        }
+        board::serial_log("Lost the Wifi link, rejoining...");
+        join(&mut control, stack, config, mac_address).await;
+        watch_link::count_reconnect();
+    }
+}
+
+// Connect to the Wifi, or have someone fix the config over the setup access point. That opens
+// without a Wifi to join, when joining keeps failing, or when the shell asks for it.
+async fn join(
+    control: &mut Control<'static>,
+    stack: Stack<'static>,
+    config: &'static NodeConfig,
+    mac_address: MacAddress
+) {
+    let joining = connect_wifi::invoke(control, &stack, config);
+    if let Either::First(Ok(_)) = select(joining, shell::portal_requested()).await {
+        return;
     }
+    control.leave().await;
+    provision::invoke(control, stack, config, mac_address).await;
 }
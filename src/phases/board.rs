@@ -12,7 +12,7 @@ use embassy_rp::{
     Peri,
 };
 use embassy_sync::{ blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel };
-use embassy_usb::class::cdc_acm::{ CdcAcmClass, State };
+use embassy_usb::class::cdc_acm::{ CdcAcmClass, Receiver, Sender, State };
 use embassy_usb::{ Builder, Config };
 use static_cell::StaticCell;
 
@@ -37,9 +37,9 @@ async fn usb_task(mut usb: embassy_usb::UsbDevice<'static, Driver<'static, USB>>
 static SERIAL_CHANNEL: Channel<CriticalSectionRawMutex, heapless::String<256>, 8> = Channel::new();
 
 #[embassy_executor::task]
-async fn serial_logger_task(mut class: CdcAcmClass<'static, Driver<'static, USB>>) {
+async fn serial_logger_task(mut sender: Sender<'static, Driver<'static, USB>>) {
     loop {
-        class.wait_connection().await;
+        sender.wait_connection().await;
 
         loop {
             // Wait for messages from the channel or send heartbeat
@@ -57,18 +57,26 @@ async fn serial_logger_task(mut class: CdcAcmClass<'static, Driver<'static, USB>
                     buffer[len] = b'\r';
                     buffer[len + 1] = b'\n';
 
-                    match class.write_packet(&buffer[..len + 2]).await {
-                        Ok(_) => {}
-                        Err(embassy_usb::driver::EndpointError::BufferOverflow) => {}
-                        Err(embassy_usb::driver::EndpointError::Disabled) => {
-                            break;
+                    // Packets are 64 bytes at most, longer lines go out in pieces.
+                    let mut disabled = false;
+                    for packet in buffer[..len + 2].chunks(64) {
+                        match sender.write_packet(packet).await {
+                            Ok(_) => {}
+                            Err(embassy_usb::driver::EndpointError::BufferOverflow) => {}
+                            Err(embassy_usb::driver::EndpointError::Disabled) => {
+                                disabled = true;
+                                break;
+                            }
                         }
                     }
+                    if disabled {
+                        break;
+                    }
                 }
                 Err(_) => {
                     // Timeout - send heartbeat
                     let heartbeat = b"1\r\n";
-                    match class.write_packet(heartbeat).await {
+                    match sender.write_packet(heartbeat).await {
                         Ok(_) => {}
                         Err(embassy_usb::driver::EndpointError::Disabled) => {
                             break;
@@ -81,6 +89,59 @@ async fn serial_logger_task(mut class: CdcAcmClass<'static, Driver<'static, USB>
     }
 }
 
+pub const SHELL_LINE_LENGTH: usize = 128;
+
+// Lines typed into the serial port, for the shell.
+static SHELL_LINES: Channel<CriticalSectionRawMutex, heapless::String<SHELL_LINE_LENGTH>, 2> = Channel::new();
+
+#[embassy_executor::task]
+async fn serial_reader_task(mut receiver: Receiver<'static, Driver<'static, USB>>) {
+    let mut line: heapless::String<SHELL_LINE_LENGTH> = heapless::String::new();
+    let mut packet = [0_u8; 64];
+
+    loop {
+        receiver.wait_connection().await;
+
+        loop {
+            let length = match receiver.read_packet(&mut packet).await {
+                Ok(length) => length,
+                Err(embassy_usb::driver::EndpointError::Disabled) => {
+                    break;
+                }
+                Err(_) => {
+                    continue;
+                }
+            };
+
+            for byte in &packet[..length] {
+                match byte {
+                    b'\r' | b'\n' => {
+                        if !line.is_empty() {
+                            SHELL_LINES.send(line.clone()).await;
+                            line.clear();
+                        }
+                    }
+                    // Backspace, or DEL as most terminals send it.
+                    0x08 | 0x7f => {
+                        line.pop();
+                    }
+                    // Anything that doesn't fit gets dropped, the shell will complain about the line.
+                    0x20..=0x7e => {
+                        let _ = line.push(*byte as char);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        line.clear();
+    }
+}
+
+pub async fn next_shell_line() -> heapless::String<SHELL_LINE_LENGTH> {
+    SHELL_LINES.receive().await
+}
+
 // Helper function to send messages to serial logger
 pub fn serial_log(msg: &str) {
     if let Ok(string_msg) = heapless::String::try_from(msg) {
@@ -88,6 +149,13 @@ pub fn serial_log(msg: &str) {
     }
 }
 
+// Same, but waits for room instead of dropping, so a long shell reply comes out whole.
+pub async fn serial_reply(msg: &str) {
+    if let Ok(string_msg) = heapless::String::try_from(msg) {
+        SERIAL_CHANNEL.send(string_msg).await;
+    }
+}
+
 pub async fn initialize(
     spawner: Spawner,
     pins: (
@@ -135,7 +203,9 @@ async fn init_usb(spawner: Spawner, usb_port: Peri<'static, USB>) {
     let usb_device = builder.build();
 
     unwrap!(spawner.spawn(usb_task(usb_device)));
-    unwrap!(spawner.spawn(serial_logger_task(serial_class)));
+    let (sender, receiver) = serial_class.split();
+    unwrap!(spawner.spawn(serial_logger_task(sender)));
+    unwrap!(spawner.spawn(serial_reader_task(receiver)));
 }
 
 async fn init_wifi(
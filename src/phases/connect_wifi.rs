@@ -1,27 +1,58 @@
 use cyw43::{ Control, JoinOptions };
 use embassy_net::Stack;
-use embassy_time::Timer;
+use embassy_time::{ Instant, Timer };
+use pibow_config::NodeConfig;
 
-use crate::{ consts::*, phases::board };
+use crate::{ consts::*, entropy, phases::board };
+
+// Fails when there's no Wifi configured, or it can't be joined after a few attempts, so a node whose
+// access point changed its password can still be fixed without a USB cable. Opening the setup
+// access point that way gains nobody anything, its password is the node's own.
+pub async fn invoke(
+    control: &mut Control<'static>,
+    stack: &Stack<'static>,
+    config: &NodeConfig
+) -> Result<(), ()> {
+    if config.wifi_network.is_empty() {
+        board::serial_log("No Wifi configured");
+        return Err(());
+    }
 
-pub async fn invoke(control: &mut Control<'static>, stack: &Stack<'static>) {
     // Connect to Wifi.
     board::serial_log("Joining wifi...");
+    let mut retry_secs = WIFI_RETRY_FIRST_SECS;
+    let mut attempts = 0;
     loop {
-        match control.join(WIFI_NETWORK, JoinOptions::new(WIFI_PASSWORD.as_bytes())).await {
+        let options = JoinOptions::new(config.wifi_password.as_bytes());
+        let started = Instant::now();
+        let joined = control.join(&config.wifi_network, options).await;
+        // How long the chip took to scan, authenticate and associate, hard to guess from outside.
+        entropy::stir_elapsed(started).await;
+        match joined {
             Ok(_) => {
                 break;
             }
             Err(_) => {
-                board::serial_log("Can't join the Wifi network");
+                board::serial_log("Can't join the Wifi network, `wifi set` or `portal` to fix it");
+                attempts += 1;
+                if attempts >= WIFI_JOIN_ATTEMPTS {
+                    return Err(());
+                }
+                // Don't hammer an access point that's still booting.
+                Timer::after_secs(retry_secs).await;
+                retry_secs = (retry_secs * 2).min(WIFI_RETRY_MAX_SECS);
             }
         }
     }
 
     board::serial_log("Waiting for DHCP...");
+    let started = Instant::now();
     while !stack.is_config_up() || !stack.is_link_up() {
         Timer::after_millis(100).await;
     }
     board::serial_log("DHCP is now up!");
+    // Same for the DHCP server, coarser since it only gets checked every 100 ms.
+    entropy::stir_elapsed(started).await;
     // And now we can use the wifi!
+    Ok(())
 }
@@ -1,11 +1,21 @@
-use blake3::Hash;
 use embassy_net::{ tcp::TcpSocket, IpAddress, Stack };
 use embassy_time::Duration;
 use embedded_io_async::Read;
+use pibow_config::NodeConfig;
+use pibow_protocol::{ Challenge, ANSWER_LENGTH };
 
-use crate::{ consts::{ ANSWER_LENGTH, NODE_PORT, STACK_BUFFER_SIZE }, phases::board };
+use crate::{ attempts, consts::STACK_BUFFER_SIZE, phases::board };
+
+// Returns the server's address and which of the keys it answered with.
+// The previous key is only there during its grace window, for servers that weren't rolled over yet.
+pub async fn invoke(
+    stack: Stack<'static>,
+    config: &NodeConfig,
+    challenge: &Challenge,
+    keys: ([u8; 32], Option<[u8; 32]>)
+) -> (IpAddress, [u8; 32]) {
+    let (key, previous_key) = keys;
 
-pub async fn invoke(stack: Stack<'static>, expected_answer: Hash) -> IpAddress {
     let mut rx_buffer = [0_u8; STACK_BUFFER_SIZE];
     let mut tx_buffer = [0_u8; STACK_BUFFER_SIZE];
 
@@ -15,35 +25,50 @@ pub async fn invoke(stack: Stack<'static>, expected_answer: Hash) -> IpAddress {
     board::serial_log("TCP Initialized");
 
     loop {
-        if let Err(_) = socket.accept(NODE_PORT).await {
+        if let Err(_) = socket.accept(config.node_port).await {
             continue;
         }
 
-        let mut challenge_answer = [0_u8; ANSWER_LENGTH];
+        let Some(remote_endpoint) = socket.remote_endpoint() else {
+            socket.abort();
+            socket.close();
+            continue;
+        };
 
-        if let Err(_) = socket.read_exact(&mut challenge_answer).await {
+        // Whoever kept guessing wrong doesn't even get read.
+        if attempts::locked_out(remote_endpoint.addr).await {
             let _ = socket.flush().await;
             socket.abort();
             socket.close();
             continue;
         }
 
-        let answer_hash = Hash::from_bytes(challenge_answer);
+        let mut challenge_answer = [0_u8; ANSWER_LENGTH];
 
-        if expected_answer != answer_hash {
+        // Holding the socket without answering counts as a wrong answer too.
+        if let Err(_) = socket.read_exact(&mut challenge_answer).await {
+            attempts::failed(remote_endpoint.addr, "answers").await;
             let _ = socket.flush().await;
             socket.abort();
             socket.close();
             continue;
         }
 
-        let remote_endpoint = socket.remote_endpoint().unwrap();
+        let answered = pibow_protocol::answered_key(challenge, &challenge_answer, key, previous_key);
+        let Some(answered_key) = answered else {
+            attempts::failed(remote_endpoint.addr, "answers").await;
+            let _ = socket.flush().await;
+            socket.abort();
+            socket.close();
+            continue;
+        };
+        attempts::succeeded(remote_endpoint.addr).await;
 
         // Disconnect the server. We'll connect to it.
         let _ = socket.flush().await;
         socket.abort();
         socket.close();
 
-        return remote_endpoint.addr;
+        return (remote_endpoint.addr, answered_key);
     }
 }
@@ -4,3 +4,5 @@ pub mod connect_wifi;
 pub mod poke_server;
 pub mod listen_answer;
 pub mod server_contact;
+pub mod watch_link;
+pub mod provision;
@@ -2,13 +2,17 @@ use core::net::{ Ipv4Addr, SocketAddr, SocketAddrV4 };
 
 use embassy_net::{ udp::{ PacketMetadata, UdpSocket }, Stack };
 use embassy_time::Timer;
+use pibow_config::NodeConfig;
+use pibow_protocol::{ Challenge, MacAddress, Poke };
 
-use crate::{
-    consts::{ CHALLENGE_LENGTH, MULTICAST_IP, MULTICAST_PORT, NODE_PORT, STACK_BUFFER_SIZE },
-    phases::board,
-};
+use crate::{ consts::STACK_BUFFER_SIZE, phases::board };
 
-pub async fn invoke(stack: Stack<'static>, challenge: &[u8; CHALLENGE_LENGTH]) {
+pub async fn invoke(
+    stack: Stack<'static>,
+    config: &NodeConfig,
+    challenge: &Challenge,
+    mac_address: MacAddress
+) {
     let mut rx_meta = [PacketMetadata::EMPTY; 8];
     let mut rx_buffer = [0; STACK_BUFFER_SIZE];
     let mut tx_meta = [PacketMetadata::EMPTY; 8];
@@ -21,16 +25,19 @@ pub async fn invoke(stack: Stack<'static>, challenge: &[u8; CHALLENGE_LENGTH]) {
         &mut tx_meta,
         &mut tx_buffer
     );
-    let _ = announcer.bind(NODE_PORT);
+    let _ = announcer.bind(config.node_port);
 
     board::serial_log("UDP Initialized");
 
     let multicast_addr = SocketAddr::V4(
-        SocketAddrV4::new(Ipv4Addr::from_bits(MULTICAST_IP), MULTICAST_PORT)
+        SocketAddrV4::new(Ipv4Addr::from_bits(config.multicast_ip), config.multicast_port)
     );
 
+    // The MAC address tells the server which key this node holds.
+    let poke = (Poke { challenge: *challenge, mac_address }).to_bytes();
+
     loop {
-        let _ = announcer.send_to(challenge, multicast_addr).await;
+        let _ = announcer.send_to(&poke, multicast_addr).await;
         Timer::after_secs(2).await;
     }
 }
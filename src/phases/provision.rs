@@ -0,0 +1,240 @@
+use core::{ fmt::Write as _, net::{ Ipv4Addr, SocketAddr, SocketAddrV4 } };
+
+use cyw43::Control;
+use embassy_futures::{ join::join3, select::select };
+use embassy_net::{
+    tcp::TcpSocket,
+    udp::{ PacketMetadata, UdpSocket },
+    ConfigV4,
+    Ipv4Cidr,
+    Stack,
+    StaticConfigV4,
+};
+use embassy_time::{ Duration, Timer };
+use embedded_io_async::{ Read, Write };
+use heapless::String;
+use pibow_config::NodeConfig;
+use pibow_portal::{ access_point_name, access_point_password, Method, PORTAL_ADDRESS };
+use pibow_protocol::MacAddress;
+
+use crate::{ consts::*, keyring, phases::board, storage };
+
+const REQUEST_LENGTH: usize = 1024;
+const PAGE_LENGTH: usize = 2048;
+
+// The node has no Wifi to join, or someone asked for it on the shell, so it opens its own and serves
+// a setup page on it. Saving the form reboots the node back into station mode with the new config.
+pub async fn invoke(
+    control: &mut Control<'static>,
+    stack: Stack<'static>,
+    config: &NodeConfig,
+    mac_address: MacAddress
+) -> ! {
+    let network = access_point_name(&mac_address);
+    // Its own password for every node, `portal` on the shell tells it.
+    let (key, _) = keyring::keys().await;
+    let password = access_point_password(&key);
+    let mut notice: String<64> = String::new();
+    let _ = write!(notice, "Opening the setup access point {network}");
+    board::serial_log(&notice);
+
+    control.start_ap_wpa2(&network, &password, PORTAL_CHANNEL).await;
+    stack.set_config_v4(
+        ConfigV4::Static(StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Addr::from(PORTAL_ADDRESS), 24),
+            gateway: None,
+            dns_servers: heapless::Vec::new(),
+        })
+    );
+
+    let portal = join3(serve_dhcp(stack), serve_dns(stack), serve_http(stack, config));
+    if config.wifi_network.is_empty() {
+        // Nothing to go back to, wait for the form.
+        portal.await;
+    } else {
+        select(portal, Timer::after_secs(PORTAL_TIMEOUT_SECS)).await;
+        board::serial_log("Nobody came to the setup page, retrying the Wifi");
+    }
+
+    Timer::after_millis(200).await;
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+// Hands out addresses to whoever joins, pointing them at the node for routes and names.
+async fn serve_dhcp(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; STACK_BUFFER_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; STACK_BUFFER_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer
+    );
+    let _ = socket.bind(pibow_portal::DHCP_SERVER_PORT);
+
+    // Clients don't have an address yet, the replies are broadcast.
+    let clients = SocketAddr::V4(
+        SocketAddrV4::new(Ipv4Addr::BROADCAST, pibow_portal::DHCP_CLIENT_PORT)
+    );
+    let mut request = [0_u8; STACK_BUFFER_SIZE];
+    let mut reply = [0_u8; pibow_portal::MAX_DHCP_REPLY_LENGTH];
+
+    loop {
+        let Ok((length, _)) = socket.recv_from(&mut request).await else {
+            continue;
+        };
+        let reply_length = pibow_portal::dhcp_reply(&request[..length], PORTAL_ADDRESS, &mut reply);
+        if let Some(reply_length) = reply_length {
+            let _ = socket.send_to(&reply[..reply_length], clients).await;
+        }
+    }
+}
+
+// Every name resolves to the node, that's what makes phones and laptops pop the page up.
+async fn serve_dns(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; STACK_BUFFER_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; STACK_BUFFER_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer
+    );
+    let _ = socket.bind(pibow_portal::DNS_PORT);
+
+    let mut query = [0_u8; STACK_BUFFER_SIZE];
+    let mut response = [0_u8; STACK_BUFFER_SIZE];
+
+    loop {
+        let Ok((length, remote)) = socket.recv_from(&mut query).await else {
+            continue;
+        };
+        let response_length = pibow_portal::dns_answer(&query[..length], PORTAL_ADDRESS, &mut response);
+        if let Some(response_length) = response_length {
+            let _ = socket.send_to(&response[..response_length], remote).await;
+        }
+    }
+}
+
+async fn serve_http(stack: Stack<'static>, config: &NodeConfig) {
+    let mut rx_buffer = [0_u8; REQUEST_LENGTH];
+    let mut tx_buffer = [0_u8; PAGE_LENGTH];
+
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(Duration::from_secs(5)));
+
+    let mut request = [0_u8; REQUEST_LENGTH];
+    let mut page: String<PAGE_LENGTH> = String::new();
+
+    loop {
+        if let Err(_) = socket.accept(pibow_portal::HTTP_PORT).await {
+            continue;
+        }
+
+        let saved = match read_request(&mut socket, &mut request).await {
+            Some((Method::Post, true, body)) => {
+                // Start from what's stored, the running config might be the consts.
+                let mut updated = storage::load().await;
+                match pibow_portal::apply_form(&request[body], &mut updated) {
+                    Ok(_) if storage::save(&updated).await.is_ok() => {
+                        let notice = Some("Saved, the node is rebooting");
+                        respond(&mut socket, &mut page, config, notice).await;
+                        true
+                    }
+                    Ok(_) => {
+                        let notice = Some("Can't write the config to flash");
+                        respond(&mut socket, &mut page, config, notice).await;
+                        false
+                    }
+                    Err(error) => {
+                        let mut notice: String<64> = String::new();
+                        let _ = write!(notice, "{error}");
+                        respond(&mut socket, &mut page, config, Some(&notice)).await;
+                        false
+                    }
+                }
+            }
+            // Anything else gets the form, whatever the path: that's the captive part.
+            Some(_) => {
+                respond(&mut socket, &mut page, config, None).await;
+                false
+            }
+            None => false,
+        };
+
+        let _ = socket.flush().await;
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+
+        if saved {
+            board::serial_log("Config saved from the setup page, rebooting...");
+            Timer::after_millis(500).await;
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+}
+
+// The method, whether it's the form's target and where the body sits in `request`.
+async fn read_request(
+    socket: &mut TcpSocket<'_>,
+    request: &mut [u8]
+) -> Option<(Method, bool, core::ops::Range<usize>)> {
+    let mut filled = 0;
+    let (method, is_save, body) = loop {
+        if filled == request.len() {
+            return None;
+        }
+        match socket.read(&mut request[filled..]).await {
+            Ok(0) | Err(_) => {
+                return None;
+            }
+            Ok(length) => {
+                filled += length;
+            }
+        }
+        if let Some(head) = pibow_portal::parse_head(&request[..filled]) {
+            let body = head.head_length..head.head_length + head.content_length;
+            break (head.method, head.path == "/save", body);
+        }
+    };
+
+    if body.end > request.len() {
+        return None;
+    }
+    if filled < body.end {
+        socket.read_exact(&mut request[filled..body.end]).await.ok()?;
+    }
+
+    Some((method, is_save, body))
+}
+
+async fn respond(
+    socket: &mut TcpSocket<'_>,
+    page: &mut String<PAGE_LENGTH>,
+    config: &NodeConfig,
+    notice: Option<&str>
+) {
+    page.clear();
+    let _ = pibow_portal::render_page(config, notice, page);
+
+    let mut head: String<128> = String::new();
+    let _ = write!(
+        head,
+        "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        page.len()
+    );
+    if let Err(_) = socket.write_all(head.as_bytes()).await {
+        return;
+    }
+    let _ = socket.write_all(page.as_bytes()).await;
+}
@@ -1,38 +1,176 @@
-use blake3::Hash;
-use embassy_futures::select::{ select, Either };
-use embassy_net::{ tcp::{ self, TcpSocket }, IpAddress, IpEndpoint, Stack };
-use embassy_rp::{ clocks::RoscRng, gpio::{ Input, Level, Output } };
+use core::fmt::Write as _;
+
+use embassy_futures::select::{ select, select3, Either, Either3 };
+use embassy_net::{ tcp::{ self, TcpReader, TcpSocket, TcpWriter }, IpAddress, IpEndpoint, Stack };
 use embassy_time::Timer;
-use embedded_io_async::{ Read, ReadExactError, Write };
+use embedded_io_async::{ Read, Write };
+use pibow_config::{ NodeConfig, MAX_CHANNELS };
+use pibow_power::{ Outcome, PowerInterface };
+use heapless::String;
+use pibow_protocol::{
+    Capabilities,
+    Cipher,
+    CommandResult,
+    FrameDecoder,
+    Greeting,
+    Handshake,
+    Hello,
+    Introduction,
+    MacAddress,
+    NodeMessage,
+    Role,
+    Sealer,
+    ServerMessage,
+    GREETING_LENGTH,
+    MAX_FRAME_LENGTH,
+    NONCE_LENGTH,
+};
+use portable_atomic::{ AtomicBool, Ordering };
 
 use crate::{
-    consts::{
-        ACTIVATE_RELAY,
-        ANSWER_LENGTH,
-        CHALLENGE_LENGTH,
-        DEACTIVATE_RELAY,
-        FAULT_TOLERANCE,
-        SECRET_HASH_KEY,
-        SERVER_PORT,
-        STACK_BUFFER_SIZE,
-    },
-    phases::board,
+    attempts,
+    consts::{ HUNG_UP_RETRY_SECS, READING_INTERVAL_SECS, STACK_BUFFER_SIZE },
+    entropy,
+    keyring,
+    phases::{ board, watch_link },
+    relay,
+    wake_on_lan,
 };
 
+// What this node can do, the server only asks for what it finds in here.
+const CAPABILITIES: Capabilities = Capabilities::ENCRYPTED_SESSIONS.union(Capabilities::KEY_ROTATION)
+    .union(Capabilities::LOCKOUT_REPORTS)
+    .union(Capabilities::COMMAND_ACKS)
+    .union(Capabilities::FORCE_OFF)
+    .union(Capabilities::TIMING_OVERRIDES)
+    .union(Capabilities::VERIFIED_ACTIONS)
+    .union(Capabilities::CHANNELS)
+    .union(Capabilities::MACHINE_STATES)
+    .union(Capabilities::ANALOG_READINGS);
+
+// Whether a session with the server is going on, for the shell's status.
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+pub fn connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+// Clears the flag however the session ends, including the whole round getting dropped.
+struct Connected;
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        CONNECTED.store(false, Ordering::Relaxed);
+    }
+}
+
+async fn send(
+    writer: &mut TcpWriter<'_>,
+    sealer: &mut Sealer,
+    message: NodeMessage
+) -> Result<(), tcp::Error> {
+    let mut payload = [0_u8; NodeMessage::MAX_LENGTH];
+    let mut frame = [0_u8; MAX_FRAME_LENGTH];
+    // Both buffers fit any node message, sealing can't fail.
+    let length = message.encode(&mut payload).unwrap_or(0);
+    let length = sealer.seal(&payload[..length], &mut frame).unwrap_or(0);
+    writer.write_all(&frame[..length]).await
+}
+
+// Reads the next frame into `frame`. It only ever waits on the socket before taking any bytes, so
+// dropping it halfway loses nothing, the decoder keeps the part of the frame it got.
+async fn next_frame(
+    reader: &mut TcpReader<'_>,
+    decoder: &mut FrameDecoder,
+    frame: &mut [u8]
+) -> Result<usize, ()> {
+    loop {
+        let decoded = reader.read_with(|bytes| {
+            let (consumed, decoded) = decoder.decode(bytes);
+            let length = decoded.map(|complete| {
+                complete.map(|complete| {
+                    frame[..complete.len()].copy_from_slice(complete);
+                    complete.len()
+                })
+            });
+            (consumed, length)
+        }).await;
+        match decoded {
+            Ok(Ok(Some(length))) => {
+                return Ok(length);
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(_)) => {
+                board::serial_log("The server sent a frame too long, breaking...");
+                return Err(());
+            }
+            Err(_) => {
+                return Err(());
+            }
+        }
+    }
+}
+
+// Sends the machines' states and readings the server hasn't heard yet. Returns whether any of them
+// is sensed through the ADC, its readings go out on an interval too.
+async fn report(
+    writer: &mut TcpWriter<'_>,
+    sealer: &mut Sealer,
+    powers: &mut [impl PowerInterface],
+    capabilities: Capabilities,
+    reported_states: &mut [Option<NodeMessage>; MAX_CHANNELS],
+    reported_readings: &mut [Option<NodeMessage>; MAX_CHANNELS]
+) -> Result<bool, tcp::Error> {
+    for (channel, power) in powers.iter_mut().enumerate() {
+        // Whatever the server understands of it, a sleeping machine is just ON to older ones, and
+        // there's nothing to tell before the pin settles.
+        let report = NodeMessage::state_report(channel as u8, power.state(), capabilities);
+        let reported = reported_states[channel];
+        let Some(state) = report.filter(|report| reported != Some(*report)) else {
+            continue;
+        };
+        if let Err(bad) = send(writer, sealer, state).await {
+            board::serial_log("Can't report the machine's state, breaking...");
+            return Err(bad);
+        }
+        reported_states[channel] = Some(state);
+    }
+
+    // What the analog pins measure, for machines sensed through the ADC.
+    let readings = capabilities.contains(Capabilities::ANALOG_READINGS);
+    let mut analog = false;
+    for (channel, power) in powers.iter_mut().enumerate() {
+        let Some(reading) = power.reading().filter(|_| readings) else {
+            continue;
+        };
+        analog = true;
+        let reading = NodeMessage::Reading { channel: channel as u8, reading };
+        if reported_readings[channel] == Some(reading) {
+            continue;
+        }
+        if let Err(bad) = send(writer, sealer, reading).await {
+            board::serial_log("Can't report the machine's reading, breaking...");
+            return Err(bad);
+        }
+        reported_readings[channel] = Some(reading);
+    }
+    Ok(analog)
+}
+
 pub async fn invoke(
     stack: Stack<'static>,
+    config: &NodeConfig,
     server_address: IpAddress,
-    mac_address: [u8; 6],
-    power_switch: &mut Output<'static>,
-    reset_switch: &mut Output<'static>,
-    machine_state: &mut Input<'static>
+    mac_address: MacAddress,
+    key: [u8; 32],
+    powers: &mut [impl PowerInterface]
 ) {
     let mut rx_buffer = [0_u8; STACK_BUFFER_SIZE];
     let mut tx_buffer = [0_u8; STACK_BUFFER_SIZE];
 
     let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
 
-    if let Err(_) = socket.connect(IpEndpoint::new(server_address, SERVER_PORT)).await {
+    if let Err(_) = socket.connect(IpEndpoint::new(server_address, config.server_port)).await {
         board::serial_log("Can't connect to server endpoint");
         let _ = socket.flush().await;
         socket.abort();
@@ -42,176 +180,301 @@ pub async fn invoke(
 
     let (mut reader, mut writer) = socket.split();
 
-    // Enclose this whole initial communication, don't wanna waste memory keeping this for no reason.
-    {
-        // Read the challenge.
-        let mut challenge = [0_u8; CHALLENGE_LENGTH];
-        if let Err(_) = reader.read_exact(&mut challenge).await {
-            board::serial_log("Can't obtain the challenge from server.");
+    // Enclose this whole handshake, only the handshake itself is kept, to unwrap rotated keys, along
+    // with the server's hello.
+    let (handshake, server_hello) = {
+        // Read the server's nonce.
+        let mut server_nonce = [0_u8; NONCE_LENGTH];
+        if let Err(_) = reader.read_exact(&mut server_nonce).await {
+            board::serial_log("Can't obtain the nonce from server.");
             let _ = socket.flush().await;
             socket.abort();
             socket.close();
             return;
         }
 
-        // Answer the challenge, also introduce this node.
-        let answer = blake3::keyed_hash(SECRET_HASH_KEY, &challenge);
-        let mut introduce_with_answer = [0_u8; 38];
-        introduce_with_answer[0..6].copy_from_slice(&mac_address);
-        introduce_with_answer[6..38].copy_from_slice(answer.as_bytes());
+        // Prove the key over both nonces, also introduce this node.
+        let mut node_nonce = [0_u8; NONCE_LENGTH];
+        if let Err(_) = entropy::fill(&mut node_nonce).await {
+            board::serial_log("No entropy for the nonce, folding...");
+            let _ = socket.flush().await;
+            socket.abort();
+            socket.close();
+            return;
+        }
+        let handshake = Handshake {
+            key,
+            mac_address,
+            server_nonce,
+            node_nonce,
+            cipher: if config.encrypt_session { Cipher::ChaCha20Poly1305 } else { Cipher::Plain },
+            node_hello: Hello::new(CAPABILITIES),
+        };
+        let introduction = (Introduction {
+            mac_address,
+            nonce: node_nonce,
+            hello: handshake.node_hello,
+            proof: *handshake.node_proof().as_bytes(),
+        }).to_bytes();
 
-        if let Err(_) = writer.write_all(&introduce_with_answer).await {
+        if let Err(_) = writer.write_all(&introduction).await {
             board::serial_log("Can't introduce to the server, folding...");
             let _ = socket.flush().await;
             socket.abort();
             socket.close();
             return;
         }
+
+        // The server has to prove the key back, nothing it says counts before that.
+        let mut greeting = [0_u8; GREETING_LENGTH];
+        if let Err(_) = reader.read_exact(&mut greeting).await {
+            // Servers from before protocol versions can't make sense of the introduction, they just
+            // hang up.
+            board::serial_log("Can't obtain the proof from server, it may speak an older protocol.");
+            let _ = socket.flush().await;
+            socket.abort();
+            socket.close();
+            return;
+        }
+        let greeting = Greeting::from_bytes(&greeting);
+        if !pibow_protocol::proof_matches(&handshake.server_proof(&greeting.hello), &greeting.proof) {
+            board::serial_log("Server failed the handshake, folding...");
+            attempts::failed(server_address, "handshakes").await;
+            let _ = socket.flush().await;
+            socket.abort();
+            socket.close();
+            return;
+        }
+
+        (handshake, greeting.hello)
+    };
+    let (mut sealer, mut opener) = handshake.session(Role::Node);
+
+    // Hang up on a server too old for this node, and tell it why.
+    let agreed = match handshake.node_hello.agree(&server_hello) {
+        Ok(agreed) => agreed,
+        Err(code) => {
+            let mut notice: String<64> = String::new();
+            let _ = write!(notice, "The server speaks protocol {}, folding...", server_hello.version);
+            board::serial_log(&notice);
+            let _ = send(&mut writer, &mut sealer, NodeMessage::Error(code)).await;
+            let _ = socket.flush().await;
+            socket.abort();
+            socket.close();
+            Timer::after_secs(HUNG_UP_RETRY_SECS).await;
+            return;
+        }
+    };
+
+    // Let the server know how stable this node's Wifi has been.
+    let reconnects = NodeMessage::Reconnects(watch_link::reconnects());
+    if let Err(_) = send(&mut writer, &mut sealer, reconnects).await {
+        board::serial_log("Can't report the reconnects to the server, folding...");
+        let _ = socket.flush().await;
+        socket.abort();
+        socket.close();
+        return;
+    }
+
+    // Only now that the server proved the key, tell it who got locked out since last time. A server
+    // that can't take the reports leaves them for the serial log.
+    let lockouts = if agreed.capabilities.contains(Capabilities::LOCKOUT_REPORTS) {
+        attempts::take_unreported().await
+    } else {
+        heapless::Vec::new()
+    };
+    for lockout in lockouts {
+        let locked_out = NodeMessage::LockedOut {
+            address: lockout.address,
+            failures: lockout.failures,
+            secs: (lockout.duration_ms / 1000) as u32,
+        };
+        if let Err(_) = send(&mut writer, &mut sealer, locked_out).await {
+            board::serial_log("Can't report the lockouts to the server, folding...");
+            let _ = socket.flush().await;
+            socket.abort();
+            socket.close();
+            return;
+        }
     }
 
-    // If nothing goes wrong, start taking requests from server!
-    let mut current_challenge = [0_u8; CHALLENGE_LENGTH];
-    let mut expected_answer: Hash;
-    let mut action_with_answer = [0_u8; ANSWER_LENGTH + 1];
+    // If nothing goes wrong, start taking commands from server!
+    CONNECTED.store(true, Ordering::Relaxed);
+    let _connected = Connected;
 
-    // Counter on how many faults from the server.
+    let mut decoder = FrameDecoder::new();
+    let mut frame = [0_u8; MAX_FRAME_LENGTH];
+
+    // Counter on how many frames from the server didn't check out.
     let mut faults: usize = 0;
 
-    let mut reported_state: Option<Level> = None;
+    // A server without channels only hears about the first machine.
+    let watched = if agreed.capabilities.contains(Capabilities::CHANNELS) { powers.len() } else { 1 };
+    let watched = watched.min(powers.len());
+    let mut reported_states: [Option<NodeMessage>; MAX_CHANNELS] = [None; MAX_CHANNELS];
+    let mut reported_readings: [Option<NodeMessage>; MAX_CHANNELS] = [None; MAX_CHANNELS];
+
+    let timings = relay::timings(config);
+
+    // Whether the server hung up on purpose, no point coming right back then.
+    let mut hung_up = false;
 
     loop {
         // Check faults.
-        if faults > FAULT_TOLERANCE {
+        if faults > config.fault_tolerance as usize {
             break;
         }
 
-        // Prepare the challenge.
-        for index in 0..CHALLENGE_LENGTH {
-            current_challenge[index] = RoscRng::next_u8();
-        }
-        expected_answer = blake3::keyed_hash(SECRET_HASH_KEY, &current_challenge);
-        // Notifying the server that this is a challenge.
-        if let Err(_) = writer.write_all(&[2]).await {
-            board::serial_log("Can't send the notifier to server, breaking...");
-            break;
-        }
-        if let Err(_) = writer.write_all(&current_challenge).await {
-            board::serial_log("Can't send the challenge to server, breaking...");
+        // Tell the server what changed first, out here where nothing cuts a send short: a frame sealed
+        // but not written in full would leave the stream or the server's counter out of sync for good.
+        let reported = report(
+            &mut writer,
+            &mut sealer,
+            &mut powers[..watched],
+            agreed.capabilities,
+            &mut reported_states,
+            &mut reported_readings
+        ).await;
+        let Ok(analog) = reported else {
             break;
-        }
-
-        // The longest waiting task that we can attach to is wait for action & answer from server.
-        // So in the mean time, we can use this wait time to send report of the machine's state to server.
-        // Since we can't move mutable across states without unsafe, this is the best way that I could think of.
-        let bad_cases: Either<
-            Result<(), ReadExactError<tcp::Error>>,
-            Result<(), tcp::Error>
-        > = select(
-            // Wait for action & answer.
-            (async || {
-                if let Err(bad) = reader.read_exact(&mut action_with_answer).await {
-                    board::serial_log("Can't obtain action & answer from server, breaking...");
-                    return Err(bad);
-                }
-                Ok(())
-            })(),
-            // Watch for machine's state.
-            (async || {
-                let mut current_state: Level;
-                loop {
-                    if let Some(state) = reported_state {
-                        // Already reported, wait for a new one.
-                        if machine_state.get_level() == state {
-                            machine_state.wait_for_any_edge().await;
-                        }
-                    }
+        };
 
-                    // Store new state to variable.
-                    current_state = machine_state.get_level();
-                    reported_state = Some(current_state);
-
-                    // Check and send the new state.
-                    let write_state: u8;
-                    if current_state == Level::High {
-                        write_state = 1;
-                    } else {
-                        write_state = 0;
-                    }
-                    if let Err(bad) = writer.write(&[write_state]).await {
-                        board::serial_log("Can't obtain action & answer from server, breaking...");
-                        return Err(bad);
-                    }
-                }
-            })()
+        // Readings drift without a change of state, they go out every once in a while too.
+        let next_reading = async {
+            if analog {
+                Timer::after_secs(READING_INTERVAL_SECS).await;
+            } else {
+                core::future::pending::<()>().await;
+            }
+        };
+        // Only waiting in here, for a frame, a change on any channel or a magic packet. Whichever
+        // loses the race loses nothing, the decoder keeps what came of a frame so far.
+        let next = select(
+            next_frame(&mut reader, &mut decoder, &mut frame),
+            select3(
+                relay::next_change(&mut powers[..watched]),
+                next_reading,
+                wake_on_lan::next_request()
+            )
         ).await;
 
-        match bad_cases {
-            Either::First(bad) => if bad.is_err() {
+        let length = match next {
+            Either::First(Ok(length)) => length,
+            Either::First(Err(_)) => {
+                board::serial_log("Can't obtain a frame from server, breaking...");
                 break;
             }
-            Either::Second(bad) => if bad.is_err() {
-                break;
+            // The state goes out like after any other press, on the next round.
+            Either::Second(Either3::Third(channel)) => {
+                wake_on_lan::power_on(powers, channel, &timings).await;
+                continue;
             }
-        }
-
-        let hash_answer = Hash::from_bytes(
-            action_with_answer[1..ANSWER_LENGTH + 1].try_into().unwrap()
-        );
+            Either::Second(_) => {
+                continue;
+            }
+        };
 
-        // Compare hashes.
-        if expected_answer != hash_answer {
-            board::serial_log("Server failed the challenge, folding...");
+        // Forged, replayed or reordered frames all fail here.
+        let Ok(payload) = opener.open(&mut frame[..length]) else {
+            board::serial_log("A frame from the server doesn't check out, folding...");
+            attempts::failed(server_address, "frames").await;
             faults += 1;
+            // Whatever it asked for didn't happen, say so.
+            if agreed.capabilities.contains(Capabilities::COMMAND_ACKS) {
+                let ack = NodeMessage::Ack { id: 0, result: CommandResult::AuthFailed };
+                if let Err(_) = send(&mut writer, &mut sealer, ack).await {
+                    board::serial_log("Can't acknowledge the command, breaking...");
+                    break;
+                }
+            }
             continue;
-        }
-
-        // Get action.
-        let action = action_with_answer[0];
+        };
 
-        // Execute the action.
-        // Power on.
-        if action == 1 {
-            if machine_state.get_level() == Level::Low {
-                power_switch.set_level(ACTIVATE_RELAY);
-                Timer::after_millis(500).await;
-                power_switch.set_level(DEACTIVATE_RELAY);
-            } else {
-                // No don't press it when it's already on.
-                // Send back already on state.
-                if let Err(_) = writer.write(&[1]).await {
-                    board::serial_log("Can't obtain action & answer from server, breaking...");
+        let Ok(message) = ServerMessage::decode(payload, agreed.capabilities) else {
+            board::serial_log("The server sent something unknown, skipping it");
+            // It checked out, so it's likely a command this node can't make out. Nothing was done.
+            if agreed.capabilities.contains(Capabilities::COMMAND_ACKS) {
+                let ack = NodeMessage::Ack { id: 0, result: CommandResult::UnknownAction };
+                if let Err(_) = send(&mut writer, &mut sealer, ack).await {
+                    board::serial_log("Can't acknowledge the command, breaking...");
                     break;
                 }
             }
             continue;
-        }
-        // Power off.
-        if action == 2 {
-            if machine_state.get_level() == Level::High {
-                power_switch.set_level(ACTIVATE_RELAY);
-                Timer::after_millis(500).await;
-                power_switch.set_level(DEACTIVATE_RELAY);
-            } else {
-                // No don't press it when it's already off.
-                // Send back already on state.
-                if let Err(_) = writer.write(&[1]).await {
-                    board::serial_log("Can't obtain action & answer from server, breaking...");
+        };
+
+        match message {
+            // From a server that takes no acks.
+            ServerMessage::Command { action } => {
+                let Some(power) = powers.first_mut() else {
+                    continue;
+                };
+                // Execute the action.
+                if let Outcome::AlreadyInState { powered } = pibow_power::execute(action, power, &timings).await {
+                    // Nothing pressed, send back the latest state to sync the server.
+                    let state = NodeMessage::machine_state(powered);
+                    if let Err(_) = send(&mut writer, &mut sealer, state).await {
+                        board::serial_log("Can't report the machine's state, breaking...");
+                        break;
+                    }
+                }
+            }
+            ServerMessage::Request { id, action, channel, overrides } => {
+                let power = powers.get_mut(channel as usize);
+                let result = match (power, timings.with_overrides(action, &overrides)) {
+                    (None, _) => {
+                        board::serial_log("The server asked for a channel that isn't wired");
+                        CommandResult::UnknownChannel
+                    }
+                    (Some(_), Err(_)) => {
+                        board::serial_log("The server's timings are out of bounds, nothing pressed");
+                        CommandResult::InvalidTiming
+                    }
+                    (Some(power), Ok(timings)) => {
+                        pibow_power::execute(action, power, &timings).await.into()
+                    }
+                };
+                if result == CommandResult::Failed {
+                    board::serial_log("The machine didn't follow the switch");
+                }
+                if let Err(_) = send(&mut writer, &mut sealer, NodeMessage::Ack { id, result }).await {
+                    board::serial_log("Can't acknowledge the command, breaking...");
+                    break;
+                }
+            }
+            ServerMessage::Error(code) => {
+                let mut notice: String<64> = String::new();
+                let _ = write!(notice, "The server is hanging up: {code:?}");
+                board::serial_log(&notice);
+                hung_up = true;
+                break;
+            }
+            ServerMessage::RotateKey { wrapped, grace_secs } => {
+                let new_key = handshake.unwrap_key(&wrapped);
+                let saved = keyring::rotate(new_key, grace_secs).await.is_ok();
+                board::serial_log(
+                    if saved {
+                        "The server rotated the secret key"
+                    } else {
+                        "Can't save the rotated key, keeping the old one"
+                    }
+                );
+                if let Err(_) = send(&mut writer, &mut sealer, NodeMessage::KeyRotated { saved }).await {
+                    board::serial_log("Can't acknowledge the rotated key, breaking...");
                     break;
                 }
             }
-            continue;
-        }
-        // Reset
-        if action == 3 {
-            reset_switch.set_level(ACTIVATE_RELAY);
-            Timer::after_millis(500).await;
-            reset_switch.set_level(DEACTIVATE_RELAY);
         }
     }
 
-    power_switch.set_level(DEACTIVATE_RELAY);
-    reset_switch.set_level(DEACTIVATE_RELAY);
+    for power in powers.iter_mut() {
+        power.release();
+    }
     let _ = socket.flush().await;
     socket.abort();
     socket.close();
+
+    if hung_up {
+        Timer::after_secs(HUNG_UP_RETRY_SECS).await;
+    }
 }
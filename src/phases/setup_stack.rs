@@ -2,9 +2,10 @@ use defmt::*;
 use embassy_executor::Spawner;
 use embassy_net::{ Config, Stack, StackResources };
 use embassy_net_wiznet::Device;
-use embassy_rp::clocks::RoscRng;
 use static_cell::StaticCell;
 
+use crate::entropy;
+
 #[embassy_executor::task]
 async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'static>>) {
     runner.run().await
@@ -12,7 +13,7 @@ async fn net_task(mut runner: embassy_net::Runner<'static, cyw43::NetDriver<'sta
 
 pub async fn invoke(spawner: Spawner, net_device: Device<'static>) -> Stack<'static> {
     let config = Config::dhcpv4(Default::default());
-    let seed = RoscRng.next_u64();
+    let seed = entropy::net_seed().await;
 
     // Init network stack
     static RESOURCES: StaticCell<StackResources<6>> = StaticCell::new();
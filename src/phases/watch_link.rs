@@ -0,0 +1,24 @@
+use embassy_net::Stack;
+use embassy_time::Timer;
+use portable_atomic::{ AtomicU32, Ordering };
+
+use crate::consts::LINK_CHECK_MILLIS;
+
+// How many times the Wifi had to be rejoined since boot, reported to the server on every session.
+static RECONNECTS: AtomicU32 = AtomicU32::new(0);
+
+pub fn reconnects() -> u32 {
+    RECONNECTS.load(Ordering::Relaxed)
+}
+
+pub fn count_reconnect() {
+    RECONNECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+// Returns once the link or the DHCP config is gone.
+// cyw43 reports a dropped association as the link going down, so this covers the join state too.
+pub async fn invoke(stack: Stack<'static>) {
+    while stack.is_link_up() && stack.is_config_up() {
+        Timer::after_millis(LINK_CHECK_MILLIS).await;
+    }
+}
@@ -0,0 +1,405 @@
+use core::cell::{ Cell, RefCell };
+
+use embassy_futures::select::{ select3, select4 };
+use embassy_rp::{
+    adc::{ self, Adc },
+    gpio::{ AnyPin, Input, Level, Output, Pull },
+    peripherals::{ ADC, PIN_26, PIN_27, PIN_28 },
+    Peri,
+};
+use embassy_sync::{
+    blocking_mutex::{ raw::CriticalSectionRawMutex, Mutex as BlockingMutex },
+    mutex::Mutex,
+};
+use embassy_time::{ with_timeout, Duration, Instant, Timer };
+use pibow_config::{ adc_pin, AnalogSense, Channel, NodeConfig, MAX_CHANNELS };
+use pibow_power::{
+    analog::{ self, Comparator, Thresholds },
+    sense::{ SenseSettings, Sensor },
+    PowerInterface,
+    Timings,
+};
+use pibow_protocol::{ MachineState, Reading };
+use portable_atomic::{ AtomicU8, Ordering };
+
+// How often to look at a state pin that's quiet, in case an edge slipped by between two waits.
+const IDLE_SAMPLE_MS: u64 = 250;
+// How often to sample an analog state pin, it has no edges to wait for.
+const ANALOG_SAMPLE_MS: u64 = 20;
+
+// The relays wired to a machine's front panel header.
+// Shared, so the USB shell can press them too. The shell's presses queue up behind each other,
+// the server's get told the relays are busy.
+struct Relays {
+    power_switch: Output<'static>,
+    reset_switch: Output<'static>,
+    active: Level,
+    idle: Level,
+}
+
+// By channel, each one locked on its own so a press on one machine doesn't hold up the others.
+static RELAYS: [Mutex<CriticalSectionRawMutex, Option<Relays>>; MAX_CHANNELS] = [
+    const { Mutex::new(None) };
+    MAX_CHANNELS
+];
+
+// Every machine's state as last sensed, as its byte on the wire. Unknown until the pins settle.
+static MACHINE_STATES: [AtomicU8; MAX_CHANNELS] = [
+    const { AtomicU8::new(MachineState::Unknown.to_byte()) };
+    MAX_CHANNELS
+];
+
+pub fn last_machine_state(channel: usize) -> MachineState {
+    MachineState::from(MACHINE_STATES[channel].load(Ordering::Relaxed))
+}
+
+// Every machine's last reading, for the ones sensed through the ADC.
+static READINGS: [BlockingMutex<CriticalSectionRawMutex, Cell<Option<Reading>>>; MAX_CHANNELS] = [
+    const { BlockingMutex::new(Cell::new(None)) };
+    MAX_CHANNELS
+];
+
+pub fn last_reading(channel: usize) -> Option<Reading> {
+    READINGS[channel].lock(Cell::get)
+}
+
+// The one ADC, every channel's analog pins share it. A conversion takes a couple of microseconds,
+// short enough to wait for it with the lock held.
+static ADC_UNIT: BlockingMutex<CriticalSectionRawMutex, RefCell<Option<Adc<'static, adc::Blocking>>>> =
+    BlockingMutex::new(RefCell::new(None));
+
+pub fn init_adc(adc: Peri<'static, ADC>) {
+    let adc = Adc::new_blocking(adc, adc::Config::default());
+    ADC_UNIT.lock(|unit| *unit.borrow_mut() = Some(adc));
+}
+
+// None when the ADC isn't set up or the conversion failed.
+fn read_millivolts(channel: &mut adc::Channel<'static>) -> Option<u16> {
+    ADC_UNIT.lock(|unit| {
+        let raw = unit.borrow_mut().as_mut()?.blocking_read(channel).ok()?;
+        Some(analog::millivolts(raw))
+    })
+}
+
+// The ADC wants the pin's own type. Taking it out of `pins` first is what makes stealing it sound,
+// nothing else can have it then.
+fn adc_channel(pins: &mut Pins, pin: u8) -> Option<adc::Channel<'static>> {
+    if !adc_pin(pin) {
+        return None;
+    }
+    pins[usize::from(pin)].take()?;
+    // No pull, it would load whatever divider the pin sits on.
+    let channel = unsafe {
+        match pin {
+            26 => adc::Channel::new_pin(PIN_26::steal(), Pull::None),
+            27 => adc::Channel::new_pin(PIN_27::steal(), Pull::None),
+            _ => adc::Channel::new_pin(PIN_28::steal(), Pull::None),
+        }
+    };
+    Some(channel)
+}
+
+// A channel's next state change, never for a channel that isn't wired.
+async fn change(power: Option<&mut impl PowerInterface>) {
+    match power {
+        Some(power) => power.wait_for_change().await,
+        None => core::future::pending().await,
+    }
+}
+
+// The next state change on any of the machines, MAX_CHANNELS is 4.
+pub async fn next_change(powers: &mut [impl PowerInterface]) {
+    let mut watching = powers.iter_mut();
+    let (a, b) = (watching.next(), watching.next());
+    let (c, d) = (watching.next(), watching.next());
+    select4(change(a), change(b), change(c), change(d)).await;
+}
+
+// The GPIOs a channel can take, by number, see pibow_config::usable_pin.
+pub type Pins = [Option<Peri<'static, AnyPin>>; 29];
+
+// The config's timings, execute keeps them within bounds.
+pub fn timings(config: &NodeConfig) -> Timings {
+    Timings {
+        power_press_ms: config.power_press_ms,
+        reset_press_ms: config.reset_press_ms,
+        force_off_hold_ms: config.force_off_hold_ms,
+        settle_ms: config.settle_ms,
+        verify_timeout_ms: config.verify_timeout_ms,
+        retry_press: config.retry_press,
+    }
+}
+
+// The config's state pin filtering, the sensor keeps it within bounds.
+pub fn sense_settings(config: &NodeConfig) -> SenseSettings {
+    SenseSettings {
+        debounce_ms: config.debounce_ms,
+        steady_ms: config.steady_ms,
+        blink_window_ms: config.blink_window_ms,
+    }
+}
+
+// Both fail when the channel isn't wired yet, nothing gets pressed then.
+pub async fn press_power(channel: usize, press_ms: u32) -> Result<(), ()> {
+    pulse(channel, |relays| &mut relays.power_switch, press_ms).await
+}
+
+pub async fn press_reset(channel: usize, press_ms: u32) -> Result<(), ()> {
+    pulse(channel, |relays| &mut relays.reset_switch, press_ms).await
+}
+
+async fn pulse(
+    channel: usize,
+    switch: impl FnOnce(&mut Relays) -> &mut Output<'static>,
+    press_ms: u32
+) -> Result<(), ()> {
+    let relays = RELAYS.get(channel).ok_or(())?;
+    let mut relays = relays.lock().await;
+    let relays = relays.as_mut().ok_or(())?;
+    let (active, idle) = (relays.active, relays.idle);
+    let switch = switch(relays);
+
+    switch.set_level(active);
+    Timer::after_millis(press_ms as u64).await;
+    switch.set_level(idle);
+    Ok(())
+}
+
+// The pin on the machine's power LED, read as a level or through the ADC.
+enum StatePin {
+    Digital(Input<'static>),
+    Analog(adc::Channel<'static>, Comparator),
+}
+
+impl StatePin {
+    // None while an analog pin has only read between its thresholds.
+    fn level(&mut self) -> Option<bool> {
+        match self {
+            StatePin::Digital(input) => Some(input.get_level() == Level::High),
+            StatePin::Analog(channel, comparator) => comparator.sample(read_millivolts(channel)?),
+        }
+    }
+
+    // Until the level might have changed.
+    async fn wait_for_edge(&mut self) {
+        match self {
+            StatePin::Digital(input) => input.wait_for_any_edge().await,
+            StatePin::Analog(..) => Timer::after_millis(ANALOG_SAMPLE_MS).await,
+        }
+    }
+
+    async fn wait_for_low(&mut self) {
+        match self {
+            StatePin::Digital(input) => input.wait_for_low().await,
+            StatePin::Analog(..) => {
+                while self.level() != Some(false) {
+                    Timer::after_millis(ANALOG_SAMPLE_MS).await;
+                }
+            }
+        }
+    }
+}
+
+// One channel's relays, plus its machine's power LED as the state pin and the sleep pin along
+// with the level it reads while suspended, when it has one. A machine sensed through the ADC can
+// have a shunt in its supply too, with its resistance in milliohms.
+pub struct GpioPower {
+    channel: usize,
+    machine_state: StatePin,
+    sleep_state: Option<(Input<'static>, Level)>,
+    shunt: Option<(adc::Channel<'static>, u16)>,
+    sensor: Sensor,
+    reading: Option<Reading>,
+}
+
+impl GpioPower {
+    // Takes the channel's pins out of `pins`, None when one of them is gone or not there at all.
+    pub async fn wire(
+        index: usize,
+        channel: &Channel,
+        settings: SenseSettings,
+        pins: &mut Pins
+    ) -> Option<Self> {
+        let [power_pin, reset_pin, state_pin] = channel.pins().map(usize::from);
+        let sleep_pin = channel.sleep_pin.map(usize::from);
+        let shunt_pin = channel.shunt.map(|shunt| usize::from(shunt.pin));
+        let all_there = index < MAX_CHANNELS
+            && [power_pin, reset_pin, state_pin]
+                .iter()
+                .chain(&sleep_pin)
+                .chain(&shunt_pin)
+                .all(|pin| pins.get(*pin).is_some_and(Option::is_some));
+        if !all_there {
+            return None;
+        }
+
+        let idle = Level::from(!channel.active_high);
+        *RELAYS[index].lock().await = Some(Relays {
+            power_switch: Output::new(pins[power_pin].take()?, idle),
+            reset_switch: Output::new(pins[reset_pin].take()?, idle),
+            active: Level::from(channel.active_high),
+            idle,
+        });
+        let machine_state = match channel.analog {
+            Some(AnalogSense { on_mv, off_mv }) => {
+                let comparator = Comparator::new(Thresholds { on_mv, off_mv });
+                StatePin::Analog(adc_channel(pins, channel.state_pin)?, comparator)
+            }
+            None => StatePin::Digital(Input::new(pins[state_pin].take()?, Pull::Down)),
+        };
+        // Pulled away from asleep, so a loose wire reads as awake.
+        let sleep_state = match sleep_pin {
+            Some(pin) => {
+                let pull = if channel.sleep_active_high { Pull::Down } else { Pull::Up };
+                Some((Input::new(pins[pin].take()?, pull), Level::from(channel.sleep_active_high)))
+            }
+            None => None,
+        };
+        let shunt = match channel.shunt {
+            Some(shunt) => Some((adc_channel(pins, shunt.pin)?, shunt.milliohms)),
+            None => None,
+        };
+        Some(GpioPower {
+            channel: index,
+            machine_state,
+            sleep_state,
+            shunt,
+            sensor: Sensor::new(settings),
+            reading: None,
+        })
+    }
+
+    // Feed the sensor the pins' levels, returns whether the state changed.
+    fn sense(&mut self) -> bool {
+        // Nothing to feed it until an analog pin crosses a threshold.
+        let Some(level) = self.machine_state.level() else {
+            return false;
+        };
+        let asleep = self.sleep_state
+            .as_ref()
+            .is_some_and(|(pin, asserted)| pin.get_level() == *asserted);
+        let now = Instant::now().as_millis();
+        let changed = self.sensor.sample_with_sleep(level, asleep, now).is_some();
+        MACHINE_STATES[self.channel].store(u8::from(self.sensor.state()), Ordering::Relaxed);
+
+        if let StatePin::Analog(_, comparator) = &self.machine_state {
+            let milliamps = self.shunt
+                .as_mut()
+                .and_then(|(channel, milliohms)| {
+                    analog::shunt_milliamps(read_millivolts(channel)?, *milliohms)
+                });
+            self.reading = comparator.reading(milliamps);
+            READINGS[self.channel].lock(|reading| reading.set(self.reading));
+        }
+        changed
+    }
+}
+
+impl PowerInterface for GpioPower {
+    // A GpioPower only exists for a wired channel.
+    async fn press_power(&mut self, press_ms: u32) {
+        let _ = press_power(self.channel, press_ms).await;
+    }
+
+    async fn press_reset(&mut self, press_ms: u32) {
+        let _ = press_reset(self.channel, press_ms).await;
+    }
+
+    async fn hold_power(&mut self, hold_ms: u32) -> bool {
+        let mut relays = RELAYS[self.channel].lock().await;
+        let Some(relays) = relays.as_mut() else {
+            return false;
+        };
+
+        relays.power_switch.set_level(relays.active);
+        // Let go as soon as the power LED does, or once the hold runs out.
+        let _ = with_timeout(
+            Duration::from_millis(hold_ms as u64),
+            self.machine_state.wait_for_low()
+        ).await;
+        relays.power_switch.set_level(relays.idle);
+
+        // The LED going out takes a moment to count as OFF.
+        let decision_ms = self.sensor.settings().decision_ms();
+        self.wait_for_state(false, decision_ms).await
+    }
+
+    // The sensed state, or the bare pin while there's nothing sensed to go by yet.
+    fn is_on(&mut self) -> bool {
+        match self.state().powered() {
+            Some(powered) => powered,
+            None => self.machine_state.level().unwrap_or(false),
+        }
+    }
+
+    fn state(&mut self) -> MachineState {
+        self.sense();
+        self.sensor.state()
+    }
+
+    // As of the last sample, state() takes a new one.
+    fn reading(&mut self) -> Option<Reading> {
+        self.reading
+    }
+
+    // The shell holds the relays while it presses a switch.
+    fn busy(&mut self) -> bool {
+        RELAYS[self.channel].try_lock().is_err()
+    }
+
+    // Only a clean change of state, the pin itself changes far more often.
+    async fn wait_for_change(&mut self) {
+        loop {
+            let wake_at = match self.sensor.deadline() {
+                Some(deadline) => Instant::from_millis(deadline),
+                None => Instant::now() + Duration::from_millis(IDLE_SAMPLE_MS),
+            };
+            let sleep_edge = async {
+                match self.sleep_state.as_mut() {
+                    Some((pin, _)) => pin.wait_for_any_edge().await,
+                    None => core::future::pending().await,
+                }
+            };
+            select3(self.machine_state.wait_for_edge(), sleep_edge, Timer::at(wake_at)).await;
+            if self.sense() {
+                return;
+            }
+        }
+    }
+
+    async fn wait_for_state(&mut self, powered: bool, timeout_ms: u32) -> bool {
+        let state = async {
+            while self.is_on() != powered {
+                self.wait_for_change().await;
+            }
+        };
+        let _ = with_timeout(Duration::from_millis(timeout_ms as u64), state).await;
+        self.is_on() == powered
+    }
+
+    // A sleeping machine has power, waking it up has to wait for the state itself.
+    async fn wait_for_machine_state(&mut self, state: MachineState, timeout_ms: u32) -> bool {
+        let reached = async {
+            while self.state() != state {
+                self.wait_for_change().await;
+            }
+        };
+        let _ = with_timeout(Duration::from_millis(timeout_ms as u64), reached).await;
+        self.state() == state
+    }
+
+    async fn delay(&mut self, ms: u32) {
+        Timer::after_millis(ms as u64).await;
+    }
+
+    fn release(&mut self) {
+        // A press that got cut short drops its lock along with it, so this only misses
+        // when a press is legitimately going on somewhere else.
+        if let Ok(mut relays) = RELAYS[self.channel].try_lock()
+            && let Some(relays) = relays.as_mut()
+        {
+            relays.power_switch.set_level(relays.idle);
+            relays.reset_switch.set_level(relays.idle);
+        }
+    }
+}
@@ -0,0 +1,650 @@
+use core::fmt::Write as _;
+
+use base64::{ engine::general_purpose::STANDARD, Engine };
+use embassy_net::Stack;
+use embassy_sync::{ blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal };
+use embassy_time::{ Instant, Timer };
+use heapless::{ String, Vec };
+use pibow_config::{ channels_fit, AnalogSense, Channel, NodeConfig, Shunt, WakeOnLan, MAX_CHANNELS };
+use pibow_power::{
+    analog::{ Thresholds, ADC_REFERENCE_MV },
+    sense::{
+        BLINK_WINDOW_MAX_MS,
+        BLINK_WINDOW_MIN_MS,
+        DEBOUNCE_MAX_MS,
+        STEADY_MAX_MS,
+        STEADY_MIN_MS,
+    },
+    FORCE_OFF_MAX_MS,
+    FORCE_OFF_MIN_MS,
+    PRESS_MAX_MS,
+    PRESS_MIN_MS,
+    SETTLE_MAX_MS,
+    VERIFY_MAX_MS,
+    VERIFY_MIN_MS,
+};
+use pibow_portal::{ access_point_name, access_point_password };
+use pibow_protocol::{ MacAddress, MachineState };
+use pibow_wake::parse_hex_address;
+
+use crate::{
+    entropy,
+    keyring,
+    phases::{ board, server_contact, watch_link },
+    relay,
+    storage,
+};
+
+const HELP: &[&str] = &[
+    "Commands:",
+    "  wifi set <ssid> <password>   Store the Wifi credentials, quote them if they have spaces",
+    "  key set <base64key>          Store this node's key, from `issue <mac>` on the server",
+    "  encryption on|off            Ask the server for an encrypted session or a plain one",
+    "  retry on|off                 Press the power switch again when the machine didn't follow",
+    "  timing <name> <ms>           Set power, reset (the presses), settle, verify or force-off",
+    "  sense <name> <ms>            Set how the state pins get filtered: debounce, steady or blink",
+    "  channel <n> <name> <power> <reset> <state> high|low [<sleep>[:high|low]]",
+    "                               Wire machine n to those pins, n one past the last adds one.",
+    "                               The sleep pin reads low while suspended, unless it says high",
+    "  channel remove               Drop the last machine, the first one always stays",
+    "  analog <n> <on> <off>|off    Read machine n's state pin through the ADC, ON from <on> mV up",
+    "                               and OFF from <off> mV down. It has to be GPIO 26 to 28 then",
+    "  shunt <n> <pin> <mohm>|off   Measure machine n's current across a shunt on GPIO 26 to 28",
+    "  wol <n> <mac> [<password>]|off",
+    "                               Power machine n ON for magic packets to that MAC on UDP 7 or 9,",
+    "                               only with that SecureOn password (like 01:02:03:04:05:06) if given",
+    "  config show                  Show the config this node is running with",
+    "  status                       Show the link, the session and the machines' states",
+    "  portal                       Open the setup access point, its password is in `config show`",
+    "  press power|reset [n]        Press a switch on machine n, the first one by default",
+    "  reboot                       Restart the node, saved changes apply after that",
+    "  help                         Show this",
+];
+
+// Only the shell opens the setup access point on a node that has a Wifi, main takes it from here.
+static PORTAL_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+pub async fn portal_requested() {
+    PORTAL_REQUEST.wait().await;
+}
+
+// A line-oriented shell on the USB serial port, for provisioning and debugging without a rebuild.
+#[embassy_executor::task]
+pub async fn shell_task(
+    stack: Stack<'static>,
+    config: &'static NodeConfig,
+    mac_address: MacAddress
+) {
+    loop {
+        let line = board::next_shell_line().await;
+        reply(format_args!("> {line}")).await;
+
+        let Some(words) = split_words(&line) else {
+            board::serial_reply("Too many words, or an unclosed quote").await;
+            continue;
+        };
+
+        match words.as_slice() {
+            [] => {}
+            ["help"] => {
+                for line in HELP {
+                    board::serial_reply(line).await;
+                }
+            }
+            ["wifi", "set", network, password] => set_wifi(network, password).await,
+            ["key", "set", key] => set_key(key).await,
+            ["encryption", "on"] => set_encryption(true).await,
+            ["encryption", "off"] => set_encryption(false).await,
+            ["retry", "on"] => set_retry_press(true).await,
+            ["retry", "off"] => set_retry_press(false).await,
+            ["timing", name, ms] => set_timing(name, ms).await,
+            ["sense", name, ms] => set_sensing(name, ms).await,
+            ["config", "show"] => show_config(config, &mac_address).await,
+            ["channel", "remove"] => remove_channel().await,
+            ["channel", index, name, power, reset, state, polarity, sleep @ ..] => {
+                set_channel(index, name, [*power, *reset, *state], polarity, sleep).await;
+            }
+            ["analog", index, thresholds @ ..] => set_analog(index, thresholds).await,
+            ["shunt", index, shunt @ ..] => set_shunt(index, shunt).await,
+            ["wol", index, wake @ ..] => set_wake_on_lan(index, wake).await,
+            ["status"] => show_status(stack, config).await,
+            ["portal"] => {
+                board::serial_reply("Opening the setup access point...").await;
+                PORTAL_REQUEST.signal(());
+            }
+            ["press", switch @ ("power" | "reset"), channel @ ..] => {
+                press(config, switch, channel).await;
+            }
+            ["reboot"] => {
+                board::serial_reply("Rebooting...").await;
+                // Give the logger a moment to get the line out.
+                Timer::after_millis(200).await;
+                cortex_m::peripheral::SCB::sys_reset();
+            }
+            _ => board::serial_reply("Unknown command, try `help`").await,
+        }
+    }
+}
+
+async fn set_wifi(network: &str, password: &str) {
+    let mut stored = storage::load().await;
+    let (Ok(network), Ok(password)) = (String::try_from(network), String::try_from(password)) else {
+        board::serial_reply("The network name is 32 bytes at most, the password 64").await;
+        return;
+    };
+    stored.wifi_network = network;
+    stored.wifi_password = password;
+    save(&stored).await;
+}
+
+async fn set_key(key: &str) {
+    // Room for a bit more than a key, so a longer one gets told apart from a broken one.
+    let mut decoded = [0_u8; 48];
+    let key = match STANDARD.decode_slice(key, &mut decoded) {
+        Ok(32) => &decoded[..32],
+        Ok(_) => {
+            board::serial_reply("The secret key must be 32 bytes").await;
+            return;
+        }
+        Err(_) => {
+            board::serial_reply("The secret key isn't valid base64").await;
+            return;
+        }
+    };
+
+    let mut stored = storage::load().await;
+    stored.secret_key.copy_from_slice(key);
+    save(&stored).await;
+}
+
+async fn set_encryption(encrypt_session: bool) {
+    let mut stored = storage::load().await;
+    stored.encrypt_session = encrypt_session;
+    save(&stored).await;
+}
+
+async fn set_retry_press(retry_press: bool) {
+    let mut stored = storage::load().await;
+    stored.retry_press = retry_press;
+    save(&stored).await;
+}
+
+async fn set_timing(name: &str, ms: &str) {
+    let (min, max) = match name {
+        "power" | "reset" => (PRESS_MIN_MS, PRESS_MAX_MS),
+        "settle" => (0, SETTLE_MAX_MS),
+        "verify" => (VERIFY_MIN_MS, VERIFY_MAX_MS),
+        "force-off" => (FORCE_OFF_MIN_MS, FORCE_OFF_MAX_MS),
+        _ => {
+            board::serial_reply("The timings are power, reset, settle, verify and force-off").await;
+            return;
+        }
+    };
+    let Some(ms) = ms.parse().ok().filter(|ms| (min..=max).contains(ms)) else {
+        reply(format_args!("The {name} timing is {min} to {max} ms")).await;
+        return;
+    };
+
+    let mut stored = storage::load().await;
+    match name {
+        "power" => stored.power_press_ms = ms,
+        "reset" => stored.reset_press_ms = ms,
+        "settle" => stored.settle_ms = ms,
+        "verify" => stored.verify_timeout_ms = ms,
+        _ => stored.force_off_hold_ms = ms,
+    }
+    save(&stored).await;
+}
+
+async fn set_sensing(name: &str, ms: &str) {
+    let (min, max) = match name {
+        "debounce" => (0, DEBOUNCE_MAX_MS),
+        "steady" => (STEADY_MIN_MS, STEADY_MAX_MS),
+        "blink" => (BLINK_WINDOW_MIN_MS, BLINK_WINDOW_MAX_MS),
+        _ => {
+            board::serial_reply("The sensing settings are debounce, steady and blink").await;
+            return;
+        }
+    };
+    let Some(ms) = ms.parse().ok().filter(|ms| (min..=max).contains(ms)) else {
+        reply(format_args!("The {name} setting is {min} to {max} ms")).await;
+        return;
+    };
+
+    let mut stored = storage::load().await;
+    match name {
+        "debounce" => stored.debounce_ms = ms,
+        "steady" => stored.steady_ms = ms,
+        _ => stored.blink_window_ms = ms,
+    }
+    save(&stored).await;
+}
+
+async fn set_channel(index: &str, name: &str, pins: [&str; 3], polarity: &str, sleep: &[&str]) {
+    let mut stored = storage::load().await;
+    let Some(index) = index.parse::<usize>().ok().filter(|index| *index <= stored.channels.len()) else {
+        reply(format_args!("Machines go from 0 to {}, in order", stored.channels.len())).await;
+        return;
+    };
+    if index == MAX_CHANNELS {
+        reply(format_args!("A node drives {MAX_CHANNELS} machines at most")).await;
+        return;
+    }
+    let Ok(name) = String::try_from(name) else {
+        board::serial_reply("The name is 16 bytes at most").await;
+        return;
+    };
+    let [Ok(power_pin), Ok(reset_pin), Ok(state_pin)] = pins.map(str::parse::<u8>) else {
+        board::serial_reply("The pins are GPIO numbers").await;
+        return;
+    };
+    let active_high = match polarity {
+        "high" => true,
+        "low" => false,
+        _ => {
+            board::serial_reply("The relays switch on high or low").await;
+            return;
+        }
+    };
+    let sleep = match sleep {
+        [] => Some((None, false)),
+        [sleep] => match sleep.split_once(':').unwrap_or((*sleep, "low")) {
+            (pin, "low") => pin.parse().ok().map(|pin| (Some(pin), false)),
+            (pin, "high") => pin.parse().ok().map(|pin| (Some(pin), true)),
+            _ => None,
+        },
+        _ => None,
+    };
+    let Some((sleep_pin, sleep_active_high)) = sleep else {
+        board::serial_reply("The sleep pin goes like 17 or 17:high").await;
+        return;
+    };
+
+    // A machine that's wired again keeps how it gets sensed.
+    let existing = stored.channels.get(index);
+    let channel = Channel {
+        name,
+        power_pin,
+        reset_pin,
+        state_pin,
+        active_high,
+        sleep_pin,
+        sleep_active_high,
+        analog: existing.and_then(|existing| existing.analog),
+        shunt: existing.and_then(|existing| existing.shunt),
+        wake_on_lan: existing.and_then(|existing| existing.wake_on_lan),
+    };
+    let mut channels = stored.channels.clone();
+    match channels.get_mut(index) {
+        Some(existing) => *existing = channel,
+        None => {
+            let _ = channels.push(channel);
+        }
+    }
+    if !channels_fit(&channels) {
+        let misfit = "Those pins are taken, wired to the Wifi chip or can't be read as analog";
+        board::serial_reply(misfit).await;
+        return;
+    }
+    // Older firmware only knows the one polarity, keep it with the first machine.
+    if let Some(first) = channels.first() {
+        stored.relay_active_high = first.active_high;
+    }
+    stored.channels = channels;
+    save(&stored).await;
+}
+
+async fn set_analog(index: &str, thresholds: &[&str]) {
+    let analog = match thresholds {
+        ["off"] => Some(None),
+        [on_mv, off_mv] => match (on_mv.parse(), off_mv.parse()) {
+            (Ok(on_mv), Ok(off_mv)) if (Thresholds { on_mv, off_mv }).valid() => {
+                Some(Some(AnalogSense { on_mv, off_mv }))
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    let Some(analog) = analog else {
+        reply(
+            format_args!("The thresholds are 0 to {ADC_REFERENCE_MV} mV, the OFF one no higher than ON")
+        ).await;
+        return;
+    };
+
+    let misfit = "The state pin has to be a free GPIO 26 to 28 for that";
+    update_channel(index, misfit, |channel| {
+        channel.analog = analog;
+        // No voltage for the current to go out with.
+        if analog.is_none() {
+            channel.shunt = None;
+        }
+    }).await;
+}
+
+async fn set_shunt(index: &str, shunt: &[&str]) {
+    let shunt = match shunt {
+        ["off"] => Some(None),
+        [pin, milliohms] => match (pin.parse(), milliohms.parse()) {
+            (Ok(pin), Ok(milliohms)) if milliohms > 0 => Some(Some(Shunt { pin, milliohms })),
+            _ => None,
+        },
+        _ => None,
+    };
+    let Some(shunt) = shunt else {
+        board::serial_reply("The shunt goes like 27 20, its GPIO and its resistance in milliohms").await;
+        return;
+    };
+
+    let misfit = "The shunt needs a free GPIO 26 to 28, and the machine analog sensing";
+    update_channel(index, misfit, |channel| channel.shunt = shunt).await;
+}
+
+async fn set_wake_on_lan(index: &str, wake: &[&str]) {
+    let wake_on_lan = match wake {
+        ["off"] => Some(None),
+        [mac_address] => parse_hex_address(mac_address)
+            .map(|mac_address| Some(WakeOnLan { mac_address, password: None })),
+        [mac_address, password] => match (parse_hex_address(mac_address), parse_hex_address(password)) {
+            (Some(mac_address), Some(password)) => {
+                Some(Some(WakeOnLan { mac_address, password: Some(password) }))
+            }
+            _ => None,
+        },
+        _ => None,
+    };
+    let Some(wake_on_lan) = wake_on_lan else {
+        board::serial_reply("The MAC address and the password go like 02:00:5e:10:00:01").await;
+        return;
+    };
+
+    // Takes no pins, it always fits.
+    update_channel(index, "", |channel| channel.wake_on_lan = wake_on_lan).await;
+}
+
+// Change one stored machine, as long as every channel still fits after.
+async fn update_channel(index: &str, misfit: &str, update: impl FnOnce(&mut Channel)) {
+    let mut stored = storage::load().await;
+    let mut channels = stored.channels.clone();
+    let count = channels.len();
+    let Some(channel) = index.parse::<usize>().ok().and_then(|index| channels.get_mut(index)) else {
+        no_such_machine(count).await;
+        return;
+    };
+    update(channel);
+    if !channels_fit(&channels) {
+        board::serial_reply(misfit).await;
+        return;
+    }
+    stored.channels = channels;
+    save(&stored).await;
+}
+
+async fn no_such_machine(count: usize) {
+    match count.checked_sub(1) {
+        Some(last) => reply(format_args!("This node drives machines 0 to {last}")).await,
+        None => board::serial_reply("This node drives no machines").await,
+    }
+}
+
+async fn remove_channel() {
+    let mut stored = storage::load().await;
+    if stored.channels.len() <= 1 {
+        board::serial_reply("The first machine always stays").await;
+        return;
+    }
+    stored.channels.pop();
+    save(&stored).await;
+}
+
+async fn press(config: &NodeConfig, switch: &str, channel: &[&str]) {
+    let channel = match channel {
+        [] => Some(0),
+        [channel] => channel.parse::<usize>().ok(),
+        _ => None,
+    };
+    let Some(channel) = channel.filter(|channel| *channel < config.channels.len()) else {
+        no_such_machine(config.channels.len()).await;
+        return;
+    };
+
+    let timings = relay::timings(config).bounded();
+    let pressed = if switch == "power" {
+        relay::press_power(channel, timings.power_press_ms).await
+    } else {
+        relay::press_reset(channel, timings.reset_press_ms).await
+    };
+    match pressed {
+        Ok(_) => reply(format_args!("Pressed the {switch} switch of machine {channel}")).await,
+        // The channels only get wired once the node is on its Wifi.
+        Err(_) => board::serial_reply("No channels wired yet, nothing pressed").await,
+    }
+}
+
+async fn save(config: &NodeConfig) {
+    match storage::save(config).await {
+        Ok(_) => board::serial_reply("Saved, `reboot` to apply").await,
+        Err(_) => board::serial_reply("Can't write the config to flash").await,
+    }
+}
+
+async fn show_config(config: &NodeConfig, mac_address: &MacAddress) {
+    let [a, b, c, d, e, f] = mac_address;
+    reply(format_args!("MAC address: {a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}")).await;
+    reply(format_args!("Wifi network: {}", config.wifi_network)).await;
+    // Never echo the password or the key, just enough to tell them apart.
+    reply(format_args!("Wifi password: {} characters", config.wifi_password.len())).await;
+    // The key in use, the server might have rotated it since boot.
+    let (key, _) = keyring::keys().await;
+    let fingerprint = blake3::hash(&key);
+    let fingerprint = fingerprint.as_bytes();
+    reply(
+        format_args!(
+            "Secret key: fingerprint {:02x}{:02x}{:02x}{:02x}",
+            fingerprint[0],
+            fingerprint[1],
+            fingerprint[2],
+            fingerprint[3]
+        )
+    ).await;
+    reply(
+        format_args!(
+            "Multicast: {}:{}",
+            core::net::Ipv4Addr::from_bits(config.multicast_ip),
+            config.multicast_port
+        )
+    ).await;
+    reply(
+        format_args!(
+            "Node port: {}, server port: {}",
+            config.node_port,
+            config.server_port
+        )
+    ).await;
+    reply(format_args!("Fault tolerance: {}", config.fault_tolerance)).await;
+    reply(
+        format_args!(
+            "Setup access point: {}, password {}",
+            access_point_name(mac_address),
+            access_point_password(&key)
+        )
+    ).await;
+    for (index, channel) in config.channels.iter().enumerate() {
+        reply(
+            format_args!(
+                "Machine {index} ({}): power GPIO {}, reset GPIO {}, state GPIO {}, relays switch on {}",
+                channel.name,
+                channel.power_pin,
+                channel.reset_pin,
+                channel.state_pin,
+                if channel.active_high { "high" } else { "low" }
+            )
+        ).await;
+        if let Some(sleep_pin) = channel.sleep_pin {
+            reply(
+                format_args!(
+                    "Machine {index} sleeps when GPIO {sleep_pin} reads {}",
+                    if channel.sleep_active_high { "high" } else { "low" }
+                )
+            ).await;
+        }
+        if let Some(analog) = channel.analog {
+            reply(
+                format_args!(
+                    "Machine {index} is read through the ADC, ON from {} mV up, OFF from {} mV down",
+                    analog.on_mv,
+                    analog.off_mv
+                )
+            ).await;
+        }
+        if let Some(shunt) = channel.shunt {
+            reply(
+                format_args!(
+                    "Machine {index} has a {} milliohm shunt on GPIO {}",
+                    shunt.milliohms,
+                    shunt.pin
+                )
+            ).await;
+        }
+        if let Some(wake) = channel.wake_on_lan {
+            let [a, b, c, d, e, f] = wake.mac_address;
+            let password = if wake.password.is_some() { ", with its SecureOn password" } else { "" };
+            reply(
+                format_args!(
+                    "Machine {index} powers ON for magic packets to \
+                    {a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{f:02x}{password}"
+                )
+            ).await;
+        }
+    }
+    reply(
+        format_args!(
+            "Session: {}",
+            if config.encrypt_session { "encrypted" } else { "plain" }
+        )
+    ).await;
+    reply(
+        format_args!(
+            "Presses: power {}ms, reset {}ms, then {}ms to settle",
+            config.power_press_ms,
+            config.reset_press_ms,
+            config.settle_ms
+        )
+    ).await;
+    reply(
+        format_args!(
+            "The machine gets {}ms to follow, a force off holds {}ms at most",
+            config.verify_timeout_ms,
+            config.force_off_hold_ms
+        )
+    ).await;
+    reply(
+        format_args!(
+            "When it doesn't follow: {}",
+            if config.retry_press { "press again" } else { "report a failure" }
+        )
+    ).await;
+    reply(
+        format_args!(
+            "State pins: {}ms debounce, {}ms to be ON or OFF, asleep when blinking twice in {}ms",
+            config.debounce_ms,
+            config.steady_ms,
+            config.blink_window_ms
+        )
+    ).await;
+
+    let stored = storage::load().await;
+    // Rotated keys are already in use, only the rest waits for a reboot.
+    let running = NodeConfig {
+        secret_key: key,
+        previous_key: stored.previous_key,
+        previous_key_grace_secs: stored.previous_key_grace_secs,
+        ..config.clone()
+    };
+    if stored != running {
+        board::serial_reply("A saved config is waiting for a `reboot`").await;
+    }
+}
+
+async fn show_status(stack: Stack<'static>, config: &NodeConfig) {
+    reply(format_args!("Uptime: {}s", Instant::now().as_secs())).await;
+    reply(
+        format_args!(
+            "Wifi: link {}, DHCP {}, rejoined {} times",
+            if stack.is_link_up() { "up" } else { "down" },
+            if stack.is_config_up() { "up" } else { "down" },
+            watch_link::reconnects()
+        )
+    ).await;
+    match stack.config_v4() {
+        Some(ip_config) => reply(format_args!("Address: {}", ip_config.address)).await,
+        None => board::serial_reply("Address: none").await,
+    }
+    if let Some(remaining) = keyring::previous_remaining().await {
+        reply(format_args!("Previous key: still good for {remaining}s")).await;
+    }
+    board::serial_reply(
+        if entropy::healthy() {
+            "Entropy: healthy"
+        } else {
+            "Entropy: failing its health test, no challenges"
+        }
+    ).await;
+    reply(
+        format_args!(
+            "Server session: {}",
+            if server_contact::connected() { "connected" } else { "none" }
+        )
+    ).await;
+    for (index, channel) in config.channels.iter().enumerate() {
+        reply(
+            format_args!(
+                "Machine {index} ({}): {}",
+                channel.name,
+                match relay::last_machine_state(index) {
+                    MachineState::Off => "OFF",
+                    MachineState::On => "ON",
+                    MachineState::Sleep => "asleep",
+                    MachineState::Unknown => "unknown",
+                }
+            )
+        ).await;
+        if let Some(reading) = relay::last_reading(index) {
+            match reading.milliamps {
+                Some(milliamps) => {
+                    let millivolts = reading.millivolts;
+                    reply(format_args!("Machine {index} reads {millivolts} mV, draws {milliamps} mA"))
+                        .await;
+                }
+                None => reply(format_args!("Machine {index} reads {} mV", reading.millivolts)).await,
+            }
+        }
+    }
+}
+
+async fn reply(arguments: core::fmt::Arguments<'_>) {
+    let mut line: String<256> = String::new();
+    // A reply too long for the line gets cut, better than nothing.
+    let _ = line.write_fmt(arguments);
+    board::serial_reply(&line).await;
+}
+
+// Whitespace separated, double quotes keep spaces in a word (no escapes).
+fn split_words(line: &str) -> Option<Vec<&str, 8>> {
+    let mut words = Vec::new();
+    let mut rest = line.trim_start();
+
+    while !rest.is_empty() {
+        let (word, remainder) = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        };
+        words.push(word).ok()?;
+        rest = remainder.trim_start();
+    }
+
+    Some(words)
+}
@@ -0,0 +1,149 @@
+use embassy_rp::{
+    flash::{ Blocking, Flash, ERASE_SIZE },
+    peripherals::FLASH,
+    Peri,
+};
+use embassy_sync::{ blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex };
+use pibow_config::{ Channel, ConfigError, NodeConfig, MAX_RECORD_LENGTH };
+
+use crate::{ consts::*, phases::board };
+
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+// The last two sectors, carved out of the image in memory.x. A save goes to the slot that isn't
+// holding the current record, so losing power halfway leaves the current one alone.
+// The last sector comes first, that's where older firmware kept its single record.
+const CONFIG_SLOTS: [u32; 2] = [(FLASH_SIZE - ERASE_SIZE) as u32, (FLASH_SIZE - 2 * ERASE_SIZE) as u32];
+
+pub type NodeFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+// Shared between the boot sequence and the USB shell.
+static NODE_FLASH: Mutex<CriticalSectionRawMutex, Option<NodeFlash>> = Mutex::new(None);
+
+pub async fn init(flash: Peri<'static, FLASH>) {
+    *NODE_FLASH.lock().await = Some(Flash::new_blocking(flash));
+}
+
+// What the node runs with until a config gets saved, straight from the build settings.
+pub fn defaults() -> NodeConfig {
+    NodeConfig {
+        wifi_network: heapless::String::try_from(WIFI_NETWORK).unwrap_or_default(),
+        wifi_password: heapless::String::try_from(WIFI_PASSWORD).unwrap_or_default(),
+        secret_key: *SECRET_HASH_KEY,
+        multicast_ip: MULTICAST_IP,
+        multicast_port: MULTICAST_PORT,
+        node_port: NODE_PORT,
+        server_port: SERVER_PORT,
+        fault_tolerance: FAULT_TOLERANCE as u16,
+        relay_active_high: RELAY_ACTIVE_HIGH,
+        encrypt_session: ENCRYPT_SESSION,
+        previous_key: [0_u8; 32],
+        previous_key_grace_secs: 0,
+        force_off_hold_ms: FORCE_OFF_HOLD_MS,
+        power_press_ms: POWER_PRESS_MS,
+        reset_press_ms: RESET_PRESS_MS,
+        settle_ms: SETTLE_MS,
+        verify_timeout_ms: VERIFY_TIMEOUT_MS,
+        retry_press: RETRY_PRESS,
+        channels: CHANNELS
+            .iter()
+            .map(|&(name, power_pin, reset_pin, state_pin, active_high, sleep_pin, sleep_active_high)| {
+                Channel {
+                    name: heapless::String::try_from(name).unwrap_or_default(),
+                    power_pin,
+                    reset_pin,
+                    state_pin,
+                    active_high,
+                    sleep_pin,
+                    sleep_active_high,
+                    // Analog sensing and Wake-on-LAN only come from the shell.
+                    analog: None,
+                    shunt: None,
+                    wake_on_lan: None,
+                }
+            })
+            .collect(),
+        debounce_ms: DEBOUNCE_MS,
+        steady_ms: STEADY_MS,
+        blink_window_ms: BLINK_WINDOW_MS,
+    }
+}
+
+pub async fn load() -> NodeConfig {
+    let mut flash = NODE_FLASH.lock().await;
+    let Some(flash) = flash.as_mut() else {
+        return defaults();
+    };
+
+    match current(flash) {
+        Ok(Some((_, _, config))) => config,
+        Ok(None) => defaults(),
+        Err(_) => {
+            board::serial_log("The stored config is damaged, using defaults");
+            defaults()
+        }
+    }
+}
+
+// The newest stored record, None when nothing was saved yet. Unlike `load`, a damaged record is an
+// error rather than the defaults, for what only changes a few fields and writes the rest back.
+pub async fn stored() -> Result<Option<NodeConfig>, ()> {
+    let mut flash = NODE_FLASH.lock().await;
+    let flash = flash.as_mut().ok_or(())?;
+    Ok(current(flash)?.map(|(_, _, config)| config))
+}
+
+pub async fn save(config: &NodeConfig) -> Result<(), ()> {
+    let mut flash = NODE_FLASH.lock().await;
+    let flash = flash.as_mut().ok_or(())?;
+
+    let (slot, sequence) = match current(flash) {
+        Ok(Some((slot, sequence, _))) => (1 - slot, sequence.wrapping_add(1)),
+        _ => (0, 0),
+    };
+
+    let mut record = [0xff_u8; MAX_RECORD_LENGTH];
+    config.encode_with_sequence(sequence, &mut record).map_err(|_| ())?;
+
+    let offset = CONFIG_SLOTS[slot];
+    flash.blocking_erase(offset, offset + ERASE_SIZE as u32).map_err(|_| ())?;
+    flash.blocking_write(offset, &record).map_err(|_| ())
+}
+
+// The slot, sequence and config of the newest good record. An error when there are records but
+// none of them decodes.
+fn current(flash: &mut NodeFlash) -> Result<Option<(usize, u8, NodeConfig)>, ()> {
+    let mut newest: Option<(usize, u8, NodeConfig)> = None;
+    let mut damaged = false;
+
+    for (slot, offset) in CONFIG_SLOTS.iter().enumerate() {
+        let mut record = [0_u8; MAX_RECORD_LENGTH];
+        if let Err(_) = flash.blocking_read(*offset, &mut record) {
+            damaged = true;
+            continue;
+        }
+
+        match NodeConfig::decode(&record, &defaults()) {
+            Ok(config) => {
+                let sequence = pibow_config::record_sequence(&record);
+                let newer = match &newest {
+                    Some((_, than, _)) => pibow_config::is_newer(sequence, *than),
+                    None => true,
+                };
+                if newer {
+                    newest = Some((slot, sequence, config));
+                }
+            }
+            Err(ConfigError::Empty) => {}
+            Err(_) => {
+                damaged = true;
+            }
+        }
+    }
+
+    match newest {
+        Some(newest) => Ok(Some(newest)),
+        None if damaged => Err(()),
+        None => Ok(None),
+    }
+}
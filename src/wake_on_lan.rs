@@ -0,0 +1,169 @@
+use core::cell::Cell;
+
+use embassy_futures::select::{ select, Either };
+use embassy_net::{ udp::{ PacketMetadata, UdpSocket }, Stack };
+use embassy_sync::{
+    blocking_mutex::{ raw::CriticalSectionRawMutex, Mutex as BlockingMutex },
+    channel::Channel,
+};
+use embassy_time::{ Duration, Instant, Timer };
+use pibow_config::{ NodeConfig, MAX_CHANNELS };
+use pibow_power::{ Outcome, PowerInterface, Timings };
+use pibow_protocol::{ Action, MachineState };
+use pibow_wake::{ MagicPacket, MAGIC_PACKET_LENGTH, PASSWORD_LENGTH };
+
+use crate::{ attempts, phases::board, relay };
+
+// Machines a magic packet asked to power ON, by channel, until whoever holds the relays gets to them.
+static REQUESTS: Channel<CriticalSectionRawMutex, usize, MAX_CHANNELS> = Channel::new();
+
+// When each machine was last asked to. Senders repeat a magic packet a few times to be sure, and a
+// second press while the machine boots would turn it right back off.
+static ASKED_AT: BlockingMutex<CriticalSectionRawMutex, Cell<[Option<Instant>; MAX_CHANNELS]>> =
+    BlockingMutex::new(Cell::new([None; MAX_CHANNELS]));
+
+// How often to look whether a magic packet's press is done.
+const PRESSING_POLL_MS: u64 = 10;
+
+// Set while `serve` presses for a magic packet. Whatever would drop it halfway, like discovery
+// finding a server, waits for `done` first.
+#[derive(Default)]
+pub struct Pressing(Cell<bool>);
+
+impl Pressing {
+    pub async fn done(&self) {
+        while self.0.get() {
+            Timer::after_millis(PRESSING_POLL_MS).await;
+        }
+    }
+}
+
+// Clears the flag however the press ends, including the whole round getting dropped.
+struct Pressed<'a>(&'a Pressing);
+
+impl Drop for Pressed<'_> {
+    fn drop(&mut self) {
+        self.0.0.set(false);
+    }
+}
+
+// Listens on one of the Wake-on-LAN ports for magic packets to the machines that take them. Needs
+// no session with the server, the requests wait for whoever holds the relays, see `serve`.
+#[embassy_executor::task(pool_size = 2)]
+pub async fn listen_task(stack: Stack<'static>, config: &'static NodeConfig, port: u16) {
+    if config.channels.iter().all(|channel| channel.wake_on_lan.is_none()) {
+        return;
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; 4 * (MAGIC_PACKET_LENGTH + PASSWORD_LENGTH)];
+    // Nothing ever goes out, echo included.
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; 16];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer
+    );
+    if let Err(_) = socket.bind(port) {
+        board::serial_log("Can't listen for magic packets");
+        return;
+    }
+
+    // Repeats are the same request as long as the machine might still be booting from the first.
+    let window = Duration::from_millis(relay::timings(config).bounded().verify_timeout_ms as u64);
+    // A magic packet with a password, and room for whatever the sender wraps it in.
+    let mut payload = [0_u8; 256];
+    loop {
+        let Ok((length, meta)) = socket.recv_from(&mut payload).await else {
+            continue;
+        };
+        let address = meta.endpoint.addr;
+        if attempts::locked_out(address).await {
+            continue;
+        }
+        let Some(packet) = MagicPacket::find(&payload[..length]) else {
+            continue;
+        };
+
+        for (channel, machine) in config.channels.iter().enumerate() {
+            let wake = machine.wake_on_lan.filter(|wake| wake.mac_address == packet.mac_address);
+            let Some(wake) = wake else {
+                continue;
+            };
+            // A wrong password counts like a wrong answer to the challenge.
+            if !packet.wakes(&wake.mac_address, wake.password.as_ref()) {
+                attempts::failed(address, "SecureOn passwords").await;
+                continue;
+            }
+            if wake.password.is_some() {
+                attempts::succeeded(address).await;
+            }
+            ask(channel, window);
+        }
+    }
+}
+
+// Queue a power ON, unless the machine got asked within the window.
+fn ask(channel: usize, window: Duration) {
+    let now = Instant::now();
+    let queued = ASKED_AT.lock(|asked_at| {
+        let mut times = asked_at.get();
+        if times[channel].is_some_and(|at| now.saturating_duration_since(at) < window) {
+            return false;
+        }
+        // Full means every machine has one queued already. The packet is lost then, so the
+        // next one mustn't be taken for a repeat of it.
+        if REQUESTS.try_send(channel).is_err() {
+            return false;
+        }
+        times[channel] = Some(now);
+        asked_at.set(times);
+        true
+    });
+    if queued {
+        board::serial_log("A magic packet asked for a machine to power ON");
+    }
+}
+
+// The next machine a magic packet asked to power ON.
+pub async fn next_request() -> usize {
+    REQUESTS.receive().await
+}
+
+// Take the requests for as long as there's no session, keeping the state pins sensed in between so
+// a request finds the machine's state up to date. The session takes them itself.
+pub async fn serve(powers: &mut [impl PowerInterface], timings: &Timings, pressing: &Pressing) {
+    loop {
+        if let Either::First(channel) = select(next_request(), relay::next_change(powers)).await {
+            pressing.0.set(true);
+            let _pressed = Pressed(pressing);
+            power_on(powers, channel, timings).await;
+        }
+    }
+}
+
+// The same power ON the server would ask for, a sleeping machine gets woken instead, like a
+// network card would.
+pub async fn power_on(
+    powers: &mut [impl PowerInterface],
+    channel: usize,
+    timings: &Timings
+) {
+    let Some(power) = powers.get_mut(channel) else {
+        return;
+    };
+    let action = if power.state() == MachineState::Sleep { Action::Wake } else { Action::PowerOn };
+    let outcome = pibow_power::execute(action, power, timings).await;
+    board::serial_log(
+        match outcome {
+            Outcome::Pressed => "Powered a machine ON for a magic packet",
+            Outcome::AlreadyInState { .. } => "A magic packet's machine is ON already",
+            Outcome::Failed => "A magic packet's machine didn't follow the switch",
+            Outcome::Busy | Outcome::Unknown => "A magic packet's machine is busy, nothing pressed",
+            Outcome::Asleep => "A magic packet's machine is asleep, nothing pressed",
+        }
+    );
+}